@@ -0,0 +1,398 @@
+//! Download archive.
+//!
+//! Remembers every video that was downloaded, across runs and sources, in
+//! the format of yt-dlp's `--download-archive`: one `extractor id` line per
+//! video, e.g. `youtube dQw4w9WgXcQ`. The same file can be shared with yt-dlp.
+
+use crate::error::Result;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use url::Url;
+
+/// Set of downloaded videos backed by an archive file
+///
+/// # Examples
+///
+/// ```no_run
+/// use application::archive::DownloadArchive;
+/// use std::path::Path;
+///
+/// let archive = DownloadArchive::open(Path::new("output/archive.txt")).unwrap();
+/// archive.insert("Youtube", "dQw4w9WgXcQ").unwrap();
+/// assert!(archive.contains("youtube dQw4w9WgXcQ"));
+/// ```
+pub struct DownloadArchive {
+    path: PathBuf,
+    inner: Mutex<ArchiveFile>,
+}
+
+struct ArchiveFile {
+    file: File,
+    entries: HashSet<String>,
+}
+
+impl DownloadArchive {
+    /// Opens the archive, reading the entries it already contains
+    ///
+    /// # Arguments
+    /// * `path` - Archive file, created with its parent directory if missing
+    ///
+    /// # Errors
+    /// * If the archive cannot be read or opened for appending
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let entries = if path.exists() {
+            read_entries(path)?
+        } else {
+            HashSet::new()
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            inner: Mutex::new(ArchiveFile { file, entries }),
+        })
+    }
+
+    /// Returns the path of the archive file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the number of archived videos
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// Returns true if no video is archived
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns true if the entry, as built by `entry`, is archived
+    pub fn contains(&self, entry: &str) -> bool {
+        self.lock().entries.contains(entry)
+    }
+
+    /// Returns the archived entries in sorted order
+    pub fn entries(&self) -> Vec<String> {
+        let mut entries: Vec<String> = self.lock().entries.iter().cloned().collect();
+        entries.sort();
+        entries
+    }
+
+    /// Adds a downloaded video to the archive
+    ///
+    /// # Arguments
+    /// * `extractor` - Extractor key of the video, e.g. `Youtube`
+    /// * `id` - Video ID reported by the extractor
+    ///
+    /// # Returns
+    /// * `Result<bool>` - Whether the video was not archived yet
+    ///
+    /// # Errors
+    /// * If the entry cannot be appended to the archive file
+    pub fn insert(&self, extractor: &str, id: &str) -> Result<bool> {
+        let entry = entry(extractor, id);
+        let mut archive = self.lock();
+        if archive.entries.contains(&entry) {
+            return Ok(false);
+        }
+        writeln!(archive.file, "{}", entry)?;
+        archive.entries.insert(entry);
+        Ok(true)
+    }
+
+    /// Removes entries so their videos are downloaded again
+    ///
+    /// # Returns
+    /// * `Result<usize>` - Number of entries that were archived and are now removed
+    ///
+    /// # Errors
+    /// * If the archive file cannot be rewritten
+    pub fn remove(&self, entries: &[String]) -> Result<usize> {
+        let mut archive = self.lock();
+        let removed = entries
+            .iter()
+            .filter(|entry| archive.entries.remove(entry.as_str()))
+            .count();
+        if removed > 0 {
+            self.rewrite(&mut archive)?;
+        }
+        Ok(removed)
+    }
+
+    /// Adds the entries of another archive file, e.g. one written by yt-dlp
+    ///
+    /// Lines that are not `extractor id` pairs are skipped.
+    ///
+    /// # Returns
+    /// * `Result<usize>` - Number of entries that were not archived yet
+    ///
+    /// # Errors
+    /// * If either file cannot be read or written
+    pub fn import(&self, path: &Path) -> Result<usize> {
+        let mut imported: Vec<String> = read_entries(path)?.into_iter().collect();
+        imported.sort();
+
+        let mut archive = self.lock();
+        let mut added = 0;
+        for entry in imported {
+            if !archive.entries.contains(&entry) {
+                writeln!(archive.file, "{}", entry)?;
+                archive.entries.insert(entry);
+                added += 1;
+            }
+        }
+        archive.file.flush()?;
+        Ok(added)
+    }
+
+    /// Replaces the archive file with the current entries
+    fn rewrite(&self, archive: &mut ArchiveFile) -> Result<()> {
+        let mut entries: Vec<&String> = archive.entries.iter().collect();
+        entries.sort();
+
+        let temp = self.path.with_extension("tmp");
+        let mut file = File::create(&temp)?;
+        for entry in entries {
+            writeln!(file, "{}", entry)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&temp, &self.path)?;
+
+        archive.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ArchiveFile> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Builds the archive entry of a video, lowercasing the extractor like yt-dlp
+pub fn entry(extractor: &str, id: &str) -> String {
+    format!("{} {}", extractor.to_lowercase(), id)
+}
+
+/// Parses an `extractor id` archive line
+///
+/// # Returns
+/// * `Option<String>` - The normalized entry, or `None` if the line is not a pair
+pub fn parse_entry(line: &str) -> Option<String> {
+    let mut parts = line.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(extractor), Some(id), None) => Some(entry(extractor, id)),
+        _ => None,
+    }
+}
+
+/// Derives the archive entry of a URL without fetching the video
+///
+/// Only YouTube URLs carry their video ID in a known place; other sites are
+/// checked against the archive once their information is fetched.
+///
+/// # Returns
+/// * `Option<String>` - `youtube <id>` for YouTube video URLs, `None` otherwise
+///
+/// # Examples
+///
+/// ```
+/// use application::archive::entry_for_url;
+///
+/// assert_eq!(
+///     entry_for_url("https://youtu.be/dQw4w9WgXcQ?t=42").as_deref(),
+///     Some("youtube dQw4w9WgXcQ")
+/// );
+/// assert_eq!(entry_for_url("https://vimeo.com/76979871"), None);
+/// ```
+pub fn entry_for_url(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?.trim_start_matches("www.");
+    let mut segments = url.path_segments()?.filter(|s| !s.is_empty());
+
+    let id = match host {
+        "youtu.be" => segments.next()?.to_string(),
+        "youtube.com" | "m.youtube.com" | "music.youtube.com" | "youtube-nocookie.com" => {
+            match segments.next()? {
+                "watch" => url
+                    .query_pairs()
+                    .find(|(key, _)| key == "v")
+                    .map(|(_, value)| value.into_owned())?,
+                "shorts" | "embed" | "live" | "v" => segments.next()?.to_string(),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+
+    // YouTube video IDs are 11 characters of [A-Za-z0-9_-]
+    let valid = id.len() == 11
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then(|| entry("youtube", &id))
+}
+
+/// Reads the valid entries of an archive file
+fn read_entries(path: &Path) -> Result<HashSet<String>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = HashSet::new();
+    for line in reader.lines() {
+        if let Some(entry) = parse_entry(&line?) {
+            entries.insert(entry);
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an archive path in a directory unique to the calling test
+    fn archive_path(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("ytb-archive-{}-{}", name, std::process::id()))
+            .join("archive.txt")
+    }
+
+    fn remove_dir(path: &Path) {
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn entry_for_url_reads_youtube_url_shapes() {
+        let id = "dQw4w9WgXcQ";
+        let cases = [
+            format!("https://www.youtube.com/watch?v={}", id),
+            format!("https://youtube.com/watch?feature=share&v={}&t=42", id),
+            format!("https://m.youtube.com/watch?v={}", id),
+            format!("https://music.youtube.com/watch?v={}&list=RD", id),
+            format!("https://youtu.be/{}", id),
+            format!("https://youtu.be/{}?si=abc", id),
+            format!("https://www.youtube.com/shorts/{}", id),
+            format!("https://www.youtube.com/embed/{}", id),
+            format!("https://www.youtube-nocookie.com/embed/{}", id),
+            format!("https://www.youtube.com/live/{}", id),
+            format!("https://www.youtube.com/v/{}", id),
+        ];
+        for url in cases {
+            assert_eq!(
+                entry_for_url(&url).as_deref(),
+                Some("youtube dQw4w9WgXcQ"),
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn entry_for_url_rejects_other_urls() {
+        let cases = [
+            "https://www.youtube.com/watch",
+            "https://www.youtube.com/watch?v=short",
+            "https://www.youtube.com/watch?v=dQw4w9WgXc!",
+            "https://www.youtube.com/playlist?list=PL123",
+            "https://www.youtube.com/@channel",
+            "https://youtu.be/",
+            "https://notyoutube.com/watch?v=dQw4w9WgXcQ",
+            "https://vimeo.com/76979871",
+            "not a url",
+        ];
+        for url in cases {
+            assert_eq!(entry_for_url(url), None, "{}", url);
+        }
+    }
+
+    #[test]
+    fn parse_entry_normalizes_pairs() {
+        assert_eq!(parse_entry("Youtube abc").as_deref(), Some("youtube abc"));
+        assert_eq!(parse_entry("  vimeo   123 ").as_deref(), Some("vimeo 123"));
+        assert_eq!(parse_entry("youtube"), None);
+        assert_eq!(parse_entry("youtube abc extra"), None);
+        assert_eq!(parse_entry(""), None);
+    }
+
+    #[test]
+    fn insert_appends_and_reopen_reads_back() {
+        let path = archive_path("insert");
+        let archive = DownloadArchive::open(&path).unwrap();
+        assert!(archive.is_empty());
+        assert!(archive.insert("Youtube", "a").unwrap());
+        assert!(!archive.insert("youtube", "a").unwrap());
+        assert!(archive.insert("Vimeo", "1").unwrap());
+        drop(archive);
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "youtube a\nvimeo 1\n"
+        );
+        let archive = DownloadArchive::open(&path).unwrap();
+        assert_eq!(archive.entries(), ["vimeo 1", "youtube a"]);
+        assert!(archive.contains("youtube a"));
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn remove_rewrites_the_file() {
+        let path = archive_path("remove");
+        let archive = DownloadArchive::open(&path).unwrap();
+        for id in ["c", "a", "b"] {
+            archive.insert("youtube", id).unwrap();
+        }
+
+        let removed = archive
+            .remove(&["youtube a".to_string(), "youtube missing".to_string()])
+            .unwrap();
+        assert_eq!(removed, 1);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "youtube b\nyoutube c\n"
+        );
+        assert!(!path.with_extension("tmp").exists());
+
+        // The archive keeps appending to the rewritten file
+        archive.insert("youtube", "d").unwrap();
+        assert_eq!(archive.remove(&["youtube missing".to_string()]).unwrap(), 0);
+        drop(archive);
+        assert_eq!(
+            DownloadArchive::open(&path).unwrap().entries(),
+            ["youtube b", "youtube c", "youtube d"]
+        );
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn import_adds_new_valid_entries() {
+        let path = archive_path("import");
+        let archive = DownloadArchive::open(&path).unwrap();
+        archive.insert("youtube", "a").unwrap();
+
+        let other = path.with_file_name("yt-dlp.txt");
+        std::fs::write(
+            &other,
+            "youtube a\nYoutube b\n\nnot an entry at all\nvimeo 1\n",
+        )
+        .unwrap();
+        assert_eq!(archive.import(&other).unwrap(), 2);
+        assert_eq!(archive.import(&other).unwrap(), 0);
+        assert_eq!(archive.len(), 3);
+        drop(archive);
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "youtube a\nvimeo 1\nyoutube b\n"
+        );
+        assert!(DownloadArchive::open(&path)
+            .unwrap()
+            .import(&path.with_file_name("missing.txt"))
+            .is_err());
+        remove_dir(&path);
+    }
+}
@@ -0,0 +1,415 @@
+//! Global bandwidth limit.
+//!
+//! Every stream download draws from one shared byte budget, so the limit
+//! holds for the whole process whatever the number of concurrent downloads.
+//! Streams downloaded by yt-dlp cannot wait for the budget; they get a fixed
+//! share of the limit instead, and their bytes are charged to the budget.
+//! The limit can change with the time of day, e.g. 2 MiB/s during office
+//! hours and unlimited at night.
+
+use crate::error::{AppError, Result};
+use chrono::{Local, NaiveTime, Timelike};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Bandwidth settings, the `[bandwidth]` table of the configuration
+///
+/// Rates are bytes per second, written as a number or with a `K`, `M` or `G`
+/// suffix (powers of 1024), e.g. `"2MB"` or `"500K/s"`; `"unlimited"` or `"0"`
+/// lifts the limit. The first schedule window containing the local time
+/// wins; outside every window `limit` applies. A window may wrap around
+/// midnight.
+///
+/// HLS and DASH streams are downloaded by yt-dlp, which is given the limit
+/// in force when the stream starts divided by `concurrent_downloads`, so
+/// the yt-dlp streams together stay within it. Their bytes are charged to
+/// the shared budget as they arrive, slowing down the other downloads.
+///
+/// ```toml
+/// [bandwidth]
+/// limit = "unlimited"
+///
+/// [[bandwidth.schedule]]
+/// start = "09:00"
+/// end = "18:00"
+/// limit = "2MB"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BandwidthConfig {
+    /// Limit outside the schedule windows, `None` for no limit
+    pub limit: Option<String>,
+    pub schedule: Vec<SpeedWindow>,
+}
+
+/// A daily time range with its own limit
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpeedWindow {
+    /// Local time the window opens, `HH:MM`
+    pub start: String,
+    /// Local time the window closes, `HH:MM`, excluded
+    pub end: String,
+    pub limit: String,
+}
+
+impl BandwidthConfig {
+    /// Checks that every rate and time can be parsed
+    ///
+    /// # Errors
+    /// * If a rate or a window time is malformed
+    pub fn validate(&self) -> Result<()> {
+        Schedule::parse(self).map(|_| ())
+    }
+}
+
+/// Parsed form of `BandwidthConfig`, rates in bytes per second
+#[derive(Debug, Clone)]
+struct Schedule {
+    limit: Option<u64>,
+    windows: Vec<(NaiveTime, NaiveTime, Option<u64>)>,
+}
+
+impl Schedule {
+    fn parse(config: &BandwidthConfig) -> Result<Self> {
+        let limit = match &config.limit {
+            Some(limit) => parse_rate(limit).map_err(|e| config_error("bandwidth.limit", &e))?,
+            None => None,
+        };
+        let windows = config
+            .schedule
+            .iter()
+            .enumerate()
+            .map(|(i, window)| {
+                let key = |field: &str| format!("bandwidth.schedule[{}].{}", i, field);
+                Ok((
+                    parse_time(&window.start).map_err(|e| config_error(&key("start"), &e))?,
+                    parse_time(&window.end).map_err(|e| config_error(&key("end"), &e))?,
+                    parse_rate(&window.limit).map_err(|e| config_error(&key("limit"), &e))?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { limit, windows })
+    }
+
+    /// Returns the limit in force at the given local time
+    ///
+    /// Windows include their start and exclude their end; the first window
+    /// containing the time wins.
+    fn limit_at(&self, time: NaiveTime) -> Option<u64> {
+        self.windows
+            .iter()
+            .find(|(start, end, _)| {
+                if start <= end {
+                    *start <= time && time < *end
+                } else {
+                    time >= *start || time < *end
+                }
+            })
+            .map_or(self.limit, |(_, _, limit)| *limit)
+    }
+}
+
+/// Byte budget shared by all downloads
+///
+/// # Examples
+///
+/// ```
+/// use application::bandwidth::{BandwidthConfig, BandwidthLimiter};
+///
+/// let config = BandwidthConfig {
+///     limit: Some("2MB".to_string()),
+///     schedule: Vec::new(),
+/// };
+/// let limiter = BandwidthLimiter::new(&config).unwrap();
+/// assert_eq!(limiter.current_limit(), Some(2 * 1024 * 1024));
+/// ```
+pub struct BandwidthLimiter {
+    schedule: Schedule,
+    bucket: Mutex<ByteBucket>,
+}
+
+/// Token bucket holding at most one second worth of bytes
+struct ByteBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl BandwidthLimiter {
+    /// Creates the limiter of a configuration
+    ///
+    /// # Errors
+    /// * If the configuration does not pass `BandwidthConfig::validate`
+    pub fn new(config: &BandwidthConfig) -> Result<Self> {
+        Ok(Self {
+            schedule: Schedule::parse(config)?,
+            bucket: Mutex::new(ByteBucket {
+                tokens: 0.0,
+                refilled: Instant::now(),
+            }),
+        })
+    }
+
+    /// Returns the limit in force now, in bytes per second, `None` for no limit
+    pub fn current_limit(&self) -> Option<u64> {
+        self.schedule.limit_at(now())
+    }
+
+    /// Returns the rate of one of `slots` downloads that cannot wait for the
+    /// budget, so that together they stay within the limit in force now
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use application::bandwidth::{BandwidthConfig, BandwidthLimiter};
+    ///
+    /// let config = BandwidthConfig {
+    ///     limit: Some("2MB".to_string()),
+    ///     schedule: Vec::new(),
+    /// };
+    /// let limiter = BandwidthLimiter::new(&config).unwrap();
+    /// assert_eq!(limiter.share(4), Some(512 * 1024));
+    /// ```
+    pub fn share(&self, slots: usize) -> Option<u64> {
+        self.current_limit()
+            .map(|limit| (limit / slots.max(1) as u64).max(1))
+    }
+
+    /// Waits until the given number of received bytes fits in the limit
+    ///
+    /// Bytes are accounted after they arrive, so a large chunk may overdraw
+    /// the budget; the next callers then wait until it is paid back.
+    pub async fn consume(&self, bytes: usize) {
+        if let Some(wait) = self.take(bytes as u64) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Charges bytes received outside of `consume`, without waiting
+    ///
+    /// Used for the streams yt-dlp downloads: the next callers of `consume`
+    /// wait until these bytes are paid back.
+    pub fn charge(&self, bytes: u64) {
+        self.take(bytes);
+    }
+
+    fn take(&self, bytes: u64) -> Option<Duration> {
+        let rate = self.current_limit()?;
+        self.bucket.lock().unwrap_or_else(|e| e.into_inner()).take(
+            bytes,
+            rate as f64,
+            Instant::now(),
+        )
+    }
+}
+
+impl ByteBucket {
+    /// Refills the bucket at `rate` up to one second worth of bytes, then
+    /// takes `bytes`, returning how long the taker has to wait if overdrawn
+    fn take(&mut self, bytes: u64, rate: f64, now: Instant) -> Option<Duration> {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - bytes as f64;
+        self.refilled = now;
+        (self.tokens < 0.0).then(|| Duration::from_secs_f64(-self.tokens / rate))
+    }
+}
+
+/// Parses a rate like `2MB`, `500K/s` or `1048576` into bytes per second
+///
+/// # Returns
+/// * `Ok(None)` - For `unlimited` and `0`
+///
+/// # Examples
+///
+/// ```
+/// use application::bandwidth::parse_rate;
+///
+/// assert_eq!(parse_rate("2MB"), Ok(Some(2 * 1024 * 1024)));
+/// assert_eq!(parse_rate("1.5 KiB/s"), Ok(Some(1536)));
+/// assert_eq!(parse_rate("unlimited"), Ok(None));
+/// assert!(parse_rate("fast").is_err());
+/// ```
+pub fn parse_rate(rate: &str) -> std::result::Result<Option<u64>, String> {
+    let trimmed = rate.trim();
+    if trimmed.eq_ignore_ascii_case("unlimited") {
+        return Ok(None);
+    }
+    let lower = trimmed.to_lowercase();
+    let bytes = parse_size(lower.strip_suffix("/s").unwrap_or(&lower))?;
+    Ok((bytes > 0).then_some(bytes))
+}
+
+/// Parses a byte size like `2GB`, `1.5 KiB` or `1048576`
+///
+/// Units are powers of 1024, whether written `MB` or `MiB`.
+///
+/// # Examples
+///
+/// ```
+/// use application::bandwidth::parse_size;
+///
+/// assert_eq!(parse_size("2GB"), Ok(2 * 1024 * 1024 * 1024));
+/// assert_eq!(parse_size("512"), Ok(512));
+/// ```
+pub fn parse_size(size: &str) -> std::result::Result<u64, String> {
+    let value = size.trim().to_lowercase();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let multiplier: u64 = match unit.trim() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1024,
+        "m" | "mb" | "mib" => 1024 * 1024,
+        "g" | "gb" | "gib" => 1024 * 1024 * 1024,
+        _ => return Err(format!("unknown unit in `{}`", size.trim())),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size `{}`", size.trim()))?;
+    Ok((number * multiplier as f64).round() as u64)
+}
+
+/// Renders a rate for progress output, e.g. `2.0 MiB/s` or `unlimited`
+pub fn format_rate(rate: Option<u64>) -> String {
+    match rate {
+        Some(rate) => format!("{}/s", format_size(rate)),
+        None => "unlimited".to_string(),
+    }
+}
+
+/// Renders a size for progress output, e.g. `1.5 GiB` or `512 B`
+pub fn format_size(bytes: u64) -> String {
+    let size = bytes as f64;
+    match size {
+        s if s >= 1024.0 * 1024.0 * 1024.0 => {
+            format!("{:.1} GiB", s / (1024.0 * 1024.0 * 1024.0))
+        }
+        s if s >= 1024.0 * 1024.0 => format!("{:.1} MiB", s / (1024.0 * 1024.0)),
+        s if s >= 1024.0 => format!("{:.1} KiB", s / 1024.0),
+        _ => format!("{} B", bytes),
+    }
+}
+
+fn parse_time(time: &str) -> std::result::Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(time.trim(), "%H:%M")
+        .map_err(|_| format!("invalid time `{}`, expected HH:MM", time))
+}
+
+fn config_error(key: &str, message: &str) -> AppError {
+    AppError::Config(format!("{}: {}", key, message))
+}
+
+/// Local time of day, to the minute like the schedule
+fn now() -> NaiveTime {
+    let now = Local::now();
+    NaiveTime::from_hms_opt(now.hour(), now.minute(), 0).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(time: &str) -> NaiveTime {
+        parse_time(time).unwrap()
+    }
+
+    fn window(start: &str, end: &str, limit: &str) -> SpeedWindow {
+        SpeedWindow {
+            start: start.to_string(),
+            end: end.to_string(),
+            limit: limit.to_string(),
+        }
+    }
+
+    fn schedule(windows: Vec<SpeedWindow>) -> Schedule {
+        Schedule::parse(&BandwidthConfig {
+            limit: Some("1K".to_string()),
+            schedule: windows,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn limit_at_excludes_the_end_of_windows() {
+        let schedule = schedule(vec![window("09:00", "18:00", "2K")]);
+        let cases = [
+            ("08:59", Some(1024)),
+            ("09:00", Some(2048)),
+            ("17:59", Some(2048)),
+            ("18:00", Some(1024)),
+        ];
+        for (at, limit) in cases {
+            assert_eq!(schedule.limit_at(time(at)), limit, "{}", at);
+        }
+    }
+
+    #[test]
+    fn limit_at_wraps_windows_around_midnight() {
+        let schedule = schedule(vec![window("22:00", "06:00", "unlimited")]);
+        let cases = [
+            ("21:59", Some(1024)),
+            ("22:00", None),
+            ("23:59", None),
+            ("00:00", None),
+            ("05:59", None),
+            ("06:00", Some(1024)),
+            ("12:00", Some(1024)),
+        ];
+        for (at, limit) in cases {
+            assert_eq!(schedule.limit_at(time(at)), limit, "{}", at);
+        }
+    }
+
+    #[test]
+    fn limit_at_takes_the_first_matching_window() {
+        let schedule = schedule(vec![
+            window("09:00", "12:00", "2K"),
+            window("00:00", "23:59", "3K"),
+        ]);
+        assert_eq!(schedule.limit_at(time("10:00")), Some(2048));
+        assert_eq!(schedule.limit_at(time("13:00")), Some(3072));
+        assert_eq!(schedule.limit_at(time("23:59")), Some(1024));
+    }
+
+    #[test]
+    fn bucket_refills_up_to_one_second_of_bytes() {
+        let start = Instant::now();
+        let mut bucket = ByteBucket {
+            tokens: 0.0,
+            refilled: start,
+        };
+        let at = |secs: f64| start + Duration::from_secs_f64(secs);
+
+        // An empty bucket makes the first taker wait for its bytes
+        assert_eq!(
+            bucket.take(500, 1000.0, start),
+            Some(Duration::from_millis(500))
+        );
+        // Half a second pays the debt back, another half refills 500 bytes
+        assert_eq!(bucket.take(500, 1000.0, at(1.0)), None);
+        // Long idle time refills one second worth of bytes only
+        assert_eq!(bucket.take(1000, 1000.0, at(10.0)), None);
+        assert_eq!(
+            bucket.take(2000, 1000.0, at(10.0)),
+            Some(Duration::from_secs(2))
+        );
+    }
+
+    #[test]
+    fn share_splits_the_limit() {
+        let limited = BandwidthLimiter::new(&BandwidthConfig {
+            limit: Some("3".to_string()),
+            schedule: Vec::new(),
+        })
+        .unwrap();
+        assert_eq!(limited.share(1), Some(3));
+        assert_eq!(limited.share(2), Some(1));
+        assert_eq!(limited.share(10), Some(1));
+        assert_eq!(limited.share(0), Some(3));
+
+        let unlimited = BandwidthLimiter::new(&BandwidthConfig::default()).unwrap();
+        assert_eq!(unlimited.share(4), None);
+    }
+}
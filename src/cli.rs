@@ -0,0 +1,221 @@
+//! Command-line interface definition.
+//!
+//! Maps each subcommand onto the library components: `Downloader` for
+//! downloads and dependency management, `SheetClient` for sheet sources,
+//! `DownloadProgress` for failure reports and `JobStore` for resuming.
+
+use application::error::ErrorKind;
+use application::formats::DownloadMode;
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Process exited normally and every download succeeded.
+pub const EXIT_OK: u8 = 0;
+/// At least one download failed, or a dependency is missing.
+pub const EXIT_FAILURES: u8 = 1;
+/// Invalid command-line usage (emitted by clap itself).
+pub const EXIT_USAGE: u8 = 2;
+/// The configuration could not be loaded.
+pub const EXIT_CONFIG: u8 = 3;
+/// An unrecoverable error aborted the run, or a source could not be read.
+pub const EXIT_ERROR: u8 = 4;
+/// The sheet could not be read and the run was aborted.
+pub const EXIT_SHEET: u8 = 5;
+/// The input directory could not be watched and the run was aborted.
+pub const EXIT_WATCH: u8 = 6;
+
+/// Concurrent video downloader for Google Sheets and URL lists.
+///
+/// Without a subcommand, downloads from the configured sheet and the URL
+/// files in the input directory.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Options shared by every subcommand
+#[derive(Debug, Args)]
+pub struct GlobalArgs {
+    /// Configuration file to use instead of ./ytb.toml
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Override a configuration value, e.g. --set concurrent_downloads=4
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+
+    /// Download videos even if the download archive or job journal has them
+    #[arg(long, global = true)]
+    pub force: bool,
+
+    /// What to keep of each video: `audio-video`, `audio` or `video`
+    #[arg(long, global = true, value_name = "MODE")]
+    pub mode: Option<DownloadMode>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Download the configured sheet and every URL file in the input directory
+    Run {
+        /// Keep running: poll the sheet and watch the input directory for new files
+        #[arg(long)]
+        watch: bool,
+    },
+
+    /// Download the given URLs, or the URLs listed in the given files
+    Download {
+        /// Video URLs or paths to text files with one URL per line
+        #[arg(required = true, value_name = "URL|FILE")]
+        inputs: Vec<String>,
+    },
+
+    /// Download every URL listed in a Google Sheet
+    Sheet {
+        /// Sheet URL, defaults to `sheet_url` from the configuration
+        url: Option<String>,
+
+        /// Keep polling the sheet and download rows as they are added
+        #[arg(long)]
+        watch: bool,
+    },
+
+    /// Download again the jobs recorded in a failure report, with their overrides
+    RetryFailed {
+        /// Failure report written by a previous run, defaults to
+        /// `failed.jsonl` in `reports_dir`
+        report: Option<PathBuf>,
+
+        /// Only retry failures of this kind: retryable, permanent or fatal
+        #[arg(long = "kind", value_name = "KIND")]
+        kinds: Vec<ErrorKind>,
+
+        /// Only retry failures with this error code, e.g. rate_limited
+        #[arg(long = "code", value_name = "CODE")]
+        codes: Vec<String>,
+
+        /// Only retry failures younger than this, e.g. 90s, 30m, 12h or 7d
+        #[arg(long, value_name = "AGE", value_parser = parse_age)]
+        max_age: Option<Duration>,
+
+        /// Only retry failures older than this
+        #[arg(long, value_name = "AGE", value_parser = parse_age)]
+        min_age: Option<Duration>,
+    },
+
+    /// Finish the jobs an interrupted run left in the job journal
+    Resume,
+
+    /// Inspect or edit the download archive
+    Archive {
+        #[command(subcommand)]
+        action: ArchiveAction,
+    },
+
+    /// Manage the yt-dlp and ffmpeg binaries
+    Deps {
+        #[command(subcommand)]
+        action: DepsAction,
+    },
+
+    /// Show metadata and available formats of a video
+    Info {
+        /// Video URL
+        url: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DepsAction {
+    /// Report whether yt-dlp and ffmpeg are installed
+    Check,
+    /// Download the latest yt-dlp and ffmpeg, replacing the installed ones
+    Update,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ArchiveAction {
+    /// Print every archived video
+    List,
+    /// Remove videos from the archive so they are downloaded again
+    Prune {
+        /// YouTube URLs, `extractor id` entries or bare YouTube IDs
+        #[arg(required = true, value_name = "VIDEO")]
+        videos: Vec<String>,
+    },
+    /// Add the entries of another archive file, e.g. one written by yt-dlp
+    Import {
+        /// Archive file with one `extractor id` line per video
+        file: PathBuf,
+    },
+}
+
+/// Parses an age such as `90s`, `30m`, `12h`, `7d` or `2w`; a bare number is seconds
+fn parse_age(age: &str) -> Result<Duration, String> {
+    let age = age.trim();
+    let split = age.find(|c: char| !c.is_ascii_digit()).unwrap_or(age.len());
+    let (number, unit) = age.split_at(split);
+    if number.is_empty() {
+        return Err(format!("`{}` does not start with a number", age));
+    }
+    let too_long = || format!("`{}` is too long", age);
+    let number: u64 = number.parse().map_err(|_| too_long())?;
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("unknown unit `{}`, expected s, m, h, d or w", unit)),
+    };
+    let seconds = number.checked_mul(seconds).ok_or_else(too_long)?;
+    Ok(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_age_reads_number_and_unit() {
+        let cases = [
+            ("90", 90),
+            ("90s", 90),
+            ("30m", 30 * 60),
+            ("12h", 12 * 60 * 60),
+            ("7d", 7 * 24 * 60 * 60),
+            ("2w", 2 * 7 * 24 * 60 * 60),
+            (" 5m ", 5 * 60),
+            ("0d", 0),
+        ];
+        for (age, seconds) in cases {
+            assert_eq!(parse_age(age), Ok(Duration::from_secs(seconds)), "{}", age);
+        }
+    }
+
+    #[test]
+    fn parse_age_rejects_invalid_ages() {
+        let max = format!("{}m", u64::MAX);
+        let cases = [
+            "",
+            "   ",
+            "h",
+            "-5m",
+            "1.5h",
+            "5 m",
+            "5y",
+            "5mm",
+            "99999999999999999999",
+            "30000000000000000000d",
+            max.as_str(),
+        ];
+        for age in cases {
+            assert!(parse_age(age).is_err(), "{}", age);
+        }
+    }
+}
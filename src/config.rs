@@ -1,13 +1,40 @@
-use serde::Deserialize;
-use std::path::PathBuf;
+//! Configuration management for the application.
+//!
+//! Provides centralized configuration options for controlling:
+//! - Concurrent download limits
+//! - Directory paths and output file names; the journal, archive, work
+//!   and reports paths are relative to the output directory
+//! - Buffer sizes
+//! - Bandwidth limits and their schedule
+//! - Format selection, audio-only and video-only modes, output container
+//! - External service URLs
+//!
+//! Values are resolved in layers, each one overriding the previous:
+//! built-in defaults, the `ytb.toml` file, `YTB_*` environment variables
+//! and finally command-line overrides.
 
-/// Configuration management for the application.
-///
-/// Provides centralized configuration options for controlling:
-/// - Concurrent download limits
-/// - Directory paths
-/// - Buffer sizes
-/// - External service URLs
+use crate::bandwidth::BandwidthConfig;
+use crate::error::{AppError, Result};
+use crate::formats::{DownloadMode, FormatPolicy};
+use crate::limits::LimitsConfig;
+use crate::progress::FAILURE_REPORT;
+use crate::retry::RetryConfig;
+use crate::sheet::{SheetConfig, SheetRef};
+use crate::template::{OutputTemplate, DEFAULT_TEMPLATE};
+use crate::transcode::{AudioConfig, VideoConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Name of the configuration file looked up in the working directory.
+pub const DEFAULT_CONFIG_FILE: &str = "ytb.toml";
+
+/// Prefix of the environment variables that override configuration values.
+pub const ENV_PREFIX: &str = "YTB_";
+
+/// Environment variable pointing to an alternative configuration file.
+pub const CONFIG_FILE_ENV: &str = "YTB_CONFIG";
 
 /// Configuration for the video downloader application.
 ///
@@ -22,25 +49,686 @@ use std::path::PathBuf;
 /// let config = Config::default();
 /// assert!(config.concurrent_downloads > 0);
 /// ```
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
+    /// Audio and video streams downloaded at the same time, across all sources
     pub concurrent_downloads: usize,
+    /// Video information requests to yt-dlp running at the same time
+    pub concurrent_fetches: usize,
+    /// ffmpeg merges running at the same time
+    pub concurrent_merges: usize,
+    /// Jobs of one batch in progress at the same time, whatever their stage
     pub buffer_size: usize,
+    /// Seconds between reports of the bytes of running jobs, 0 disables them
+    pub progress_interval_secs: u64,
     pub output_dir: PathBuf,
+    /// Name of the output files, relative to `output_dir`, see `OutputTemplate`
+    pub output_template: String,
     pub input_dir: PathBuf,
     pub libraries_dir: PathBuf,
     pub sheet_url: Option<String>,
+    pub sheet: SheetConfig,
+    /// Download all URL files in `input_dir` as one deduplicated batch
+    pub merge_input_files: bool,
+    /// Move processed URL files into `input_dir/done` or `input_dir/failed`
+    pub archive_input_files: bool,
+    /// Seconds a new URL file must keep the same size before it is read in watch mode
+    pub input_settle_secs: u64,
+    /// Journal recording the state of every job, used to resume interrupted runs,
+    /// relative to `output_dir` like the paths below
+    pub journal_file: PathBuf,
+    /// yt-dlp compatible archive of downloaded videos, `None` disables it
+    pub download_archive: Option<PathBuf>,
+    /// Work directory of the streams being downloaded and merged
+    pub temp_dir: PathBuf,
+    /// Directory of the failure reports
+    pub reports_dir: PathBuf,
+    /// Download videos again even if the archive or journal has them
+    pub force: bool,
+    pub retry: RetryConfig,
+    pub limits: LimitsConfig,
+    pub bandwidth: BandwidthConfig,
+    pub formats: FormatPolicy,
+    /// What to keep of each video, unless its source sets a mode
+    pub mode: DownloadMode,
+    pub audio: AudioConfig,
+    pub video: VideoConfig,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             concurrent_downloads: 10,
+            concurrent_fetches: 4,
+            concurrent_merges: 2,
             buffer_size: 10,
+            progress_interval_secs: 10,
             output_dir: PathBuf::from("output"),
+            output_template: DEFAULT_TEMPLATE.to_string(),
             input_dir: PathBuf::from("input"),
             libraries_dir: PathBuf::from("libs"),
             sheet_url: Some(String::from("https://docs.google.com/spreadsheets/d/160Obd-Z9nMz2LfnbqUVvvwCvel7AGfjwREZtVwtM1_M")),
+            sheet: SheetConfig::default(),
+            merge_input_files: false,
+            archive_input_files: false,
+            input_settle_secs: 2,
+            journal_file: PathBuf::from("jobs.jsonl"),
+            download_archive: Some(PathBuf::from("archive.txt")),
+            temp_dir: PathBuf::from(".tmp"),
+            reports_dir: PathBuf::from("."),
+            force: false,
+            retry: RetryConfig::default(),
+            limits: LimitsConfig::default(),
+            bandwidth: BandwidthConfig::default(),
+            formats: FormatPolicy::default(),
+            mode: DownloadMode::default(),
+            audio: AudioConfig::default(),
+            video: VideoConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from `ytb.toml`, the environment and no CLI overrides
+    ///
+    /// Shorthand for `ConfigLoader::new().load()`.
+    pub fn load() -> Result<LoadedConfig> {
+        ConfigLoader::new().load()
+    }
+
+    /// Checks that the configured values are usable
+    ///
+    /// # Errors
+    /// * If a concurrency limit or buffer size is zero
+    /// * If `sheet_url` is not a valid Google Sheets URL
+    /// * If `output_template` is malformed or leaves `output_dir`
+    /// * If the `[retry]` or `[limits]` settings are out of range
+    /// * If a `[bandwidth]` rate or schedule time is malformed
+    /// * If a `[formats]` cap is malformed or zero
+    /// * If the `[audio]` bitrate is malformed
+    pub fn validate(&self) -> Result<()> {
+        for (key, value) in [
+            ("concurrent_downloads", self.concurrent_downloads),
+            ("concurrent_fetches", self.concurrent_fetches),
+            ("concurrent_merges", self.concurrent_merges),
+        ] {
+            if value == 0 {
+                return Err(AppError::Config(format!("{} must be greater than 0", key)));
+            }
+        }
+        if self.buffer_size == 0 {
+            return Err(AppError::Config(
+                "buffer_size must be greater than 0".to_string(),
+            ));
+        }
+        if let Some(sheet_url) = &self.sheet_url {
+            SheetRef::parse(sheet_url).map_err(|e| AppError::Config(e.to_string()))?;
+        }
+        OutputTemplate::parse(&self.output_template)?;
+        self.retry.validate()?;
+        self.limits.validate()?;
+        self.bandwidth.validate()?;
+        self.formats.validate()?;
+        self.audio.validate()?;
+        Ok(())
+    }
+
+    /// Resolves a configured path against `output_dir`
+    ///
+    /// Absolute paths are returned as they are.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use application::Config;
+    /// use std::path::Path;
+    ///
+    /// let config = Config::default();
+    /// assert_eq!(config.resolve(Path::new("jobs.jsonl")), Path::new("output/jobs.jsonl"));
+    /// assert_eq!(config.resolve(Path::new("/var/jobs.jsonl")), Path::new("/var/jobs.jsonl"));
+    /// ```
+    pub fn resolve(&self, path: &Path) -> PathBuf {
+        self.output_dir.join(path)
+    }
+
+    /// Returns the path of the job journal
+    pub fn journal_path(&self) -> PathBuf {
+        self.resolve(&self.journal_file)
+    }
+
+    /// Returns the path of the download archive, `None` when it is disabled
+    pub fn archive_path(&self) -> Option<PathBuf> {
+        self.download_archive
+            .as_deref()
+            .map(|path| self.resolve(path))
+    }
+
+    /// Returns the work directory of downloads in progress
+    pub fn temp_path(&self) -> PathBuf {
+        self.resolve(&self.temp_dir)
+    }
+
+    /// Returns the directory of the failure reports
+    pub fn reports_path(&self) -> PathBuf {
+        self.resolve(&self.reports_dir)
+    }
+
+    /// Returns the failure report appended to by every batch
+    pub fn failure_report(&self) -> PathBuf {
+        self.reports_path().join(FAILURE_REPORT)
+    }
+}
+
+/// Origin of a resolved configuration value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// Built-in default from `Config::default()`
+    Default,
+    /// Configuration file
+    File(PathBuf),
+    /// Environment variable, with its name
+    Env(String),
+    /// Command-line override
+    Cli,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::Default => write!(f, "default"),
+            ConfigSource::File(path) => write!(f, "file {}", path.display()),
+            ConfigSource::Env(name) => write!(f, "env {}", name),
+            ConfigSource::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+/// A resolved configuration together with the layer each value came from
+#[derive(Debug)]
+pub struct LoadedConfig {
+    pub config: Config,
+    sources: BTreeMap<String, ConfigSource>,
+}
+
+impl LoadedConfig {
+    /// Returns the layer that provided `key` (dotted for nested values)
+    pub fn source_of(&self, key: &str) -> &ConfigSource {
+        self.sources.get(key).unwrap_or(&ConfigSource::Default)
+    }
+
+    /// Lists every configuration value with its rendered value and origin
+    ///
+    /// # Returns
+    /// * `Vec<(String, String, &ConfigSource)>` - `(key, value, source)` sorted by key
+    pub fn report(&self) -> Vec<(String, String, &ConfigSource)> {
+        let mut values = BTreeMap::new();
+        if let Ok(toml::Value::Table(table)) = toml::Value::try_from(&self.config) {
+            flatten_table("", &table, &mut values);
+        }
+
+        values
+            .into_iter()
+            .map(|(key, value)| {
+                let source = self.source_of(&key);
+                (key, value.to_string(), source)
+            })
+            .collect()
+    }
+}
+
+/// Builds a `Config` from the file, environment and command-line layers
+///
+/// # Examples
+///
+/// ```no_run
+/// use application::config::ConfigLoader;
+///
+/// let loaded = ConfigLoader::new()
+///     .file("ytb.toml")
+///     .set("concurrent_downloads", "4")
+///     .load()
+///     .unwrap();
+/// assert_eq!(loaded.config.concurrent_downloads, 4);
+/// ```
+#[derive(Debug, Default)]
+pub struct ConfigLoader {
+    file: Option<PathBuf>,
+    env: Option<Vec<(String, String)>>,
+    overrides: Vec<(String, String)>,
+}
+
+impl ConfigLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses an explicit configuration file, which then must exist
+    pub fn file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.file = Some(path.into());
+        self
+    }
+
+    /// Reads `YTB_*` values from `vars` instead of the process environment
+    pub fn env<K, V>(mut self, vars: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.env = Some(
+            vars.into_iter()
+                .map(|(name, value)| (name.into(), value.into()))
+                .collect(),
+        );
+        self
+    }
+
+    /// Adds a command-line override for `key` (dotted for nested values)
+    pub fn set(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.overrides.push((key.into(), value.into()));
+        self
+    }
+
+    /// Adds a `key=value` command-line override
+    ///
+    /// # Errors
+    /// * If the argument has no `=`
+    pub fn set_pair(self, pair: &str) -> Result<Self> {
+        let (key, value) = pair.split_once('=').ok_or_else(|| {
+            AppError::Config(format!("override `{}` must have the form key=value", pair))
+        })?;
+        Ok(self.set(key.trim(), value.trim()))
+    }
+
+    /// Resolves the configuration from all layers
+    ///
+    /// # Errors
+    /// * If an explicitly requested configuration file does not exist
+    /// * If the file is not valid TOML
+    /// * If the file or an override contains an unknown key
+    /// * If any layer contains a value of the wrong type
+    /// * If the merged configuration fails `Config::validate`
+    pub fn load(self) -> Result<LoadedConfig> {
+        let mut merged = toml::Table::new();
+        let mut sources = BTreeMap::new();
+
+        if let Some((path, table)) = self.read_file()? {
+            let source = ConfigSource::File(path);
+            check_layer(&table, &source)?;
+            merge_layer(&mut merged, &mut sources, "", table, &|_| source.clone());
+        }
+
+        let mut env_names = BTreeMap::new();
+        let mut env_layer = toml::Table::new();
+        for (name, raw) in self.env_vars() {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            if name == CONFIG_FILE_ENV {
+                continue;
+            }
+            let key = key.to_lowercase().replace("__", ".");
+            // Other tools may share the prefix, so only known keys are errors
+            let value = match typed_value(&key, &raw, &ConfigSource::Env(name.clone())) {
+                Ok(value) => value,
+                Err(e) if is_unknown_key(&e) => {
+                    eprintln!("Warning: ignoring {}: not a configuration key", name);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            insert_dotted(&mut env_layer, &key, value);
+            env_names.insert(key, name);
+        }
+        merge_layer(&mut merged, &mut sources, "", env_layer, &|key| {
+            ConfigSource::Env(
+                env_names
+                    .get(key)
+                    .cloned()
+                    .unwrap_or_else(|| format!("{}{}", ENV_PREFIX, key.to_uppercase())),
+            )
+        });
+
+        let mut cli_layer = toml::Table::new();
+        for (key, raw) in &self.overrides {
+            insert_dotted(
+                &mut cli_layer,
+                key,
+                typed_value(key, raw, &ConfigSource::Cli)?,
+            );
+        }
+        merge_layer(&mut merged, &mut sources, "", cli_layer, &|_| {
+            ConfigSource::Cli
+        });
+
+        let mut config: Config = toml::Value::Table(merged).try_into().map_err(|e| {
+            AppError::Config(format!(
+                "invalid configuration: {}",
+                e.to_string().trim_end()
+            ))
+        })?;
+
+        // An empty value is the only way to unset an option from env or CLI
+        if config.sheet_url.as_deref().is_some_and(str::is_empty) {
+            config.sheet_url = None;
         }
+        if config
+            .download_archive
+            .as_ref()
+            .is_some_and(|path| path.as_os_str().is_empty())
+        {
+            config.download_archive = None;
+        }
+        config.validate()?;
+
+        Ok(LoadedConfig { config, sources })
+    }
+
+    /// Reads the configuration file, if any
+    ///
+    /// The file is taken from `ConfigLoader::file`, then `YTB_CONFIG`, then
+    /// `ytb.toml`; only the implicit default may be missing.
+    fn read_file(&self) -> Result<Option<(PathBuf, toml::Table)>> {
+        let config_env = self
+            .env_vars()
+            .into_iter()
+            .find_map(|(name, value)| (name == CONFIG_FILE_ENV).then_some(value));
+        let (path, required) = match (&self.file, config_env) {
+            (Some(path), _) => (path.clone(), true),
+            (None, Some(path)) => (PathBuf::from(path), true),
+            (None, None) => (PathBuf::from(DEFAULT_CONFIG_FILE), false),
+        };
+
+        if !required && !path.exists() {
+            return Ok(None);
+        }
+
+        let content = std::fs::read_to_string(&path).map_err(|e| {
+            AppError::Config(format!("cannot read config file {}: {}", path.display(), e))
+        })?;
+        let table = parse_file(&path, &content)?;
+        Ok(Some((path, table)))
+    }
+
+    /// Returns the variables set with `ConfigLoader::env`, or the process environment
+    fn env_vars(&self) -> Vec<(String, String)> {
+        match &self.env {
+            Some(vars) => vars.clone(),
+            None => std::env::vars().collect(),
+        }
+    }
+}
+
+fn parse_file(path: &Path, content: &str) -> Result<toml::Table> {
+    content
+        .parse::<toml::Table>()
+        .map_err(|e| AppError::Config(format!("{}: {}", path.display(), e)))
+}
+
+/// Deserializes a single layer on its own so errors name the offending layer
+fn check_layer(layer: &toml::Table, source: &ConfigSource) -> Result<()> {
+    toml::Value::Table(layer.clone())
+        .try_into::<Config>()
+        .map(|_| ())
+        .map_err(|e| AppError::Config(format!("{}: {}", source, e.to_string().trim_end())))
+}
+
+/// Interprets a raw env/CLI string as a TOML value, falling back to a string
+fn parse_value(raw: &str) -> toml::Value {
+    format!("value = {}", raw)
+        .parse::<toml::Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+}
+
+/// Converts a raw env/CLI string to the value `key` expects
+///
+/// # Details
+/// The string is read as TOML first, so `4`, `true` or `["a"]` work; when
+/// that does not fit the type of `key` it is used as a plain string, so
+/// string options accept values like `0` or `2024`.
+///
+/// # Errors
+/// * If `key` is unknown or neither form fits its type
+fn typed_value(key: &str, raw: &str, source: &ConfigSource) -> Result<toml::Value> {
+    let parsed = parse_value(raw);
+    let check = |value: &toml::Value| {
+        let mut layer = toml::Table::new();
+        insert_dotted(&mut layer, key, value.clone());
+        check_layer(&layer, source)
+    };
+
+    match check(&parsed) {
+        Ok(()) => Ok(parsed),
+        Err(e) if parsed.is_str() || is_unknown_key(&e) => Err(e),
+        Err(e) => {
+            let string = toml::Value::String(raw.to_string());
+            check(&string).map(|()| string).map_err(|_| e)
+        }
+    }
+}
+
+/// Returns whether a layer was rejected for naming a key `Config` does not have
+fn is_unknown_key(error: &AppError) -> bool {
+    matches!(error, AppError::Config(message) if message.contains("unknown field"))
+}
+
+fn insert_dotted(table: &mut toml::Table, key: &str, value: toml::Value) {
+    match key.split_once('.') {
+        Some((head, rest)) => {
+            let entry = table
+                .entry(head.to_string())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            if !entry.is_table() {
+                *entry = toml::Value::Table(toml::Table::new());
+            }
+            if let toml::Value::Table(nested) = entry {
+                insert_dotted(nested, rest, value);
+            }
+        }
+        None => {
+            table.insert(key.to_string(), value);
+        }
+    }
+}
+
+fn merge_layer(
+    target: &mut toml::Table,
+    sources: &mut BTreeMap<String, ConfigSource>,
+    prefix: &str,
+    layer: toml::Table,
+    source: &dyn Fn(&str) -> ConfigSource,
+) {
+    for (key, value) in layer {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match (target.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(nested)) => {
+                merge_layer(existing, sources, &path, nested, source);
+            }
+            (_, toml::Value::Table(nested)) => {
+                let mut fresh = toml::Table::new();
+                merge_layer(&mut fresh, sources, &path, nested, source);
+                target.insert(key, toml::Value::Table(fresh));
+            }
+            (_, value) => {
+                sources.insert(path.clone(), source(&path));
+                target.insert(key, value);
+            }
+        }
+    }
+}
+
+fn flatten_table(prefix: &str, table: &toml::Table, out: &mut BTreeMap<String, toml::Value>) {
+    for (key, value) in table {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value {
+            toml::Value::Table(nested) => flatten_table(&path, nested, out),
+            value => {
+                out.insert(path, value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `content` to a config file unique to the calling test
+    fn config_file(name: &str, content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("ytb-config-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// Loads `file` with the given environment and `--set` pairs, then
+    /// removes the config file again
+    fn load(
+        name: &str,
+        file: &str,
+        env: &[(&str, &str)],
+        set: &[(&str, &str)],
+    ) -> Result<LoadedConfig> {
+        let path = config_file(name, file);
+        let mut loader = ConfigLoader::new()
+            .file(path.clone())
+            .env(env.iter().copied());
+        for &(key, value) in set {
+            loader = loader.set(key, value);
+        }
+        let loaded = loader.load();
+        std::fs::remove_file(&path).unwrap();
+        loaded
+    }
+
+    #[test]
+    fn layers_override_in_order() {
+        let loaded = load(
+            "layers",
+            "concurrent_downloads = 2\nconcurrent_fetches = 3\nbuffer_size = 5\n",
+            &[("YTB_CONCURRENT_FETCHES", "6"), ("YTB_BUFFER_SIZE", "7")],
+            &[("buffer_size", "8")],
+        )
+        .unwrap();
+
+        assert_eq!(loaded.config.concurrent_downloads, 2);
+        assert_eq!(loaded.config.concurrent_fetches, 6);
+        assert_eq!(loaded.config.buffer_size, 8);
+        assert_eq!(loaded.config.concurrent_merges, 2);
+        assert!(matches!(
+            loaded.source_of("concurrent_downloads"),
+            ConfigSource::File(_)
+        ));
+        assert_eq!(
+            loaded.source_of("concurrent_fetches"),
+            &ConfigSource::Env("YTB_CONCURRENT_FETCHES".to_string())
+        );
+        assert_eq!(loaded.source_of("buffer_size"), &ConfigSource::Cli);
+        assert_eq!(
+            loaded.source_of("concurrent_merges"),
+            &ConfigSource::Default
+        );
+    }
+
+    #[test]
+    fn nested_keys_use_double_underscores_and_dots() {
+        let loaded = load(
+            "nested",
+            "",
+            &[("YTB_RETRY__MAX_ATTEMPTS", "9")],
+            &[("bandwidth.limit", "2M")],
+        )
+        .unwrap();
+
+        assert_eq!(loaded.config.retry.max_attempts, 9);
+        assert_eq!(loaded.config.bandwidth.limit.as_deref(), Some("2M"));
+        assert_eq!(
+            loaded.source_of("retry.max_attempts"),
+            &ConfigSource::Env("YTB_RETRY__MAX_ATTEMPTS".to_string())
+        );
+    }
+
+    #[test]
+    fn numeric_strings_keep_their_type() {
+        let loaded = load(
+            "strings",
+            "",
+            &[("YTB_AUDIO__BITRATE", "192000")],
+            &[("bandwidth.limit", "0"), ("output_dir", "2024")],
+        )
+        .unwrap();
+
+        assert_eq!(loaded.config.audio.bitrate.as_deref(), Some("192000"));
+        assert_eq!(loaded.config.bandwidth.limit.as_deref(), Some("0"));
+        assert_eq!(loaded.config.output_dir, Path::new("2024"));
+    }
+
+    #[test]
+    fn wrong_types_are_rejected() {
+        let error = load("types", "", &[], &[("concurrent_downloads", "many")]).unwrap_err();
+        assert!(error.to_string().contains("command line"), "{}", error);
+
+        let error = load("env-types", "", &[("YTB_FORCE", "maybe")], &[]).unwrap_err();
+        assert!(error.to_string().contains("YTB_FORCE"), "{}", error);
+    }
+
+    #[test]
+    fn unknown_env_keys_are_ignored() {
+        let loaded = load(
+            "unknown-env",
+            "",
+            &[("YTB_FOO", "1"), ("YTB_RETRY__FOO", "bar")],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(loaded.source_of("foo"), &ConfigSource::Default);
+    }
+
+    #[test]
+    fn unknown_file_and_cli_keys_are_rejected() {
+        assert!(load("unknown-file", "foo = 1\n", &[], &[]).is_err());
+        assert!(load("unknown-cli", "", &[], &[("foo", "1")]).is_err());
+    }
+
+    #[test]
+    fn empty_values_unset_options() {
+        let loaded = load(
+            "unset",
+            "",
+            &[("YTB_SHEET_URL", "")],
+            &[("download_archive", "")],
+        )
+        .unwrap();
+
+        assert_eq!(loaded.config.sheet_url, None);
+        assert_eq!(loaded.config.download_archive, None);
+        assert_eq!(loaded.config.archive_path(), None);
+    }
+
+    #[test]
+    fn config_env_names_the_file() {
+        let path = config_file("config-env", "buffer_size = 3\n");
+        let loaded = ConfigLoader::new()
+            .env([(CONFIG_FILE_ENV.to_string(), path.display().to_string())])
+            .load()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.config.buffer_size, 3);
+    }
+
+    #[test]
+    fn set_pair_requires_an_equals_sign() {
+        assert!(ConfigLoader::new().set_pair("buffer_size").is_err());
+        assert!(ConfigLoader::new().set_pair("buffer_size = 4").is_ok());
     }
 }
@@ -1,27 +1,66 @@
-use crate::progress::DownloadProgress;
-use crate::{config::Config, error::Result};
+use crate::archive::{self, DownloadArchive};
+use crate::bandwidth::BandwidthLimiter;
+use crate::config::Config;
+use crate::error::{AppError, ErrorKind, Result, Stage};
+use crate::formats::{self, DownloadMode, FormatChoice};
+use crate::journal::{JobState, JobStore};
+use crate::limits::RateLimiter;
+use crate::manifest::YtDlp;
+use crate::progress::{DownloadProgress, DownloadSummary, JobProgress, StreamKind, StreamProgress};
+use crate::retry::retry;
+use crate::template::{OutputNames, OutputTemplate, TemplateFields};
+use crate::transcode::{AudioCodec, Ffmpeg, Input};
+use crate::transfer::{self, Transfer};
 use futures::stream::{self, StreamExt};
-use yt_dlp::fetcher::deps::Libraries;
+use serde::{Deserialize, Serialize};
+use yt_dlp::client::deps::Libraries;
 
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Component, Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::{Mutex, Semaphore};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{Mutex, OwnedMutexGuard, Semaphore};
 use tracing::instrument;
+use yt_dlp::model::format::Format;
 use yt_dlp::model::Video;
-use yt_dlp::Youtube;
+use yt_dlp::Downloader as Youtube;
+
+/// Interval at which the output of a running ffmpeg step is measured
+const OUTPUT_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
 /// A downloader that manages concurrent video downloads and processing
 ///
 /// # Fields
 /// * `fetcher` - Thread-safe reference to Youtube downloader instance
-/// * `semaphore` - Controls concurrent download limits
+/// * `limits` - Bound the fetches, downloads and merges running at once
+/// * `limiter` - Per-site concurrency caps and request rates
+/// * `transfer` - Stream downloads within the global bandwidth limit
+/// * `yt_dlp` - Managed yt-dlp binary, downloading manifest based streams
+/// * `ffmpeg` - Managed ffmpeg binary, merging streams and converting audio
+/// * `template` - Parsed `Config::output_template`
+/// * `names` - Output names reserved by running jobs
+/// * `running` - Jobs in progress by id, so duplicates run one at a time
 /// * `config` - Application configuration settings
 /// * `active_downloads` - Counter for currently active downloads
+/// * `jobs` - Journal of job states, shared by every batch
+/// * `archive` - Videos downloaded by earlier runs, unless disabled
 pub struct Downloader {
     fetcher: Arc<Youtube>,
-    semaphore: Arc<Semaphore>,
+    limits: StageLimits,
+    limiter: Arc<RateLimiter>,
+    transfer: Transfer,
+    yt_dlp: YtDlp,
+    ffmpeg: Ffmpeg,
+    template: OutputTemplate,
+    names: OutputNames,
+    running: JobLocks,
     config: Arc<Config>,
     active_downloads: Arc<AtomicUsize>,
+    jobs: JobStore,
+    archive: Option<DownloadArchive>,
 }
 
 impl Downloader {
@@ -35,21 +74,48 @@ impl Downloader {
     ///
     /// # Errors
     /// * If directory creation fails
+    /// * If the job journal or download archive cannot be opened
+    /// * If the work directories of finished jobs cannot be listed
+    /// * If the bandwidth schedule or output template is invalid
     /// * If Youtube initialization fails
     #[instrument(skip(config))]
     pub async fn new(config: Config) -> Result<Self> {
         // Initialize directories
-        for dir in [&config.output_dir, &config.input_dir, &config.libraries_dir] {
+        for dir in [
+            config.output_dir.clone(),
+            config.input_dir.clone(),
+            config.libraries_dir.clone(),
+            config.temp_path(),
+            config.reports_path(),
+        ] {
             tokio::fs::create_dir_all(dir).await?;
         }
 
+        let jobs = JobStore::open(&config.journal_path())?;
+        sweep_temp_dirs(&config.temp_path(), &jobs)?;
+        let archive = match config.archive_path() {
+            Some(path) => Some(DownloadArchive::open(&path)?),
+            None => None,
+        };
+        let bandwidth = BandwidthLimiter::new(&config.bandwidth)?;
+        let template = OutputTemplate::parse(&config.output_template)?;
         let fetcher = Self::initialize_youtube(&config).await?;
+        let limiter = Arc::new(RateLimiter::new(config.limits.clone()));
 
         Ok(Self {
             fetcher: Arc::new(fetcher),
-            semaphore: Arc::new(Semaphore::new(config.concurrent_downloads)),
+            limits: StageLimits::new(&config),
+            limiter: Arc::clone(&limiter),
+            transfer: Transfer::new(bandwidth, limiter),
+            yt_dlp: YtDlp::new(&config.libraries_dir),
+            ffmpeg: Ffmpeg::new(&config.libraries_dir),
+            template,
+            names: OutputNames::new(),
+            running: JobLocks::default(),
             config: Arc::new(config),
             active_downloads: Arc::new(AtomicUsize::new(0)),
+            jobs,
+            archive,
         })
     }
 
@@ -77,45 +143,257 @@ impl Downloader {
         let yt_dlp = config.libraries_dir.join("yt-dlp");
         let ffmpeg = config.libraries_dir.join("ffmpeg");
         let libraries = Libraries::new(yt_dlp, ffmpeg);
-        let youtube = Youtube::new(libraries, config.output_dir.clone())?;
+        let youtube = Youtube::new(libraries, config.output_dir.clone()).await?;
         youtube.update_downloader().await?;
 
         Ok(youtube)
     }
 
-    /// Downloads a single video from the given URL
+    /// Downloads the latest yt-dlp and ffmpeg, replacing the installed binaries
+    ///
+    /// # Arguments
+    /// * `config` - Configuration containing the libraries directory
+    ///
+    /// # Errors
+    /// * If a binary cannot be downloaded or moved into place
+    ///
+    /// # Details
+    /// The binaries are downloaded to an empty staging directory first, so
+    /// the installed ones are kept if a download fails.
+    pub async fn update_dependencies(config: &Config) -> Result<()> {
+        replace_binaries(&config.libraries_dir, |staging| async move {
+            Youtube::with_new_binaries(staging, config.output_dir.clone()).await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Reports whether the yt-dlp and ffmpeg binaries are installed
+    ///
+    /// # Arguments
+    /// * `config` - Configuration containing the libraries directory
+    ///
+    /// # Returns
+    /// * `Vec<(&str, PathBuf, bool)>` - Name, expected path and presence of each binary
+    ///
+    /// # Details
+    /// Only inspects the filesystem, nothing is downloaded.
+    pub fn dependency_status(config: &Config) -> Vec<(&'static str, PathBuf, bool)> {
+        ["yt-dlp", "ffmpeg"]
+            .into_iter()
+            .map(|name| {
+                let path = config.libraries_dir.join(name);
+                let installed = path.exists();
+                (name, path, installed)
+            })
+            .collect()
+    }
+
+    /// Fetches metadata and the available formats of a video
     ///
     /// # Arguments
-    /// * `url` - URL of the video to download
+    /// * `url` - URL of the video
+    ///
+    /// # Returns
+    /// * `Result<Video>` - Video information as reported by yt-dlp
+    ///
+    /// # Details
+    /// Transient failures are retried according to `Config::retry`, each
+    /// attempt within the limits of the URL's site.
+    pub async fn fetch_info(&self, url: &str) -> Result<Video> {
+        let site = self.limiter.site(url, None);
+        retry(&self.config.retry, url, Stage::FetchingInfo, || {
+            self.limited(&self.limits.fetch, &site, async move {
+                Ok(self.fetcher.fetch_video_infos(url.to_string()).await?)
+            })
+        })
+        .await
+    }
+
+    /// Runs one request within the limits of its stage and of its site
+    ///
+    /// The stage permit is taken first, so the site's permit and rate token
+    /// are only taken when the request can start right away. A rate-limited
+    /// response slows the site down for the next requests.
+    async fn limited<T>(
+        &self,
+        stage: &Semaphore,
+        site: &str,
+        request: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let _stage = stage.acquire().await.expect("semaphores are never closed");
+        let _permit = self.limiter.acquire(site).await;
+        let result = request.await;
+        if matches!(&result, Err(e) if e.code() == "rate_limited") {
+            self.limiter.throttled(site);
+        }
+        result
+    }
+
+    /// Downloads a single video from the given job
+    ///
+    /// # Arguments
+    /// * `job` - URL of the video to download and its naming overrides
     /// * `index` - Position of this video in the download queue
+    /// * `progress` - Byte counters of the job's downloads and merge
     ///
     /// # Returns
-    /// * `Result<()>` - Success or error status
+    /// * `Result<Option<String>>` - Name of the output file, relative to the output
+    ///   directory, or `None` if the video is in the download archive
     ///
     /// # Details
     /// Handles the complete download process including:
-    /// 1. Fetching video information
-    /// 2. Downloading audio and video separately
-    /// 3. Combining them into final output
+    /// 1. Fetching video information, selecting formats and naming the
+    ///    output from `Config::output_template`
+    /// 2. Downloading audio and video separately into the job's work directory
+    /// 3. Combining them into a `.part` file in the work directory, moved to
+    ///    the output once complete
     /// 4. Cleaning up temporary files
-    #[instrument(skip(self))]
-    async fn download_video(&self, url: &String, index: usize) -> Result<()> {
+    ///
+    /// Each stage is recorded in the job journal before it starts. Unless
+    /// `force` is set, the download archive is checked before fetching the
+    /// video information when the URL carries the video ID, after otherwise.
+    #[instrument(skip(self, progress))]
+    async fn download_video(
+        &self,
+        job: &DownloadJob,
+        index: usize,
+        progress: &JobProgress,
+    ) -> Result<Option<String>> {
         let _active = DownloadGuard::new(&self.active_downloads);
-        let video = self.fetcher.fetch_video_infos(url.clone()).await?;
+        if self.is_archived(archive::entry_for_url(&job.url)) {
+            return Ok(None);
+        }
+
+        let mode = self.job_mode(job);
+        let interrupted_at = self.jobs.resume_stage(job);
+        self.jobs.record(job, JobState::FetchingInfo)?;
+        let video = self.fetch_info(&job.url).await?;
+        let entry = archive::entry(&video.extractor_info.extractor_key, &video.id);
+        if self.is_archived(Some(entry)) {
+            return Ok(None);
+        }
+
+        let choice = self
+            .config
+            .formats
+            .select(&video, mode)
+            .map_err(|e| e.at(&job.url, Stage::FetchingInfo))?;
+        println!("Selected formats {} for {}", choice, job.url);
+        let conversion = self.audio_conversion(mode, choice);
+        let (audio_format, video_format) = match choice {
+            FormatChoice::Progressive(format) => (None, Some(format)),
+            FormatChoice::Separate { video, audio } => (audio, video),
+        };
+        let extension = match (mode, conversion, audio_format) {
+            (DownloadMode::Audio, Some(codec), _) => codec.extension(),
+            (DownloadMode::Audio, None, Some(audio)) => formats::extension(audio),
+            _ => self.config.video.container.extension(),
+        };
 
+        let fields = TemplateFields {
+            index,
+            id: video.id.clone(),
+            title: job.title.clone().unwrap_or_else(|| video.title.clone()),
+            uploader: video.uploader.clone(),
+            channel: video.channel.clone(),
+            upload_date: video
+                .upload_date
+                .and_then(|timestamp| chrono::DateTime::from_timestamp(timestamp, 0))
+                .map(|date| date.format("%Y%m%d").to_string()),
+            extractor: video.extractor_info.extractor.clone(),
+            resolution: match (mode, video_format) {
+                (DownloadMode::Audio, _) => "audio".to_string(),
+                (_, Some(format)) => format
+                    .video_resolution
+                    .height
+                    .map(|height| format!("{}p", height))
+                    .unwrap_or_default(),
+                (_, None) => String::new(),
+            },
+            format_id: choice.to_string(),
+            ext: extension.to_string(),
+        };
+        let mut path = self
+            .template
+            .render(&fields)
+            .map_err(|e| e.at(&job.url, Stage::Saving))?;
+        if let Some(folder) = job
+            .output_folder()
+            .map_err(|e| e.at(&job.url, Stage::Saving))?
+        {
+            path = folder.join(path);
+        }
+        // Held until the job ends, so concurrent jobs get distinct names
+        let reserved = self.names.reserve(&self.config.output_dir, &path);
+        if let Some(parent) = reserved.path().parent() {
+            tokio::fs::create_dir_all(self.config.output_dir.join(parent)).await?;
+        }
+        let name = reserved.path().to_string_lossy().into_owned();
+
+        // Temporary files keep the extension of their source, for ffmpeg
+        let dir = self.config.temp_path().join(job.id());
+        tokio::fs::create_dir_all(&dir).await?;
+        let output = self.config.output_dir.join(&name);
         let filenames: FileNames = FileNames {
-            audio: format!("audio_{}.mp3", video.id),
-            video: format!("video_{}.mp4", video.id),
-            name: format!("{}_{}_{}.mp4", index, video.id, video.title),
+            audio: dir.join(format!(
+                "audio.{}",
+                audio_format.map_or("m4a", formats::extension)
+            )),
+            video: dir.join(format!(
+                "video.{}",
+                video_format.map_or("mp4", formats::extension)
+            )),
+            // Named after the job rather than the output, whose name may
+            // differ in the next run, so an interrupted download resumes
+            part: dir.join("output.part"),
+            output,
+            dir,
+            name,
         };
 
-        self.process_download(&video, &filenames).await?;
+        self.process_download(job, &video, choice, &filenames, interrupted_at, progress)
+            .await?;
+        save_output(&filenames.part, &filenames.output)
+            .await
+            .map_err(|e| AppError::from(e).at(&job.url, Stage::Saving))?;
         self.cleanup_temp_files(&filenames).await?;
+        if let Some(archive) = &self.archive {
+            archive.insert(&video.extractor_info.extractor_key, &video.id)?;
+        }
+        self.jobs.complete(job, &filenames.name)?;
 
-        Ok(())
+        Ok(Some(filenames.name))
+    }
+
+    /// Returns what to keep of a job's video, its own mode or `Config::mode`
+    fn job_mode(&self, job: &DownloadJob) -> DownloadMode {
+        job.mode.unwrap_or(self.config.mode)
     }
 
-    /// Removes temporary audio and video files after processing
+    /// Returns the codec the audio must be converted to, if any
+    ///
+    /// In audio-only mode, audio is converted to `Config::audio`'s codec, and
+    /// extracted to m4a from a progressive format when no codec is set.
+    fn audio_conversion(&self, mode: DownloadMode, choice: FormatChoice<'_>) -> Option<AudioCodec> {
+        match (mode, choice) {
+            (DownloadMode::AudioVideo | DownloadMode::Video, _) => None,
+            (DownloadMode::Audio, FormatChoice::Progressive(_)) => {
+                Some(self.config.audio.codec.unwrap_or(AudioCodec::M4a))
+            }
+            (DownloadMode::Audio, FormatChoice::Separate { .. }) => self.config.audio.codec,
+        }
+    }
+
+    /// Returns true if the entry is in the download archive and `force` is not set
+    fn is_archived(&self, entry: Option<String>) -> bool {
+        match (&self.archive, entry) {
+            (Some(archive), Some(entry)) => !self.config.force && archive.contains(&entry),
+            _ => false,
+        }
+    }
+
+    /// Removes the work directory of a job after processing
     ///
     /// # Arguments
     /// * `filenames` - Structure containing paths to temporary files
@@ -123,11 +401,11 @@ impl Downloader {
     /// # Returns
     /// * `Result<()>` - Success status (errors are logged but not propagated)
     async fn cleanup_temp_files(&self, filenames: &FileNames) -> Result<()> {
-        if let Err(e) = std::fs::remove_file(format!("output/{}", &filenames.audio)) {
-            eprintln!("Warning: Could not delete temporary audio file: {}", e);
-        }
-        if let Err(e) = std::fs::remove_file(format!("output/{}", &filenames.video)) {
-            eprintln!("Warning: Could not delete temporary video file: {}", e);
+        match tokio::fs::remove_dir_all(&filenames.dir).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                eprintln!("Warning: Could not delete temporary files: {}", e);
+            }
+            _ => {}
         }
 
         Ok(())
@@ -136,33 +414,276 @@ impl Downloader {
     /// Processes the download and combination of audio and video streams
     ///
     /// # Arguments
+    /// * `job` - Job whose stages are recorded in the journal
     /// * `video` - Video metadata and format information
+    /// * `choice` - Formats selected by `Config::formats`
     /// * `filenames` - Structure containing output file paths
+    /// * `interrupted_at` - Stage a previous attempt of the job stopped at
+    /// * `progress` - Byte counters of the job's downloads and merge
     ///
     /// # Returns
     /// * `Result<()>` - Success or error status
     ///
     /// # Details
-    /// 1. Downloads best quality audio if available
-    /// 2. Downloads best quality video if available
-    /// 3. Combines audio and video into final output file
-    async fn process_download(&self, video: &Video, filenames: &FileNames) -> Result<()> {
-        if let Some(audio_format) = video.best_audio_format() {
-            self.fetcher
-                .download_format(&audio_format, &filenames.audio)
+    /// A progressive format already in the configured container is
+    /// downloaded straight to the `.part` file. Otherwise:
+    /// 1. Downloads the selected audio stream, if any
+    /// 2. Downloads the selected video stream, if any
+    /// 3. Writes the output file from them, see `Downloader::finish`
+    ///
+    /// Each stage is retried on its own according to `Config::retry`.
+    /// Downloads are limited per extractor or host, see `Config::limits`,
+    /// and share the global bandwidth limit, see `Config::bandwidth`.
+    /// Streams a previous attempt finished downloading are reused when
+    /// their temporary file is still there, and plain HTTPS streams it left
+    /// half way are resumed, see `Transfer::download`.
+    async fn process_download(
+        &self,
+        job: &DownloadJob,
+        video: &Video,
+        choice: FormatChoice<'_>,
+        filenames: &FileNames,
+        interrupted_at: Option<JobState>,
+        progress: &JobProgress,
+    ) -> Result<()> {
+        let mode = self.job_mode(job);
+        let site = self
+            .limiter
+            .site(&job.url, Some(&video.extractor_info.extractor_key));
+
+        let (audio_format, video_format) = match choice {
+            FormatChoice::Progressive(format)
+                if mode == DownloadMode::AudioVideo
+                    && formats::extension(format) == self.config.video.container.extension() =>
+            {
+                self.jobs.record(job, JobState::DownloadingVideo)?;
+                let stream = progress.stream(StreamKind::Video);
+                return self
+                    .fetch_stream(
+                        job,
+                        &site,
+                        Stage::DownloadingVideo,
+                        format,
+                        &filenames.part,
+                        &stream,
+                    )
+                    .await;
+            }
+            FormatChoice::Progressive(format) => (None, Some(format)),
+            FormatChoice::Separate { video, audio } => (audio, video),
+        };
+
+        let (has_audio, has_video) = match interrupted_at {
+            Some(JobState::DownloadingVideo) => (true, false),
+            Some(JobState::Merging) => (true, true),
+            _ => (false, false),
+        };
+        if let Some(audio_format) = audio_format {
+            let stream = progress.stream(StreamKind::Audio);
+            if has_audio && filenames.audio.is_file() && !transfer::is_partial(&filenames.audio) {
+                println!("Reusing downloaded audio of {}", job.url);
+                reused(&stream, &filenames.audio).await;
+            } else {
+                self.jobs.record(job, JobState::DownloadingAudio)?;
+                self.fetch_stream(
+                    job,
+                    &site,
+                    Stage::DownloadingAudio,
+                    audio_format,
+                    &filenames.audio,
+                    &stream,
+                )
                 .await?;
+            }
         }
 
-        if let Some(video_format) = video.best_video_format() {
-            self.fetcher
-                .download_format(&video_format, &filenames.video)
+        if let Some(video_format) = video_format {
+            let stream = progress.stream(StreamKind::Video);
+            if has_video && filenames.video.is_file() && !transfer::is_partial(&filenames.video) {
+                println!("Reusing downloaded video of {}", job.url);
+                reused(&stream, &filenames.video).await;
+            } else {
+                self.jobs.record(job, JobState::DownloadingVideo)?;
+                self.fetch_stream(
+                    job,
+                    &site,
+                    Stage::DownloadingVideo,
+                    video_format,
+                    &filenames.video,
+                    &stream,
+                )
                 .await?;
+            }
         }
 
-        self.fetcher
-            .combine_audio_and_video(&filenames.audio, &filenames.video, &filenames.name)
-            .await?;
+        let merge = progress.stream(StreamKind::Merge);
+        self.finish(job, choice, mode, filenames, &merge).await
+    }
+
+    /// Writes the `.part` file of a job from its downloaded streams
+    ///
+    /// # Details
+    /// * Audio-only: the audio is converted to `Config::audio`'s codec, or
+    ///   moved as is when no conversion is needed
+    /// * Otherwise the streams to keep are merged or remuxed into the
+    ///   configured container, copying the ones it can hold; a single stream
+    ///   already in that container is moved as is
+    ///
+    /// Conversions and merges are retried and share the merge limit. Their
+    /// progress is the size of the `.part` file against the size of the
+    /// inputs, an estimate when streams are re-encoded.
+    async fn finish<'a>(
+        &self,
+        job: &DownloadJob,
+        choice: FormatChoice<'a>,
+        mode: DownloadMode,
+        filenames: &FileNames,
+        merge: &StreamProgress,
+    ) -> Result<()> {
+        let output = &filenames.part;
+        let audio_path = &filenames.audio;
+        let video_path = &filenames.video;
+
+        // Files to take each kind of stream from, with their codec
+        let (video, audio, progressive) = match choice {
+            FormatChoice::Progressive(format) => {
+                let input = |codec: Option<&'a str>| {
+                    Some(Input {
+                        path: video_path,
+                        codec,
+                    })
+                };
+                (
+                    input(format.codec_info.video_codec.as_deref()),
+                    input(format.codec_info.audio_codec.as_deref()),
+                    true,
+                )
+            }
+            FormatChoice::Separate { video, audio } => (
+                video.map(|format| Input {
+                    path: video_path,
+                    codec: format.codec_info.video_codec.as_deref(),
+                }),
+                audio.map(|format| Input {
+                    path: audio_path,
+                    codec: format.codec_info.audio_codec.as_deref(),
+                }),
+                false,
+            ),
+        };
+        let (video, audio) = match mode {
+            DownloadMode::AudioVideo => (video, audio),
+            DownloadMode::Audio => (None, audio),
+            DownloadMode::Video => (video, None),
+        };
+
+        if let (DownloadMode::Audio, Some(codec)) = (mode, self.audio_conversion(mode, choice)) {
+            let input = audio.map_or(audio_path.as_path(), |audio| audio.path);
+            let bitrate = self.config.audio.bitrate.as_deref();
+            self.jobs.record(job, JobState::Merging)?;
+            let work = retry(&self.config.retry, &job.url, Stage::Converting, || async {
+                let _permit = self
+                    .limits
+                    .merge
+                    .acquire()
+                    .await
+                    .expect("semaphores are never closed");
+                self.ffmpeg
+                    .extract_audio(input, output, codec, bitrate)
+                    .await
+            });
+            return track_output(output, merge, &[input], work).await;
+        }
+
+        let container = self.config.video.container;
+        let single = match (video, audio) {
+            (Some(stream), None) | (None, Some(stream)) if !progressive => Some(stream.path),
+            _ => None,
+        };
+        let in_place = single.filter(|path| {
+            mode == DownloadMode::Audio
+                || path.extension().and_then(|ext| ext.to_str()) == Some(container.extension())
+        });
+        if let Some(path) = in_place {
+            // Nothing to merge or remux, the only stream is the output
+            return move_file(path, output)
+                .await
+                .map_err(|e| AppError::from(e).at(&job.url, Stage::Saving));
+        }
+
+        self.jobs.record(job, JobState::Merging)?;
+        let work = retry(&self.config.retry, &job.url, Stage::Merging, || async {
+            let _permit = self
+                .limits
+                .merge
+                .acquire()
+                .await
+                .expect("semaphores are never closed");
+            self.ffmpeg.mux(video, audio, output, container).await
+        });
+        let mut inputs: Vec<&Path> = video.iter().chain(audio.iter()).map(|i| i.path).collect();
+        inputs.dedup();
+        track_output(output, merge, &inputs, work).await
+    }
+
+    /// Downloads one stream of a job, retried and within the limits of its site
+    async fn fetch_stream(
+        &self,
+        job: &DownloadJob,
+        site: &str,
+        stage: Stage,
+        format: &Format,
+        path: &Path,
+        stream: &StreamProgress,
+    ) -> Result<()> {
+        retry(&self.config.retry, &job.url, stage, || {
+            self.limited(&self.limits.download, site, async move {
+                self.download_stream(&job.url, site, format, path, stream)
+                    .await
+            })
+        })
+        .await
+    }
 
+    /// Downloads one stream of a video to a file
+    ///
+    /// # Details
+    /// Plain HTTPS formats go through `Transfer`, within the global bandwidth
+    /// limit; each of its range requests takes a token of the site's rate
+    /// limit. Other formats, e.g. HLS and DASH manifests, are downloaded by
+    /// yt-dlp, held to their share of the bandwidth limit in force when they
+    /// start; their bytes are counted and charged to the limit as yt-dlp
+    /// reports them.
+    async fn download_stream(
+        &self,
+        url: &str,
+        site: &str,
+        format: &Format,
+        path: &Path,
+        stream: &StreamProgress,
+    ) -> Result<()> {
+        if Transfer::supports(format) {
+            self.transfer.download(format, path, stream, site).await?;
+        } else {
+            let size = format
+                .file_info
+                .filesize
+                .or(format.file_info.filesize_approx)
+                .and_then(|size| u64::try_from(size).ok());
+            stream.start(0, size);
+            let bandwidth = self.transfer.bandwidth();
+            let rate = bandwidth.share(self.config.concurrent_downloads);
+            let mut charged = 0;
+            self.yt_dlp
+                .download_format(url, &format.format_id, path, rate, |downloaded| {
+                    bandwidth.charge(downloaded.saturating_sub(charged));
+                    charged = charged.max(downloaded);
+                    stream.set(downloaded);
+                })
+                .await?;
+            stream.set(tokio::fs::metadata(path).await?.len());
+            stream.finish();
+        }
         Ok(())
     }
 
@@ -172,51 +693,194 @@ impl Downloader {
     /// * `urls` - Vector of video URLs to process
     ///
     /// # Returns
-    /// * `Result<()>` - Overall success or error status
+    /// * `Result<DownloadSummary>` - Counts of succeeded and failed downloads
     ///
     /// # Details
-    /// * Manages concurrent downloads using a semaphore
-    /// * Tracks progress and provides statistics
+    /// Shorthand for `process_jobs` with jobs that have no overrides.
+    pub async fn process_urls(&self, urls: &[String]) -> Result<DownloadSummary> {
+        let jobs: Vec<DownloadJob> = urls.iter().cloned().map(DownloadJob::from).collect();
+        self.process_jobs(&jobs).await
+    }
+
+    /// Processes a list of jobs for concurrent downloading
+    ///
+    /// # Arguments
+    /// * `jobs` - Videos to download with their naming overrides
+    ///
+    /// # Returns
+    /// * `Result<DownloadSummary>` - Counts of succeeded and failed downloads
+    ///
+    /// # Details
+    /// Shorthand for `process_jobs_with_events` without an event listener.
+    pub async fn process_jobs(&self, jobs: &[DownloadJob]) -> Result<DownloadSummary> {
+        self.process_jobs_with_events(jobs, None).await
+    }
+
+    /// Processes a list of jobs, reporting each job's lifecycle as it happens
+    ///
+    /// # Arguments
+    /// * `jobs` - Videos to download with their naming overrides
+    /// * `events` - Receives a `JobEvent` when a job starts, completes or fails
+    ///
+    /// # Returns
+    /// * `Result<DownloadSummary>` - Counts of succeeded and failed downloads
+    ///
+    /// # Details
+    /// * Keeps up to `buffer_size` jobs in progress, whose stages share the
+    ///   fetch, download and merge limits with every other batch
+    /// * Tracks progress and provides statistics, printing the bytes of the
+    ///   running jobs every `progress_interval_secs`
     /// * Handles errors for individual downloads while continuing with others
-    pub async fn process_urls(&self, urls: &Vec<String>) -> Result<()> {
-        let total_videos = urls.len();
+    /// * Skips jobs the journal records as done whose output still exists,
+    ///   and videos in the download archive, unless `force` is set
+    /// * Runs jobs with the same `DownloadJob::id`, in this batch or another,
+    ///   one after the other, since they share a work directory
+    /// * The event sender is dropped when the batch ends, closing the channel
+    ///
+    /// # Errors
+    /// * If the jobs cannot be recorded in the journal
+    /// * If a job fails with a fatal error, after the running jobs finish;
+    ///   jobs not started yet fail with `AppError::Aborted`, a retryable error
+    pub async fn process_jobs_with_events(
+        &self,
+        jobs: &[DownloadJob],
+        events: Option<UnboundedSender<JobEvent>>,
+    ) -> Result<DownloadSummary> {
+        let total_videos = jobs.len();
         println!("Found {} videos to download", total_videos);
+        self.jobs.enqueue(jobs)?;
         let progress = Arc::new(Mutex::new(DownloadProgress::new(total_videos)));
+        let bytes = Arc::clone(&progress.lock().await.bytes);
+        let bytes = &bytes;
+        let fatal: std::sync::Mutex<Option<AppError>> = std::sync::Mutex::new(None);
+        let fatal = &fatal;
 
-        let download_tasks = stream::iter(urls.into_iter().enumerate())
-            .map(|(index, url)| {
+        let download_tasks = stream::iter(jobs.iter().enumerate())
+            .map(|(index, job)| {
                 let progress = Arc::clone(&progress);
-                let sem = Arc::clone(&self.semaphore);
+                let events = events.clone();
+                let notify = move |event: JobEvent| {
+                    if let Some(events) = &events {
+                        // The listener going away must not stop the downloads
+                        let _ = events.send(event);
+                    }
+                };
 
                 async move {
-                    let _permit = sem.acquire().await.unwrap();
+                    // A duplicate waits, then finds the job done instead of
+                    // sharing its work directory
+                    let _running = self.running.lock(&job.id()).await;
+                    let completed = self.jobs.completed_output(job, &self.config.output_dir);
+                    if let Some(output) = completed.filter(|_| !self.config.force) {
+                        println!(
+                            "Skipping video {}: already downloaded as {}",
+                            index + 1,
+                            output
+                        );
+                        notify(JobEvent::Completed { index, output });
+                        let mut progress = progress.lock().await;
+                        progress.set_bandwidth_limit(self.transfer.bandwidth().current_limit());
+                        progress.update(true);
+                        return;
+                    }
+
+                    let aborted = fatal
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .as_ref()
+                        .map(|e| AppError::Aborted {
+                            reason: e.to_string(),
+                        });
+                    if let Some(e) = aborted {
+                        if let Err(e) = self.jobs.fail(job, &e.to_string()) {
+                            eprintln!("Warning: could not journal failure: {}", e);
+                        }
+                        notify(JobEvent::Failed {
+                            index,
+                            code: e.code(),
+                            error: e.to_string(),
+                        });
+                        let mut progress = progress.lock().await;
+                        progress.record_failure(job, &e);
+                        progress.update(false);
+                        return;
+                    }
                     println!("Starting download for video {}", index + 1);
+                    notify(JobEvent::Started { index });
 
                     let start = std::time::Instant::now();
-                    let result = self.download_video(url, index + 1).await;
+                    let job_bytes = bytes.start_job(index + 1);
+                    let result = self.download_video(job, index + 1, &job_bytes).await;
+                    bytes.finish_job(&job_bytes);
                     let duration = start.elapsed();
 
                     let success = result.is_ok();
                     let mut progress_guard = progress.lock().await;
 
                     match result {
-                        Ok(_) => println!(
-                            "Video {} completed in {:.1}s",
-                            index + 1,
-                            duration.as_secs_f64()
-                        ),
+                        Ok(None) => {
+                            println!(
+                                "Skipping video {}: already in the download archive",
+                                index + 1
+                            );
+                            if let Err(e) = self.jobs.skip(job) {
+                                eprintln!("Warning: could not journal skipped job: {}", e);
+                            }
+                            notify(JobEvent::Skipped { index });
+                        }
+                        Ok(Some(output)) => {
+                            println!(
+                                "Video {} completed in {:.1}s",
+                                index + 1,
+                                duration.as_secs_f64()
+                            );
+                            notify(JobEvent::Completed { index, output });
+                        }
                         Err(e) => {
                             let error_msg = e.to_string();
-                            eprintln!("Failed to download video {}: {}", index + 1, error_msg);
-                            progress_guard.record_failure(url, error_msg);
+                            eprintln!(
+                                "Failed to download video {} [{}]: {}",
+                                index + 1,
+                                e.code(),
+                                error_msg
+                            );
+                            if let Err(e) = self.jobs.fail(job, &error_msg) {
+                                eprintln!("Warning: could not journal failure: {}", e);
+                            }
+                            progress_guard.record_failure(job, &e);
+                            notify(JobEvent::Failed {
+                                index,
+                                code: e.code(),
+                                error: error_msg,
+                            });
+                            if e.kind() == ErrorKind::Fatal {
+                                fatal
+                                    .lock()
+                                    .unwrap_or_else(|e| e.into_inner())
+                                    .get_or_insert(e);
+                            }
                         }
                     }
+                    progress_guard.set_bandwidth_limit(self.transfer.bandwidth().current_limit());
                     progress_guard.update(success);
                 }
             })
-            .buffer_unordered(10);
+            .buffer_unordered(self.config.buffer_size);
 
-        download_tasks.collect::<Vec<_>>().await;
+        let interval = Duration::from_secs(self.config.progress_interval_secs);
+        let report_bytes = async {
+            if interval.is_zero() {
+                return std::future::pending().await;
+            }
+            loop {
+                tokio::time::sleep(interval).await;
+                bytes.print();
+            }
+        };
+        tokio::select! {
+            _ = download_tasks.collect::<Vec<_>>() => {}
+            _ = report_bytes => {}
+        }
 
         // Print final statistics and export failures
         let final_progress = progress.lock().await;
@@ -231,11 +895,24 @@ impl Downloader {
         );
         println!("Failed downloads: {}", final_progress.errors);
 
-        if let Err(e) = final_progress.export_failures() {
+        if let Err(e) = final_progress.export_failures(&self.config.failure_report()) {
             eprintln!("Failed to export failure report: {}", e);
         }
 
-        Ok(())
+        if let Some(e) = fatal.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            return Err(e);
+        }
+        Ok(final_progress.summary())
+    }
+
+    /// Returns the journal of job states
+    pub fn jobs(&self) -> &JobStore {
+        &self.jobs
+    }
+
+    /// Returns the download archive, `None` when `download_archive` is unset
+    pub fn archive(&self) -> Option<&DownloadArchive> {
+        self.archive.as_ref()
     }
 
     /// Returns a reference to the configuration
@@ -247,6 +924,126 @@ impl Downloader {
     }
 }
 
+/// A video to download, with optional overrides from its source
+///
+/// # Fields
+/// * `url` - URL of the video
+/// * `title` - Title used in the output name instead of the video title
+/// * `folder` - Subdirectory of the output directory to save into
+/// * `mode` - What to keep of the video instead of `Config::mode`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadJob {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub folder: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<DownloadMode>,
+}
+
+impl DownloadJob {
+    /// Returns an identifier of the job that is the same in every run
+    ///
+    /// # Details
+    /// FNV-1a hash of the URL, output folder and mode if set, as 16 hex
+    /// digits. The title is left out so renaming a row does not download it
+    /// again.
+    pub fn id(&self) -> String {
+        let folder = self
+            .folder
+            .as_ref()
+            .map(|folder| folder.to_string_lossy())
+            .unwrap_or_default();
+        let mode = self
+            .mode
+            .map(|mode| format!("\0{}", mode))
+            .unwrap_or_default();
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in self
+            .url
+            .bytes()
+            .chain([0])
+            .chain(folder.bytes())
+            .chain(mode.bytes())
+        {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        format!("{:016x}", hash)
+    }
+
+    /// Returns the output subfolder, refusing paths that leave the output directory
+    ///
+    /// # Errors
+    /// * If the folder is absolute or contains `..`
+    fn output_folder(&self) -> Result<Option<PathBuf>> {
+        let Some(folder) = &self.folder else {
+            return Ok(None);
+        };
+        if folder
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            Ok(Some(folder.clone()))
+        } else {
+            Err(AppError::InvalidFolder {
+                folder: folder.clone(),
+            })
+        }
+    }
+}
+
+impl From<String> for DownloadJob {
+    fn from(url: String) -> Self {
+        Self {
+            url,
+            title: None,
+            folder: None,
+            mode: None,
+        }
+    }
+}
+
+/// Lifecycle notification for one job of a batch
+///
+/// `index` is the position of the job in the slice given to `process_jobs_with_events`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobEvent {
+    /// The job left the batch queue and is being processed
+    Started { index: usize },
+    /// The job finished, `output` is relative to the output directory
+    Completed { index: usize, output: String },
+    /// The job was not downloaded because its video is in the download archive
+    Skipped { index: usize },
+    /// The job failed with the code and message recorded in `DownloadProgress`
+    Failed {
+        index: usize,
+        code: &'static str,
+        error: String,
+    },
+}
+
+/// Permits for the stages of a job, shared by every batch
+///
+/// Fetches and merges are limited separately from downloads, so a job busy
+/// with a CPU-bound merge does not hold back the network-bound downloads.
+struct StageLimits {
+    fetch: Semaphore,
+    download: Semaphore,
+    merge: Semaphore,
+}
+
+impl StageLimits {
+    fn new(config: &Config) -> Self {
+        Self {
+            fetch: Semaphore::new(config.concurrent_fetches),
+            download: Semaphore::new(config.concurrent_downloads),
+            merge: Semaphore::new(config.concurrent_merges),
+        }
+    }
+}
+
 /// RAII guard for tracking active downloads
 ///
 /// Automatically increments counter on creation and
@@ -268,14 +1065,261 @@ impl<'a> Drop for DownloadGuard<'a> {
     }
 }
 
+/// Locks of the jobs in progress, keyed by `DownloadJob::id`
+#[derive(Default)]
+struct JobLocks {
+    locks: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl JobLocks {
+    /// Waits until no other job with this id runs, then holds the id until dropped
+    async fn lock(&self, id: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+            // Locks only the map holds are neither held nor awaited
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            Arc::clone(locks.entry(id.to_string()).or_default())
+        };
+        lock.lock_owned().await
+    }
+}
+
 /// Structure holding temporary and final filenames for a download
 ///
 /// # Fields
-/// * `audio` - Temporary audio file name
-/// * `video` - Temporary video file name
-/// * `name` - Final output file name
+/// * `dir` - Work directory of the job, named after `DownloadJob::id`
+/// * `audio` - Temporary audio file, in the work directory
+/// * `video` - Temporary video file, in the work directory
+/// * `part` - Output being written, in the work directory
+/// * `output` - Final output file
+/// * `name` - Final output file name, relative to the output directory
 struct FileNames {
-    audio: String,
-    video: String,
+    dir: PathBuf,
+    audio: PathBuf,
+    video: PathBuf,
+    part: PathBuf,
+    output: PathBuf,
     name: String,
 }
+
+/// Removes the work directories of finished and unknown jobs
+///
+/// # Details
+/// Directories of jobs that were interrupted or failed are kept, so running
+/// them again reuses or resumes their streams. Only entries named like a
+/// job id are touched.
+fn sweep_temp_dirs(temp_dir: &Path, jobs: &JobStore) -> Result<()> {
+    let unfinished = jobs.resumable_ids();
+    for entry in std::fs::read_dir(temp_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_job = name.len() == 16 && name.chars().all(|c| c.is_ascii_hexdigit());
+        if !is_job || unfinished.contains(&name) {
+            continue;
+        }
+        if let Err(e) = std::fs::remove_dir_all(entry.path()) {
+            eprintln!(
+                "Warning: Could not delete orphaned work directory {:?}: {}",
+                entry.path(),
+                e
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Installs the binaries to `.update/` and moves them over the installed ones
+///
+/// # Arguments
+/// * `libraries_dir` - Directory of the installed binaries
+/// * `install` - Installs yt-dlp and ffmpeg to the empty directory it is given
+///
+/// # Details
+/// Nothing is replaced unless `install` succeeds. The staging directory is
+/// removed in any case, along with any left by an interrupted update.
+async fn replace_binaries<F: Future<Output = Result<()>>>(
+    libraries_dir: &Path,
+    install: impl FnOnce(PathBuf) -> F,
+) -> Result<()> {
+    let staging = libraries_dir.join(".update");
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::create_dir_all(&staging)?;
+
+    let result = async {
+        install(staging.clone()).await?;
+        for name in ["yt-dlp", "ffmpeg"] {
+            std::fs::rename(staging.join(name), libraries_dir.join(name))?;
+        }
+        Ok(())
+    }
+    .await;
+    let _ = std::fs::remove_dir_all(&staging);
+    result
+}
+
+/// Counts a stream a previous attempt downloaded as complete, nothing transferred
+async fn reused(stream: &StreamProgress, path: &Path) {
+    let size = tokio::fs::metadata(path).await.map_or(0, |m| m.len());
+    stream.start(size, Some(size));
+    stream.finish();
+}
+
+/// Runs an ffmpeg step, counting the size of the file it writes
+///
+/// # Arguments
+/// * `output` - File ffmpeg writes
+/// * `stream` - Counter of the step
+/// * `inputs` - Files ffmpeg reads, their total size is the expected size
+/// * `work` - The ffmpeg step
+async fn track_output(
+    output: &Path,
+    stream: &StreamProgress,
+    inputs: &[&Path],
+    work: impl Future<Output = Result<()>>,
+) -> Result<()> {
+    let mut total = 0;
+    for input in inputs {
+        total += tokio::fs::metadata(input).await.map_or(0, |m| m.len());
+    }
+    stream.start(0, Some(total));
+
+    let poll = async {
+        loop {
+            tokio::time::sleep(OUTPUT_POLL_INTERVAL).await;
+            if let Ok(metadata) = tokio::fs::metadata(output).await {
+                stream.set(metadata.len());
+            }
+        }
+    };
+    let result = tokio::select! {
+        result = work => result,
+        _ = poll => Ok(()),
+    };
+    if result.is_ok() {
+        stream.set(tokio::fs::metadata(output).await.map_or(0, |m| m.len()));
+        stream.finish();
+    }
+    result
+}
+
+/// Moves a finished output from the work directory to its name
+///
+/// # Details
+/// Across filesystems the file is copied next to the output as `.part`
+/// first, so the output name never holds an incomplete file.
+async fn save_output(part: &Path, output: &Path) -> std::io::Result<()> {
+    if tokio::fs::rename(part, output).await.is_ok() {
+        return Ok(());
+    }
+    let copy = PathBuf::from(format!("{}.part", output.display()));
+    tokio::fs::copy(part, &copy).await?;
+    tokio::fs::rename(&copy, output).await?;
+    tokio::fs::remove_file(part).await
+}
+
+/// Moves a file, copying it when the work and output directories are on
+/// different filesystems
+async fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if tokio::fs::rename(from, to).await.is_ok() {
+        return Ok(());
+    }
+    tokio::fs::copy(from, to).await?;
+    tokio::fs::remove_file(from).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn job_locks_serialize_the_same_id() {
+        let locks = JobLocks::default();
+        let first = locks.lock("a").await;
+        let _other = locks.lock("b").await;
+
+        let waiting = locks.lock("a");
+        tokio::pin!(waiting);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), &mut waiting)
+                .await
+                .is_err()
+        );
+        drop(first);
+        tokio::time::timeout(Duration::from_millis(50), waiting)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn save_output_replaces_the_part_file() {
+        let dir = std::env::temp_dir().join(format!("ytb-downloader-save-{}", std::process::id()));
+        tokio::fs::create_dir_all(dir.join("work")).await.unwrap();
+        let part = dir.join("work").join("output.part");
+        let output = dir.join("video.mp4");
+        tokio::fs::write(&part, b"video").await.unwrap();
+
+        save_output(&part, &output).await.unwrap();
+        assert!(!part.exists());
+        assert_eq!(tokio::fs::read(&output).await.unwrap(), b"video");
+        assert!(!dir.join("video.mp4.part").exists());
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn replace_binaries_moves_installed_binaries_into_place() {
+        let dir = std::env::temp_dir().join(format!("ytb-downloader-deps-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join(".update")).unwrap();
+        std::fs::write(dir.join(".update").join("stale"), b"").unwrap();
+        std::fs::write(dir.join("yt-dlp"), b"old yt-dlp").unwrap();
+
+        replace_binaries(&dir, |staging| async move {
+            assert!(!staging.join("stale").exists());
+            std::fs::write(staging.join("yt-dlp"), b"new yt-dlp")?;
+            std::fs::write(staging.join("ffmpeg"), b"new ffmpeg")?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(dir.join("yt-dlp")).unwrap(), b"new yt-dlp");
+        assert_eq!(std::fs::read(dir.join("ffmpeg")).unwrap(), b"new ffmpeg");
+        assert!(!dir.join(".update").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replace_binaries_keeps_installed_binaries_on_failure() {
+        let dir =
+            std::env::temp_dir().join(format!("ytb-downloader-deps-fail-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("yt-dlp"), b"old yt-dlp").unwrap();
+        std::fs::write(dir.join("ffmpeg"), b"old ffmpeg").unwrap();
+
+        // yt-dlp downloaded, ffmpeg failed
+        let result = replace_binaries(&dir, |staging| async move {
+            std::fs::write(staging.join("yt-dlp"), b"new yt-dlp")?;
+            Err(AppError::Config("download failed".to_string()))
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(std::fs::read(dir.join("yt-dlp")).unwrap(), b"old yt-dlp");
+        assert_eq!(std::fs::read(dir.join("ffmpeg")).unwrap(), b"old ffmpeg");
+        assert!(!dir.join(".update").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn job_locks_forget_released_ids() {
+        let locks = JobLocks::default();
+        drop(locks.lock("a").await);
+        let _held = locks.lock("b").await;
+        let ids: Vec<String> = locks.locks.lock().unwrap().keys().cloned().collect();
+        assert_eq!(ids, ["b"]);
+    }
+}
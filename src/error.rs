@@ -1,15 +1,90 @@
+//! Error types for the application.
+//!
+//! Defines a comprehensive error handling system that covers:
+//! - IO operations
+//! - Network requests
+//! - URL parsing
+//! - Video processing
+//! - External service interactions
+//!
+//! Every error has a `kind`, telling whether retrying can help and whether
+//! the run can go on, and a stable `code` used in failure reports and logs.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
 use thiserror::Error;
 use url;
 
-/// Error types for the application.
+/// yt-dlp messages mapped to a code and kind, matched case-insensitively
 ///
-/// Defines a comprehensive error handling system that covers:
-/// - IO operations
-/// - Network requests
-/// - URL parsing
-/// - Video processing
-/// - External service interactions
+/// The first matching marker wins, so permanent causes come first. Messages
+/// without a marker are taken as transient, see `classify_message`.
+const MESSAGE_CODES: [(&str, &str, ErrorKind); 29] = [
+    ("private video", "video_private", ErrorKind::Permanent),
+    ("members-only", "video_private", ErrorKind::Permanent),
+    ("join this channel", "video_private", ErrorKind::Permanent),
+    (
+        "video unavailable",
+        "video_unavailable",
+        ErrorKind::Permanent,
+    ),
+    (
+        "has been removed",
+        "video_unavailable",
+        ErrorKind::Permanent,
+    ),
+    (
+        "account associated with this video has been terminated",
+        "video_unavailable",
+        ErrorKind::Permanent,
+    ),
+    ("copyright", "video_unavailable", ErrorKind::Permanent),
+    ("http error 404", "video_unavailable", ErrorKind::Permanent),
+    (
+        "not available in your country",
+        "geo_blocked",
+        ErrorKind::Permanent,
+    ),
+    ("geo restricted", "geo_blocked", ErrorKind::Permanent),
+    ("geo-restricted", "geo_blocked", ErrorKind::Permanent),
+    (
+        "blocked it in your country",
+        "geo_blocked",
+        ErrorKind::Permanent,
+    ),
+    (
+        "sign in to confirm your age",
+        "age_restricted",
+        ErrorKind::Permanent,
+    ),
+    ("unsupported url", "unsupported_url", ErrorKind::Permanent),
+    (
+        "requested format is not available",
+        "no_format",
+        ErrorKind::Permanent,
+    ),
+    ("drm protected", "video_unavailable", ErrorKind::Permanent),
+    ("http error 429", "rate_limited", ErrorKind::Retryable),
+    ("too many requests", "rate_limited", ErrorKind::Retryable),
+    ("http error 500", "server_error", ErrorKind::Retryable),
+    ("http error 502", "server_error", ErrorKind::Retryable),
+    ("http error 503", "server_error", ErrorKind::Retryable),
+    ("http error 504", "server_error", ErrorKind::Retryable),
+    ("timed out", "network", ErrorKind::Retryable),
+    ("timeout", "network", ErrorKind::Retryable),
+    ("connection reset", "network", ErrorKind::Retryable),
+    ("connection refused", "network", ErrorKind::Retryable),
+    ("connection aborted", "network", ErrorKind::Retryable),
+    (
+        "temporary failure in name resolution",
+        "network",
+        ErrorKind::Retryable,
+    ),
+    ("network is unreachable", "network", ErrorKind::Retryable),
+];
 
 /// Represents all possible errors that can occur in the application.
 ///
@@ -19,18 +94,63 @@ use url;
 /// - Network: HTTP requests and responses
 /// - Parsing: URL and data parsing
 /// - Youtube: Video download and processing
-/// - Custom: Application-specific errors
+/// - Ffmpeg: Audio conversion
+/// - YtDlp: Manifest based stream downloads
+/// - Job: Failure of one stage of a download, wrapping its cause
+/// - Sheet: Unreadable or unreachable Google Sheet
+/// - Config: Invalid or unreadable configuration
+/// - Watch: Filesystem notifications
+/// - Aborted: Job left out of a batch stopped by a fatal error
 #[derive(Error, Debug)]
 pub enum AppError {
     #[error("IO error: {0}")]
     Io(#[from] io::Error),
 
-    #[error("Download error: {0}")]
-    Download(String),
+    #[error("{stage} failed for {url}: {source}")]
+    Job {
+        url: String,
+        stage: Stage,
+        /// Number of times the stage was tried
+        attempts: u32,
+        #[source]
+        source: Box<AppError>,
+    },
+
+    #[error("Output folder {folder:?} must be relative to the output directory")]
+    InvalidFolder { folder: PathBuf },
+
+    #[error("No format of video {video} matches the format policy")]
+    NoFormat { video: String },
+
+    #[error("ffmpeg failed: {message}")]
+    Ffmpeg { message: String },
+
+    #[error("yt-dlp failed: {message}")]
+    YtDlp { message: String },
+
+    #[error("`{input}` is neither a file nor a URL: {source}")]
+    InvalidInput {
+        input: String,
+        #[source]
+        source: url::ParseError,
+    },
 
     #[error("Sheet error: {0}")]
     Sheet(String),
 
+    #[error("Failed to fetch sheet {url}: {source}")]
+    SheetFetch {
+        url: String,
+        #[source]
+        source: reqwest::Error,
+    },
+
+    #[error("Sheet request to {url} failed with status {status}")]
+    SheetStatus { url: String, status: u16 },
+
+    #[error("Config error: {0}")]
+    Config(String),
+
     #[error("Youtube error: {0}")]
     Youtube(#[from] yt_dlp::error::Error),
 
@@ -40,20 +160,366 @@ pub enum AppError {
     #[error("URL parse error: {0}")]
     UrlParse(#[from] url::ParseError),
 
-    #[error("{0}")]
-    Custom(String),
+    #[error("Watch error: {0}")]
+    Watch(#[from] notify::Error),
+
+    #[error("Not started, the batch was aborted: {reason}")]
+    Aborted { reason: String },
+}
+
+/// How an error affects the work that raised it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorKind {
+    /// The same operation may succeed if tried again later
+    Retryable,
+    /// The operation will keep failing, but other jobs can go on
+    Permanent,
+    /// The run itself cannot go on
+    Fatal,
+}
+
+impl FromStr for ErrorKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> std::result::Result<Self, Self::Err> {
+        match kind {
+            "retryable" => Ok(ErrorKind::Retryable),
+            "permanent" => Ok(ErrorKind::Permanent),
+            "fatal" => Ok(ErrorKind::Fatal),
+            _ => Err(format!(
+                "unknown error kind `{}`, expected retryable, permanent or fatal",
+                kind
+            )),
+        }
+    }
 }
 
-impl From<&str> for AppError {
-    fn from(error: &str) -> Self {
-        AppError::Custom(error.to_string())
+/// Step of a download job
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    FetchingInfo,
+    DownloadingAudio,
+    DownloadingVideo,
+    Merging,
+    Converting,
+    Saving,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Stage::FetchingInfo => "fetching video info",
+            Stage::DownloadingAudio => "downloading audio",
+            Stage::DownloadingVideo => "downloading video",
+            Stage::Merging => "merging",
+            Stage::Converting => "converting audio",
+            Stage::Saving => "saving",
+        };
+        f.write_str(name)
+    }
+}
+
+impl AppError {
+    /// Wraps the error with the URL and stage of the job it interrupted
+    pub fn at(self, url: &str, stage: Stage) -> Self {
+        self.after_attempts(url, stage, 1)
+    }
+
+    /// Wraps the error of the last of several attempts of a stage
+    pub fn after_attempts(self, url: &str, stage: Stage, attempts: u32) -> Self {
+        AppError::Job {
+            url: url.to_string(),
+            stage,
+            attempts,
+            source: Box::new(self),
+        }
+    }
+
+    /// Returns how many times the failed operation was tried
+    pub fn attempts(&self) -> u32 {
+        match self {
+            AppError::Job { attempts, .. } => *attempts,
+            _ => 1,
+        }
+    }
+
+    /// Returns whether the error is worth retrying and whether the run can go on
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use application::error::{ErrorKind, Stage};
+    /// use application::AppError;
+    ///
+    /// let timeout = std::io::Error::new(std::io::ErrorKind::TimedOut, "read timed out");
+    /// let error = AppError::Io(timeout).at("https://youtu.be/x", Stage::DownloadingAudio);
+    /// assert_eq!(error.kind(), ErrorKind::Retryable);
+    /// assert_eq!(error.code(), "network");
+    ///
+    /// let error = AppError::Config("buffer_size must be greater than 0".to_string());
+    /// assert_eq!(error.kind(), ErrorKind::Fatal);
+    /// ```
+    pub fn kind(&self) -> ErrorKind {
+        self.classify().1
+    }
+
+    /// Returns a stable, machine-readable code of the error, e.g. `video_private`
+    pub fn code(&self) -> &'static str {
+        self.classify().0
+    }
+
+    fn classify(&self) -> (&'static str, ErrorKind) {
+        match self {
+            AppError::Job { source, .. } => source.classify(),
+            AppError::Io(e) => classify_io(e),
+            AppError::Request(e) | AppError::SheetFetch { source: e, .. } => classify_request(e),
+            AppError::SheetStatus { status, .. } => classify_status(*status),
+            AppError::Youtube(e) => classify_message(&e.to_string()),
+            AppError::YtDlp { message } => classify_message(message),
+            AppError::InvalidFolder { .. } => ("invalid_folder", ErrorKind::Permanent),
+            AppError::InvalidInput { .. } => ("invalid_input", ErrorKind::Permanent),
+            AppError::NoFormat { .. } => ("no_format", ErrorKind::Permanent),
+            AppError::Ffmpeg { .. } => ("ffmpeg", ErrorKind::Permanent),
+            AppError::UrlParse(_) => ("invalid_url", ErrorKind::Permanent),
+            AppError::Sheet(_) => ("sheet", ErrorKind::Fatal),
+            AppError::Config(_) => ("config", ErrorKind::Fatal),
+            AppError::Watch(_) => ("watch", ErrorKind::Fatal),
+            AppError::Aborted { .. } => ("aborted", ErrorKind::Retryable),
+        }
     }
 }
 
-impl From<String> for AppError {
-    fn from(error: String) -> Self {
-        AppError::Custom(error)
+fn classify_io(error: &io::Error) -> (&'static str, ErrorKind) {
+    match error.kind() {
+        io::ErrorKind::TimedOut
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::UnexpectedEof => ("network", ErrorKind::Retryable),
+        io::ErrorKind::PermissionDenied
+        | io::ErrorKind::StorageFull
+        | io::ErrorKind::ReadOnlyFilesystem
+        | io::ErrorKind::OutOfMemory => ("io", ErrorKind::Fatal),
+        _ => ("io", ErrorKind::Permanent),
     }
 }
 
+fn classify_request(error: &reqwest::Error) -> (&'static str, ErrorKind) {
+    if error.is_timeout() || error.is_connect() {
+        return ("network", ErrorKind::Retryable);
+    }
+    match error.status() {
+        Some(status) => classify_status(status.as_u16()),
+        // The connection broke while sending the request or reading the body
+        None if error.is_request() || error.is_body() => ("network", ErrorKind::Retryable),
+        None => ("http", ErrorKind::Permanent),
+    }
+}
+
+fn classify_status(status: u16) -> (&'static str, ErrorKind) {
+    match status {
+        429 => ("rate_limited", ErrorKind::Retryable),
+        500..=599 => ("server_error", ErrorKind::Retryable),
+        _ => ("http", ErrorKind::Permanent),
+    }
+}
+
+/// Classifies an error reported as text
+///
+/// Unknown messages are retryable: yt-dlp reports most network trouble,
+/// like truncated reads, SSL resets or "Unable to download webpage", in
+/// words of its own, and the retry policy bounds what a permanent failure
+/// mistaken for a transient one costs.
+fn classify_message(message: &str) -> (&'static str, ErrorKind) {
+    let message = message.to_lowercase();
+    MESSAGE_CODES
+        .iter()
+        .find(|(marker, _, _)| message.contains(marker))
+        .map(|(_, code, kind)| (*code, *kind))
+        .unwrap_or(("yt_dlp", ErrorKind::Retryable))
+}
+
 pub type Result<T> = std::result::Result<T, AppError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn classify_io_by_kind() {
+        let cases = [
+            (io::ErrorKind::TimedOut, "network", ErrorKind::Retryable),
+            (
+                io::ErrorKind::ConnectionReset,
+                "network",
+                ErrorKind::Retryable,
+            ),
+            (
+                io::ErrorKind::UnexpectedEof,
+                "network",
+                ErrorKind::Retryable,
+            ),
+            (io::ErrorKind::PermissionDenied, "io", ErrorKind::Fatal),
+            (io::ErrorKind::StorageFull, "io", ErrorKind::Fatal),
+            (io::ErrorKind::NotFound, "io", ErrorKind::Permanent),
+            (io::ErrorKind::InvalidData, "io", ErrorKind::Permanent),
+        ];
+        for (kind, code, expected) in cases {
+            assert_eq!(
+                classify_io(&io::Error::new(kind, "test")),
+                (code, expected),
+                "{:?}",
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn classify_status_by_code() {
+        let cases = [
+            (429, "rate_limited", ErrorKind::Retryable),
+            (500, "server_error", ErrorKind::Retryable),
+            (503, "server_error", ErrorKind::Retryable),
+            (599, "server_error", ErrorKind::Retryable),
+            (403, "http", ErrorKind::Permanent),
+            (404, "http", ErrorKind::Permanent),
+            (301, "http", ErrorKind::Permanent),
+        ];
+        for (status, code, kind) in cases {
+            assert_eq!(classify_status(status), (code, kind), "{}", status);
+        }
+    }
+
+    /// Serves one HTTP response with the given status, returning its URL
+    async fn serve_status(status: u16) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let response = format!(
+                "HTTP/1.1 {} Test\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                status
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn classify_request_by_failure() {
+        let client = reqwest::Client::new();
+
+        for (status, code, kind) in [
+            (429, "rate_limited", ErrorKind::Retryable),
+            (502, "server_error", ErrorKind::Retryable),
+            (404, "http", ErrorKind::Permanent),
+        ] {
+            let error = client
+                .get(serve_status(status).await)
+                .send()
+                .await
+                .unwrap()
+                .error_for_status()
+                .unwrap_err();
+            assert_eq!(classify_request(&error), (code, kind), "{}", status);
+        }
+
+        // Nothing listens on a port freed right after binding it
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let error = client.get(closed).send().await.unwrap_err();
+        assert_eq!(classify_request(&error), ("network", ErrorKind::Retryable));
+
+        let error = client.get("not a url").send().await.unwrap_err();
+        assert_eq!(classify_request(&error), ("http", ErrorKind::Permanent));
+    }
+
+    #[test]
+    fn classify_message_by_marker() {
+        let cases = [
+            (
+                "ERROR: [youtube] abc: Private video. Sign in if you've been granted access",
+                "video_private",
+                ErrorKind::Permanent,
+            ),
+            (
+                "ERROR: [youtube] abc: Video unavailable",
+                "video_unavailable",
+                ErrorKind::Permanent,
+            ),
+            (
+                "ERROR: Unsupported URL: https://example.com/",
+                "unsupported_url",
+                ErrorKind::Permanent,
+            ),
+            (
+                "ERROR: unable to download video data: HTTP Error 429: Too Many Requests",
+                "rate_limited",
+                ErrorKind::Retryable,
+            ),
+            (
+                "ERROR: unable to download video data: HTTP Error 503: Service Unavailable",
+                "server_error",
+                ErrorKind::Retryable,
+            ),
+            ("Read timed out.", "network", ErrorKind::Retryable),
+            // Transient failures without a marker of their own
+            (
+                "ERROR: [youtube] abc: Unable to download webpage: <urlopen error EOF occurred in violation of protocol (_ssl.c:1129)>",
+                "yt_dlp",
+                ErrorKind::Retryable,
+            ),
+            (
+                "ERROR: 1024 bytes read, 2048 more expected",
+                "yt_dlp",
+                ErrorKind::Retryable,
+            ),
+        ];
+        for (message, code, kind) in cases {
+            assert_eq!(classify_message(message), (code, kind), "{}", message);
+        }
+    }
+
+    #[test]
+    fn classify_message_prefers_permanent_markers() {
+        let cases = [
+            (
+                "HTTP Error 429 while checking: Private video",
+                "video_private",
+            ),
+            ("Video unavailable (HTTP Error 503)", "video_unavailable"),
+            (
+                "Sign in to confirm your age, connection reset",
+                "age_restricted",
+            ),
+        ];
+        for (message, code) in cases {
+            assert_eq!(
+                classify_message(message),
+                (code, ErrorKind::Permanent),
+                "{}",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn job_errors_take_the_kind_of_their_cause() {
+        let error = AppError::YtDlp {
+            message: "Private video".to_string(),
+        }
+        .after_attempts("https://youtu.be/x", Stage::FetchingInfo, 3);
+        assert_eq!(error.code(), "video_private");
+        assert_eq!(error.kind(), ErrorKind::Permanent);
+        assert_eq!(error.attempts(), 3);
+
+        let error = AppError::Watch(notify::Error::generic("gone"));
+        assert_eq!((error.code(), error.kind()), ("watch", ErrorKind::Fatal));
+    }
+}
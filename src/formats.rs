@@ -0,0 +1,582 @@
+//! Format selection.
+//!
+//! Picks the streams to download among the formats yt-dlp reports for a
+//! video, within the limits of the `[formats]` policy: resolution, frame
+//! rate and file size caps, preferred codecs, and whether a single format
+//! holding both audio and video is preferred over merging two streams.
+//! In audio-only and video-only modes, only a stream of that kind is picked
+//! when there is one. Between formats of the same quality, plain HTTPS ones
+//! are picked over manifest based ones (HLS, DASH), which yt-dlp has to
+//! download.
+
+use crate::bandwidth::parse_size;
+use crate::error::{AppError, Result};
+use crate::transfer::Transfer;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+use yt_dlp::model::format::Format;
+use yt_dlp::model::Video;
+
+/// Format selection settings, the `[formats]` table of the configuration
+///
+/// Formats above a cap are never chosen. Among the others, codecs listed
+/// earlier win, then the highest quality; plain HTTPS formats only win over
+/// manifest based ones of the same quality. Codecs are named like `h264`,
+/// `h265`, `vp9`, `av1`, `opus`, `aac` or by their yt-dlp prefix, e.g. `avc1`.
+///
+/// ```toml
+/// [formats]
+/// max_height = 1080
+/// max_fps = 30
+/// video_codecs = ["h264", "vp9"]
+/// audio_codecs = ["opus", "aac"]
+/// max_filesize = "2GB"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FormatPolicy {
+    /// Highest video height in pixels, e.g. 1080
+    pub max_height: Option<u32>,
+    /// Highest frame rate
+    pub max_fps: Option<f64>,
+    /// Preferred video codecs, best first
+    pub video_codecs: Vec<String>,
+    /// Preferred audio codecs, best first
+    pub audio_codecs: Vec<String>,
+    /// Largest size of each downloaded stream, e.g. `"500MB"`, when known
+    pub max_filesize: Option<String>,
+    /// Download one format holding audio and video, when one fits, instead of merging
+    pub prefer_progressive: bool,
+}
+
+/// What a job keeps of a video
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DownloadMode {
+    /// Audio and video, merged into one file
+    #[default]
+    AudioVideo,
+    /// Only the audio, converted according to `Config::audio`
+    Audio,
+    /// Only the video, without sound
+    Video,
+}
+
+impl fmt::Display for DownloadMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DownloadMode::AudioVideo => "audio-video",
+            DownloadMode::Audio => "audio",
+            DownloadMode::Video => "video",
+        })
+    }
+}
+
+impl FromStr for DownloadMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> std::result::Result<Self, Self::Err> {
+        match mode.trim().to_lowercase().as_str() {
+            "audio-video" => Ok(DownloadMode::AudioVideo),
+            "audio" => Ok(DownloadMode::Audio),
+            "video" => Ok(DownloadMode::Video),
+            _ => Err(format!(
+                "unknown download mode `{}`, expected audio-video, audio or video",
+                mode
+            )),
+        }
+    }
+}
+
+/// Streams chosen for a video
+#[derive(Debug, Clone, Copy)]
+pub enum FormatChoice<'a> {
+    /// One format holding both audio and video, saved as is
+    Progressive(&'a Format),
+    /// Separate streams, at least one of them set; merged when both are
+    Separate {
+        video: Option<&'a Format>,
+        audio: Option<&'a Format>,
+    },
+}
+
+impl fmt::Display for FormatChoice<'_> {
+    /// Writes the format ids the way yt-dlp does, e.g. `137+251`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatChoice::Progressive(format) => f.write_str(&format.format_id),
+            FormatChoice::Separate { video, audio } => {
+                let ids: Vec<&str> = [video, audio]
+                    .into_iter()
+                    .flatten()
+                    .map(|format| format.format_id.as_str())
+                    .collect();
+                f.write_str(&ids.join("+"))
+            }
+        }
+    }
+}
+
+impl FormatPolicy {
+    /// Checks that the caps are usable
+    ///
+    /// # Errors
+    /// * If `max_filesize` is not a size or a cap is 0
+    pub fn validate(&self) -> Result<()> {
+        self.filesize_cap()?;
+        if self.max_height == Some(0) {
+            return Err(AppError::Config(
+                "formats.max_height must be greater than 0".to_string(),
+            ));
+        }
+        if self.max_fps.is_some_and(|fps| fps <= 0.0) {
+            return Err(AppError::Config(
+                "formats.max_fps must be greater than 0".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Chooses the formats to download for a video
+    ///
+    /// # Details
+    /// Separate video and audio streams are preferred, unless
+    /// `prefer_progressive` is set. When one kind of stream is missing, or
+    /// none fits the policy, a progressive format is used if one fits, or
+    /// the remaining stream on its own.
+    ///
+    /// In audio-only and video-only modes the best stream of that kind is
+    /// chosen, or a progressive format to extract it from if there is none.
+    ///
+    /// # Errors
+    /// * `AppError::NoFormat` if no format of the video fits the policy
+    pub fn select<'a>(&self, video: &'a Video, mode: DownloadMode) -> Result<FormatChoice<'a>> {
+        let max_filesize = self.filesize_cap()?;
+        let exceeds = |format: &&Format| {
+            let size = format
+                .file_info
+                .filesize
+                .or(format.file_info.filesize_approx)
+                .and_then(|size| u64::try_from(size).ok());
+            let height = format.video_resolution.height;
+            let fps = format.video_resolution.fps.map(f64::from);
+            matches!((max_filesize, size), (Some(max), Some(size)) if size > max)
+                || matches!((self.max_height, height), (Some(max), Some(height)) if height > max)
+                || matches!((self.max_fps, fps), (Some(max), Some(fps)) if fps > max + 0.5)
+        };
+        let candidates = || video.formats.iter().filter(|format| !exceeds(format));
+
+        let progressive = candidates()
+            .filter(|format| has_video(format) && has_audio(format))
+            .max_by(|a, b| self.compare_video(a, b));
+        let video_only = candidates()
+            .filter(|format| has_video(format) && !has_audio(format))
+            .max_by(|a, b| self.compare_video(a, b));
+        let audio_only = candidates()
+            .filter(|format| has_audio(format) && !has_video(format))
+            .max_by(|a, b| self.compare_audio(a, b));
+
+        let no_format = || AppError::NoFormat {
+            video: video.id.clone(),
+        };
+        let choice = match (mode, progressive, video_only, audio_only) {
+            (DownloadMode::Audio, _, _, Some(audio)) => FormatChoice::Separate {
+                video: None,
+                audio: Some(audio),
+            },
+            (DownloadMode::Video, _, Some(video), _) => FormatChoice::Separate {
+                video: Some(video),
+                audio: None,
+            },
+            (DownloadMode::Audio | DownloadMode::Video, Some(format), _, _) => {
+                FormatChoice::Progressive(format)
+            }
+            (DownloadMode::Audio | DownloadMode::Video, None, _, _) => return Err(no_format()),
+            (_, Some(format), _, _) if self.prefer_progressive => FormatChoice::Progressive(format),
+            (_, _, Some(video), Some(audio)) => FormatChoice::Separate {
+                video: Some(video),
+                audio: Some(audio),
+            },
+            (_, Some(format), _, _) => FormatChoice::Progressive(format),
+            (_, None, None, None) => return Err(no_format()),
+            (_, None, video, audio) => FormatChoice::Separate { video, audio },
+        };
+        Ok(choice)
+    }
+
+    fn filesize_cap(&self) -> Result<Option<u64>> {
+        self.max_filesize
+            .as_deref()
+            .map(|size| {
+                parse_size(size)
+                    .ok()
+                    .filter(|size| *size > 0)
+                    .ok_or_else(|| {
+                        AppError::Config(format!("formats.max_filesize: invalid size `{}`", size))
+                    })
+            })
+            .transpose()
+    }
+
+    /// Orders video formats by codec preference, resolution and frame rate,
+    /// then by protocol and bitrate
+    fn compare_video(&self, a: &Format, b: &Format) -> Ordering {
+        let quality = |format: &Format| {
+            (
+                height(format),
+                format.video_resolution.fps.map(f64::from).unwrap_or(0.0),
+            )
+        };
+        let bitrate = |format: &Format| format.rates_info.total_rate.map(f64::from).unwrap_or(0.0);
+        codec_rank(&self.video_codecs, a.codec_info.video_codec.as_deref())
+            .cmp(&codec_rank(
+                &self.video_codecs,
+                b.codec_info.video_codec.as_deref(),
+            ))
+            .reverse()
+            .then_with(|| {
+                quality(a)
+                    .partial_cmp(&quality(b))
+                    .unwrap_or(Ordering::Equal)
+            })
+            .then_with(|| Transfer::supports(a).cmp(&Transfer::supports(b)))
+            .then_with(|| {
+                bitrate(a)
+                    .partial_cmp(&bitrate(b))
+                    .unwrap_or(Ordering::Equal)
+            })
+    }
+
+    /// Orders audio formats by codec preference, bitrate and sample rate, then by protocol
+    fn compare_audio(&self, a: &Format, b: &Format) -> Ordering {
+        let quality = |format: &Format| {
+            (
+                format.rates_info.audio_rate.map(f64::from).unwrap_or(0.0),
+                format.codec_info.asr.unwrap_or(0) as f64,
+            )
+        };
+        codec_rank(&self.audio_codecs, a.codec_info.audio_codec.as_deref())
+            .cmp(&codec_rank(
+                &self.audio_codecs,
+                b.codec_info.audio_codec.as_deref(),
+            ))
+            .reverse()
+            .then_with(|| {
+                quality(a)
+                    .partial_cmp(&quality(b))
+                    .unwrap_or(Ordering::Equal)
+            })
+            .then_with(|| Transfer::supports(a).cmp(&Transfer::supports(b)))
+    }
+}
+
+/// Returns the extension of the file a format downloads to
+///
+/// Falls back to the container its codecs usually come in when yt-dlp
+/// reports no known extension.
+pub fn extension(format: &Format) -> &'static str {
+    use yt_dlp::model::format::Extension;
+
+    match format.download_info.ext {
+        Extension::M4A => "m4a",
+        Extension::Mp3 => "mp3",
+        Extension::Mp4 => "mp4",
+        Extension::Webm => "webm",
+        _ => {
+            let codecs = [
+                format.codec_info.video_codec.as_deref(),
+                format.codec_info.audio_codec.as_deref(),
+            ];
+            let webm = codecs.into_iter().flatten().any(|codec| {
+                ["vp8", "vp9", "vp09", "opus", "vorbis"]
+                    .iter()
+                    .any(|prefix| codec.starts_with(prefix))
+            });
+            match (webm, has_video(format)) {
+                (true, _) => "webm",
+                (false, true) => "mp4",
+                (false, false) => "m4a",
+            }
+        }
+    }
+}
+
+fn height(format: &Format) -> u32 {
+    format.video_resolution.height.unwrap_or(0)
+}
+
+fn has_video(format: &Format) -> bool {
+    is_codec(format.codec_info.video_codec.as_deref()) && is_stream(format)
+}
+
+fn has_audio(format: &Format) -> bool {
+    is_codec(format.codec_info.audio_codec.as_deref()) && is_stream(format)
+}
+
+/// yt-dlp reports a missing stream as the codec `none`
+fn is_codec(codec: Option<&str>) -> bool {
+    codec.is_some_and(|codec| !codec.is_empty() && codec != "none")
+}
+
+/// Storyboards are images, not streams
+fn is_stream(format: &Format) -> bool {
+    format.storyboard_info.fragments.is_none()
+}
+
+/// Returns the position of a codec in the preference list, after all of it if absent
+fn codec_rank(preferred: &[String], codec: Option<&str>) -> usize {
+    let codec = codec.unwrap_or_default().to_lowercase();
+    preferred
+        .iter()
+        .position(|name| {
+            codec_prefixes(name)
+                .iter()
+                .any(|prefix| codec.starts_with(prefix.as_str()))
+        })
+        .unwrap_or(preferred.len())
+}
+
+/// Maps a codec name to the prefixes yt-dlp reports it with
+fn codec_prefixes(name: &str) -> Vec<String> {
+    let name = name.trim().to_lowercase();
+    let aliases: &[&str] = match name.as_str() {
+        "h264" | "avc" => &["avc1", "avc3", "h264"],
+        "h265" | "hevc" => &["hvc1", "hev1", "h265"],
+        "vp9" => &["vp9", "vp09"],
+        "av1" => &["av01"],
+        "aac" => &["mp4a"],
+        _ => &[],
+    };
+    if aliases.is_empty() {
+        vec![name]
+    } else {
+        aliases.iter().map(|alias| alias.to_string()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn format(id: &str, vcodec: &str, acodec: &str, height: Option<u32>) -> serde_json::Value {
+        json!({
+            "format": id,
+            "format_id": id,
+            "protocol": "https",
+            "url": format!("https://cdn.example.com/{}", id),
+            "vcodec": vcodec,
+            "acodec": acodec,
+            "height": height,
+            "http_headers": {
+                "User-Agent": "",
+                "Accept": "",
+                "Accept-Language": "",
+                "Sec-Fetch-Mode": "",
+            },
+        })
+    }
+
+    fn hls(mut format: serde_json::Value) -> serde_json::Value {
+        format["protocol"] = json!("m3u8_native");
+        format["manifest_url"] = json!("https://cdn.example.com/master.m3u8");
+        format
+    }
+
+    fn video(formats: Vec<serde_json::Value>) -> Video {
+        serde_json::from_value(json!({
+            "id": "abc",
+            "title": "Title",
+            "formats": formats,
+            "thumbnails": [],
+            "automatic_captions": {},
+            "tags": [],
+            "categories": [],
+            "age_limit": 0,
+            "live_status": "not_live",
+            "playable_in_embed": true,
+            "extractor": "generic",
+            "extractor_key": "Generic",
+            "_version": {
+                "version": "2025.01.15",
+                "repository": "yt-dlp/yt-dlp",
+            },
+        }))
+        .unwrap()
+    }
+
+    fn policy() -> FormatPolicy {
+        FormatPolicy::default()
+    }
+
+    #[test]
+    fn select_picks_by_mode_and_policy() {
+        let streams = || {
+            vec![
+                format("18", "avc1.42001E", "mp4a.40.2", Some(360)),
+                format("137", "avc1.640028", "none", Some(1080)),
+                format("248", "vp9", "none", Some(1080)),
+                format("136", "avc1.4d401f", "none", Some(720)),
+                format("140", "none", "mp4a.40.2", None),
+                format("251", "none", "opus", None),
+            ]
+        };
+        let h264 = FormatPolicy {
+            video_codecs: vec!["h264".to_string()],
+            audio_codecs: vec!["aac".to_string()],
+            ..policy()
+        };
+        let capped = FormatPolicy {
+            max_height: Some(720),
+            audio_codecs: vec!["aac".to_string()],
+            ..policy()
+        };
+        let progressive = FormatPolicy {
+            prefer_progressive: true,
+            ..policy()
+        };
+        let opus = FormatPolicy {
+            video_codecs: vec!["vp9".to_string()],
+            audio_codecs: vec!["opus".to_string()],
+            ..policy()
+        };
+        let cases = [
+            (h264.clone(), DownloadMode::AudioVideo, "137+140"),
+            (opus.clone(), DownloadMode::AudioVideo, "248+251"),
+            (capped.clone(), DownloadMode::AudioVideo, "136+140"),
+            (progressive, DownloadMode::AudioVideo, "18"),
+            (capped, DownloadMode::Audio, "140"),
+            (opus, DownloadMode::Audio, "251"),
+            (h264, DownloadMode::Video, "137"),
+        ];
+        for (policy, mode, expected) in cases {
+            let video = video(streams());
+            let choice = policy.select(&video, mode).unwrap();
+            assert_eq!(choice.to_string(), expected, "{:?} {}", policy, mode);
+        }
+    }
+
+    #[test]
+    fn select_prefers_quality_over_protocol() {
+        let manifest = video(vec![
+            hls(format("hls-1080", "avc1.640028", "mp4a.40.2", Some(1080))),
+            format("18", "avc1.42001E", "mp4a.40.2", Some(360)),
+        ]);
+        let choice = policy()
+            .select(&manifest, DownloadMode::AudioVideo)
+            .unwrap();
+        assert_eq!(choice.to_string(), "hls-1080");
+
+        let av1 = FormatPolicy {
+            video_codecs: vec!["av1".to_string()],
+            ..policy()
+        };
+        let codecs = video(vec![
+            hls(format("hls-av1", "av01.0.05M.08", "none", Some(720))),
+            format("137", "avc1.640028", "none", Some(1080)),
+        ]);
+        let choice = av1.select(&codecs, DownloadMode::Video).unwrap();
+        assert_eq!(choice.to_string(), "hls-av1");
+    }
+
+    #[test]
+    fn select_prefers_https_between_equal_formats() {
+        let video = video(vec![
+            hls(format("hls-720", "avc1.4d401f", "mp4a.40.2", Some(720))),
+            format("22", "avc1.4d401f", "mp4a.40.2", Some(720)),
+        ]);
+        let choice = policy().select(&video, DownloadMode::AudioVideo).unwrap();
+        assert_eq!(choice.to_string(), "22");
+    }
+
+    #[test]
+    fn select_falls_back_to_manifests() {
+        let video = video(vec![
+            hls(format("hls-360", "avc1.42001E", "mp4a.40.2", Some(360))),
+            hls(format("hls-720", "avc1.4d401f", "mp4a.40.2", Some(720))),
+        ]);
+        let choice = policy().select(&video, DownloadMode::AudioVideo).unwrap();
+        assert_eq!(choice.to_string(), "hls-720");
+
+        let choice = policy().select(&video, DownloadMode::Audio).unwrap();
+        assert!(matches!(choice, FormatChoice::Progressive(_)));
+    }
+
+    #[test]
+    fn select_without_fitting_format_fails() {
+        let capped = FormatPolicy {
+            max_height: Some(480),
+            ..policy()
+        };
+        let cases = [
+            (video(vec![]), policy(), DownloadMode::AudioVideo),
+            (
+                video(vec![format("137", "avc1.640028", "none", Some(1080))]),
+                capped,
+                DownloadMode::AudioVideo,
+            ),
+            (
+                video(vec![format("137", "avc1.640028", "none", Some(1080))]),
+                policy(),
+                DownloadMode::Audio,
+            ),
+        ];
+        for (video, policy, mode) in cases {
+            let error = policy.select(&video, mode).unwrap_err();
+            assert_eq!(error.code(), "no_format", "{:?} {}", policy, mode);
+        }
+    }
+
+    #[test]
+    fn codec_rank_follows_aliases() {
+        let preferred = vec!["h264".to_string(), "vp9".to_string()];
+        let cases = [
+            (Some("avc1.640028"), 0),
+            (Some("vp09.00.40.08"), 1),
+            (Some("av01.0.08M.08"), 2),
+            (None, 2),
+        ];
+        for (codec, rank) in cases {
+            assert_eq!(codec_rank(&preferred, codec), rank, "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn validate_rejects_zero_caps() {
+        let cases = [
+            (
+                FormatPolicy {
+                    max_height: Some(0),
+                    ..policy()
+                },
+                false,
+            ),
+            (
+                FormatPolicy {
+                    max_fps: Some(0.0),
+                    ..policy()
+                },
+                false,
+            ),
+            (
+                FormatPolicy {
+                    max_filesize: Some("huge".to_string()),
+                    ..policy()
+                },
+                false,
+            ),
+            (
+                FormatPolicy {
+                    max_filesize: Some("2GB".to_string()),
+                    ..policy()
+                },
+                true,
+            ),
+        ];
+        for (policy, valid) in cases {
+            assert_eq!(policy.validate().is_ok(), valid, "{:?}", policy);
+        }
+    }
+}
@@ -0,0 +1,274 @@
+//! Local URL file sourcing.
+//!
+//! Discovers the `.txt` URL lists in the input directory, downloads them
+//! either one file at a time or as a single merged batch, and optionally
+//! archives each file according to its outcome.
+
+use crate::error::Result;
+use crate::progress::DownloadSummary;
+use crate::Downloader;
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+
+/// Subdirectory of the input directory receiving fully successful files
+pub const DONE_DIR: &str = "done";
+
+/// Subdirectory of the input directory receiving files with failed downloads
+pub const FAILED_DIR: &str = "failed";
+
+/// Lists the URL files of a directory in a deterministic order
+///
+/// # Arguments
+/// * `dir` - Directory to scan, subdirectories are not descended into
+///
+/// # Returns
+/// * `Result<Vec<PathBuf>>` - Paths of the `.txt` files sorted by file name
+pub fn list_url_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().and_then(|ext| ext.to_str()) == Some("txt") {
+            files.push(path);
+        }
+    }
+    files.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+    Ok(files)
+}
+
+/// Reads and validates URLs from a text file.
+///
+/// # Format
+/// - One URL per line
+/// - Empty lines are ignored
+/// - Lines are trimmed of whitespace
+///
+/// # Arguments
+/// * `path` - Path to the text file containing URLs
+///
+/// # Returns
+/// A vector of validated URLs as strings
+///
+/// # Errors
+/// Returns error if:
+/// - File cannot be opened
+/// - File reading fails
+/// - Line parsing fails
+pub async fn read_urls(path: &Path) -> Result<Vec<String>> {
+    let file = File::open(path)?;
+    let reader = io::BufReader::new(file);
+    let mut urls = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        let trimmed = line.trim();
+        if !trimmed.is_empty() {
+            urls.push(trimmed.to_string());
+        }
+    }
+
+    Ok(urls)
+}
+
+/// Concatenates URL lists, keeping the first occurrence of each URL
+///
+/// # Examples
+///
+/// ```
+/// use application::input::merge_urls;
+///
+/// let a = vec!["u1".to_string(), "u2".to_string()];
+/// let b = vec!["u2".to_string(), "u3".to_string()];
+/// assert_eq!(merge_urls([a.as_slice(), b.as_slice()]), ["u1", "u2", "u3"]);
+/// ```
+pub fn merge_urls<'a>(lists: impl IntoIterator<Item = &'a [String]>) -> Vec<String> {
+    let mut merged: Vec<String> = Vec::new();
+    for urls in lists {
+        for url in urls {
+            if !merged.contains(url) {
+                merged.push(url.clone());
+            }
+        }
+    }
+    merged
+}
+
+/// Moves a processed URL file into `done/` or `failed/` next to it
+///
+/// # Arguments
+/// * `path` - URL file to move
+/// * `success` - Whether every download of the file succeeded
+///
+/// # Returns
+/// * `Result<PathBuf>` - New location of the file
+///
+/// # Details
+/// An existing file of the same name is never overwritten, a timestamp
+/// is appended to the new name instead.
+pub fn archive_file(path: &Path, success: bool) -> Result<PathBuf> {
+    let parent = path.parent().unwrap_or_else(|| Path::new("."));
+    let target_dir = parent.join(if success { DONE_DIR } else { FAILED_DIR });
+    std::fs::create_dir_all(&target_dir)?;
+
+    let file_name = path.file_name().unwrap_or_default();
+    let mut target = target_dir.join(file_name);
+    if target.exists() {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let stamp = chrono::Local::now().format("%Y%m%d-%H%M%S");
+        target = target_dir.join(format!("{}.{}.txt", stem, stamp));
+    }
+
+    std::fs::rename(path, &target)?;
+    Ok(target)
+}
+
+/// Processes video URLs from local text files.
+///
+/// # Processing Steps
+/// 1. Lists the `.txt` files of the input directory in name order
+/// 2. Downloads each file as its own batch, or all of them as one
+///    deduplicated batch when `merge_input_files` is set
+/// 3. Prints a summary per file
+/// 4. Archives each file when `archive_input_files` is set
+///
+/// # Arguments
+/// * `downloader` - Handles video download operations and configuration
+///
+/// # Returns
+/// * `Result<DownloadSummary>` - Combined counts of all files
+///
+/// # Errors
+/// Returns error if:
+/// - Directory reading fails
+/// - File reading fails
+/// - Archiving a file fails
+pub async fn process_local_files(downloader: &Downloader) -> Result<DownloadSummary> {
+    let config = downloader.config();
+    let files = list_url_files(&config.input_dir)?;
+    if files.is_empty() {
+        println!("No URL files found in {:?}", config.input_dir);
+        return Ok(DownloadSummary::default());
+    }
+
+    let mut batches = Vec::with_capacity(files.len());
+    for path in files {
+        println!("Processing file: {:?}", path);
+        let urls = read_urls(&path).await?;
+        batches.push((path, urls));
+    }
+
+    let mut file_summaries = Vec::with_capacity(batches.len());
+    let mut summary = DownloadSummary::default();
+    if config.merge_input_files {
+        let merged = merge_urls(batches.iter().map(|(_, urls)| urls.as_slice()));
+        println!(
+            "Merged {} files into {} unique URLs",
+            batches.len(),
+            merged.len()
+        );
+
+        summary = downloader.process_urls(&merged).await?;
+        for (path, urls) in batches {
+            file_summaries.push((path, summary.subset(&urls)));
+        }
+    } else {
+        for (path, urls) in batches {
+            let file_summary = downloader.process_urls(&urls).await?;
+            summary.merge(file_summary.clone());
+            file_summaries.push((path, file_summary));
+        }
+    }
+
+    println!("\nFile Summary:");
+    for (path, file_summary) in &file_summaries {
+        println!(
+            "{:?}: {} URLs, {} succeeded, {} failed",
+            path, file_summary.total, file_summary.succeeded, file_summary.failed
+        );
+        if config.archive_input_files {
+            let target = archive_file(path, file_summary.is_success())?;
+            println!("  moved to {:?}", target);
+        }
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty directory unique to the calling test
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ytb-input-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn lists_only_txt_files_in_name_order() {
+        let dir = scratch_dir("list");
+        for name in ["b.txt", "a.txt", "c.csv", "notes.TXT"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        std::fs::create_dir(dir.join("done.txt")).unwrap();
+
+        let names: Vec<_> = list_url_files(&dir)
+            .unwrap()
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, ["a.txt", "b.txt"]);
+    }
+
+    #[tokio::test]
+    async fn reads_trimmed_non_empty_lines() {
+        let dir = scratch_dir("read");
+        let path = dir.join("urls.txt");
+        std::fs::write(&path, "  https://a\n\n\thttps://b  \r\n   \nhttps://a\n").unwrap();
+
+        assert_eq!(
+            read_urls(&path).await.unwrap(),
+            ["https://a", "https://b", "https://a"]
+        );
+    }
+
+    #[test]
+    fn merging_keeps_first_occurrences_in_order() {
+        let cases: [(&[&[&str]], &[&str]); 4] = [
+            (&[], &[]),
+            (&[&["a", "b"]], &["a", "b"]),
+            (&[&["a", "b"], &["b", "c"]], &["a", "b", "c"]),
+            (&[&["c", "c"], &["a"], &["c", "b"]], &["c", "a", "b"]),
+        ];
+        for (lists, expected) in cases {
+            let lists: Vec<Vec<String>> = lists
+                .iter()
+                .map(|urls| urls.iter().map(|url| url.to_string()).collect())
+                .collect();
+            assert_eq!(merge_urls(lists.iter().map(Vec::as_slice)), expected);
+        }
+    }
+
+    #[test]
+    fn archives_by_outcome_without_overwriting() {
+        let dir = scratch_dir("archive");
+        let first = dir.join("urls.txt");
+        std::fs::write(&first, "1").unwrap();
+        let target = archive_file(&first, true).unwrap();
+        assert_eq!(target, dir.join(DONE_DIR).join("urls.txt"));
+        assert!(!first.exists());
+
+        std::fs::write(&first, "2").unwrap();
+        let second = archive_file(&first, true).unwrap();
+        assert_ne!(second, target);
+        assert_eq!(second.parent(), target.parent());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "1");
+        assert_eq!(std::fs::read_to_string(&second).unwrap(), "2");
+
+        std::fs::write(&first, "3").unwrap();
+        let failed = archive_file(&first, false).unwrap();
+        assert_eq!(failed, dir.join(FAILED_DIR).join("urls.txt"));
+    }
+}
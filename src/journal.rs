@@ -0,0 +1,435 @@
+//! Durable job journal.
+//!
+//! Every state change of a download job is appended as one JSON line to the
+//! journal file, so a crashed or interrupted run can be picked up again: jobs
+//! that finished are skipped, jobs that stopped half way resume from the last
+//! stage they completed.
+
+use crate::downloader::DownloadJob;
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Stage a job reached, in the order a download goes through them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JobState {
+    Queued,
+    FetchingInfo,
+    DownloadingAudio,
+    DownloadingVideo,
+    /// Merging the streams, or converting the audio in audio-only mode
+    Merging,
+    Done,
+    Failed,
+}
+
+impl JobState {
+    /// Returns true for `Done` and `Failed`, after which the job is not running anymore
+    pub fn is_terminal(self) -> bool {
+        matches!(self, JobState::Done | JobState::Failed)
+    }
+}
+
+/// Latest known state of one job
+struct JobRecord {
+    job: DownloadJob,
+    state: JobState,
+    /// Output file relative to the output directory, set once `Done`
+    output: Option<String>,
+    /// Error message, set once `Failed`
+    error: Option<String>,
+    /// Stage a failed job stopped at
+    stage: Option<JobState>,
+    /// RFC 3339 time of the latest state change
+    updated: String,
+    /// Position of the job's first entry, keeps listings in queue order
+    seq: usize,
+}
+
+/// One line of the journal file
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    id: String,
+    state: JobState,
+    #[serde(flatten)]
+    job: DownloadJob,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stage: Option<JobState>,
+    at: String,
+}
+
+/// Append-only store of job states backed by a JSON-lines file
+///
+/// Jobs are identified by `DownloadJob::id`, which stays the same across runs.
+///
+/// # Examples
+///
+/// ```no_run
+/// use application::downloader::DownloadJob;
+/// use application::journal::{JobState, JobStore};
+/// use std::path::Path;
+///
+/// let store = JobStore::open(Path::new("output/jobs.jsonl")).unwrap();
+/// let job = DownloadJob::from("https://www.youtube.com/watch?v=dQw4w9WgXcQ".to_string());
+/// store.record(&job, JobState::Queued).unwrap();
+/// assert_eq!(store.state(&job), Some(JobState::Queued));
+/// ```
+pub struct JobStore {
+    path: PathBuf,
+    inner: Mutex<Journal>,
+}
+
+struct Journal {
+    file: File,
+    records: HashMap<String, JobRecord>,
+}
+
+impl JobStore {
+    /// Opens the journal, replaying the states it already contains
+    ///
+    /// # Arguments
+    /// * `path` - Journal file, created with its parent directory if missing
+    ///
+    /// # Details
+    /// A line that cannot be parsed, typically the last one of a crashed
+    /// run, is skipped with a warning. When the file holds more lines than
+    /// jobs, it is rewritten with only the latest state of each job.
+    ///
+    /// # Errors
+    /// * If the journal cannot be read, compacted or opened for appending
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut records = HashMap::new();
+        let mut lines = 0;
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            for (number, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                lines += 1;
+                match serde_json::from_str::<Entry>(&line) {
+                    Ok(entry) => apply(&mut records, entry),
+                    Err(e) => {
+                        eprintln!("Warning: skipping line {} of {:?}: {}", number + 1, path, e)
+                    }
+                }
+            }
+        }
+
+        if lines > records.len() {
+            compact(path, &records)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            inner: Mutex::new(Journal { file, records }),
+        })
+    }
+
+    /// Returns the path of the journal file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the latest recorded state of a job
+    pub fn state(&self, job: &DownloadJob) -> Option<JobState> {
+        self.lock()
+            .records
+            .get(&job.id())
+            .map(|record| record.state)
+    }
+
+    /// Returns the output of a job that is done and whose file still exists
+    ///
+    /// # Arguments
+    /// * `job` - Job to look up
+    /// * `output_dir` - Directory the recorded output is relative to
+    pub fn completed_output(&self, job: &DownloadJob, output_dir: &Path) -> Option<String> {
+        let journal = self.lock();
+        let record = journal.records.get(&job.id())?;
+        let output = record.output.as_ref()?;
+        (record.state == JobState::Done && output_dir.join(output).is_file())
+            .then(|| output.clone())
+    }
+
+    /// Returns the stage a job stopped at, to resume it from there
+    ///
+    /// # Returns
+    /// * `Option<JobState>` - The state of an interrupted job, the stage a
+    ///   failed job failed at, `None` for new and done jobs
+    pub fn resume_stage(&self, job: &DownloadJob) -> Option<JobState> {
+        let journal = self.lock();
+        let record = journal.records.get(&job.id())?;
+        match record.state {
+            JobState::Done => None,
+            JobState::Failed => record.stage,
+            state => Some(state),
+        }
+    }
+
+    /// Marks the new jobs of a batch as queued
+    ///
+    /// Jobs already in the journal keep their state: done jobs are skipped
+    /// while their output exists, interrupted and failed jobs resume from
+    /// the stage they stopped at.
+    pub fn enqueue(&self, jobs: &[DownloadJob]) -> Result<()> {
+        for job in jobs {
+            if self.state(job).is_none() {
+                self.record(job, JobState::Queued)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends a state change of a job
+    ///
+    /// # Errors
+    /// * If the entry cannot be written to the journal file
+    pub fn record(&self, job: &DownloadJob, state: JobState) -> Result<()> {
+        self.append(job, state, None, None, None)
+    }
+
+    /// Records that a job finished with the given output file
+    pub fn complete(&self, job: &DownloadJob, output: &str) -> Result<()> {
+        self.append(job, JobState::Done, Some(output.to_string()), None, None)
+    }
+
+    /// Records that a job needed no download, leaving it without output
+    pub fn skip(&self, job: &DownloadJob) -> Result<()> {
+        self.append(job, JobState::Done, None, None, None)
+    }
+
+    /// Records that a job failed with the given error, at the stage it was in
+    pub fn fail(&self, job: &DownloadJob, error: &str) -> Result<()> {
+        let stage = self.resume_stage(job);
+        self.append(job, JobState::Failed, None, Some(error.to_string()), stage)
+    }
+
+    /// Returns the jobs that were started but neither finished nor failed
+    ///
+    /// # Returns
+    /// * `Vec<DownloadJob>` - Interrupted jobs in the order they were first queued
+    pub fn unfinished(&self) -> Vec<DownloadJob> {
+        let journal = self.lock();
+        let mut records: Vec<_> = journal
+            .records
+            .values()
+            .filter(|record| !record.state.is_terminal())
+            .collect();
+        records.sort_by_key(|record| record.seq);
+        records
+            .into_iter()
+            .map(|record| record.job.clone())
+            .collect()
+    }
+
+    /// Returns the ids of the jobs that did not finish, failed ones included
+    ///
+    /// Their work directories may hold streams to resume.
+    pub fn resumable_ids(&self) -> HashSet<String> {
+        self.lock()
+            .records
+            .iter()
+            .filter(|(_, record)| record.state != JobState::Done)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    fn append(
+        &self,
+        job: &DownloadJob,
+        state: JobState,
+        output: Option<String>,
+        error: Option<String>,
+        stage: Option<JobState>,
+    ) -> Result<()> {
+        let entry = Entry {
+            id: job.id(),
+            state,
+            job: job.clone(),
+            output,
+            error,
+            stage,
+            at: chrono::Utc::now().to_rfc3339(),
+        };
+        let mut line = serde_json::to_string(&entry).map_err(std::io::Error::from)?;
+        line.push('\n');
+
+        let mut journal = self.lock();
+        // One write per entry, so a crash can only truncate the last line
+        journal.file.write_all(line.as_bytes())?;
+        if state.is_terminal() {
+            journal.file.sync_data()?;
+        }
+        apply(&mut journal.records, entry);
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Journal> {
+        // A panic while holding the lock leaves the map consistent, keep going
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Applies one journal entry to the latest states
+fn apply(records: &mut HashMap<String, JobRecord>, entry: Entry) {
+    let seq = records.len();
+    let record = records.entry(entry.id).or_insert_with(|| JobRecord {
+        job: entry.job.clone(),
+        state: entry.state,
+        output: None,
+        error: None,
+        stage: None,
+        updated: entry.at.clone(),
+        seq,
+    });
+    record.job = entry.job;
+    record.state = entry.state;
+    record.output = entry.output;
+    record.error = entry.error;
+    record.stage = entry.stage;
+    record.updated = entry.at;
+}
+
+/// Rewrites the journal with one line per job
+fn compact(path: &Path, records: &HashMap<String, JobRecord>) -> Result<()> {
+    let mut records: Vec<_> = records.iter().collect();
+    records.sort_by_key(|(_, record)| record.seq);
+
+    let temp = path.with_extension("jsonl.tmp");
+    let mut file = File::create(&temp)?;
+    for (id, record) in records {
+        let entry = Entry {
+            id: id.clone(),
+            state: record.state,
+            job: record.job.clone(),
+            output: record.output.clone(),
+            error: record.error.clone(),
+            stage: record.stage,
+            at: record.updated.clone(),
+        };
+        let line = serde_json::to_string(&entry).map_err(std::io::Error::from)?;
+        writeln!(file, "{}", line)?;
+    }
+    file.sync_all()?;
+    std::fs::rename(&temp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty directory unique to the calling test
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ytb-journal-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn job(url: &str) -> DownloadJob {
+        DownloadJob::from(url.to_string())
+    }
+
+    #[test]
+    fn done_jobs_are_skipped_after_a_restart() {
+        let dir = scratch_dir("done");
+        let path = dir.join("jobs.jsonl");
+        let job = job("https://youtu.be/a");
+
+        let store = JobStore::open(&path).unwrap();
+        store.enqueue(std::slice::from_ref(&job)).unwrap();
+        store.record(&job, JobState::Merging).unwrap();
+        std::fs::write(dir.join("a.mp4"), "video").unwrap();
+        store.complete(&job, "a.mp4").unwrap();
+        drop(store);
+
+        let store = JobStore::open(&path).unwrap();
+        store.enqueue(std::slice::from_ref(&job)).unwrap();
+        assert_eq!(store.state(&job), Some(JobState::Done));
+        assert_eq!(store.completed_output(&job, &dir).as_deref(), Some("a.mp4"));
+        assert_eq!(store.resume_stage(&job), None);
+
+        std::fs::remove_file(dir.join("a.mp4")).unwrap();
+        assert_eq!(store.completed_output(&job, &dir), None);
+    }
+
+    #[test]
+    fn interrupted_and_failed_jobs_keep_their_stage() {
+        let dir = scratch_dir("stages");
+        let path = dir.join("jobs.jsonl");
+        let interrupted = job("https://youtu.be/a");
+        let failed = job("https://youtu.be/b");
+        let jobs = [interrupted.clone(), failed.clone()];
+
+        let store = JobStore::open(&path).unwrap();
+        store.enqueue(&jobs).unwrap();
+        store
+            .record(&interrupted, JobState::DownloadingVideo)
+            .unwrap();
+        store.record(&failed, JobState::Merging).unwrap();
+        store.fail(&failed, "ffmpeg failed").unwrap();
+        drop(store);
+
+        let store = JobStore::open(&path).unwrap();
+        store.enqueue(&jobs).unwrap();
+        assert_eq!(store.state(&interrupted), Some(JobState::DownloadingVideo));
+        assert_eq!(
+            store.resume_stage(&interrupted),
+            Some(JobState::DownloadingVideo)
+        );
+        assert_eq!(store.state(&failed), Some(JobState::Failed));
+        assert_eq!(store.resume_stage(&failed), Some(JobState::Merging));
+        assert_eq!(store.unfinished(), [interrupted]);
+        assert_eq!(store.resumable_ids().len(), 2);
+    }
+
+    #[test]
+    fn new_jobs_are_queued_in_order() {
+        let dir = scratch_dir("queued");
+        let store = JobStore::open(&dir.join("jobs.jsonl")).unwrap();
+        let jobs = [job("https://youtu.be/c"), job("https://youtu.be/a")];
+        store.enqueue(&jobs).unwrap();
+
+        assert_eq!(store.state(&jobs[0]), Some(JobState::Queued));
+        assert_eq!(store.resume_stage(&jobs[0]), Some(JobState::Queued));
+        assert_eq!(store.unfinished(), jobs);
+        assert_eq!(store.state(&job("https://youtu.be/b")), None);
+    }
+
+    #[test]
+    fn open_skips_broken_lines_and_compacts() {
+        let dir = scratch_dir("compact");
+        let path = dir.join("jobs.jsonl");
+        let job = job("https://youtu.be/a");
+
+        let store = JobStore::open(&path).unwrap();
+        store.enqueue(std::slice::from_ref(&job)).unwrap();
+        store.record(&job, JobState::FetchingInfo).unwrap();
+        store.record(&job, JobState::DownloadingAudio).unwrap();
+        drop(store);
+        let mut content = std::fs::read_to_string(&path).unwrap();
+        content.push_str("{\"id\":\"trunc");
+        std::fs::write(&path, content).unwrap();
+
+        let store = JobStore::open(&path).unwrap();
+        assert_eq!(store.state(&job), Some(JobState::DownloadingAudio));
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+    }
+}
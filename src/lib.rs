@@ -11,6 +11,19 @@
 /// - `Downloader`: Core video downloading functionality
 /// - `SheetClient`: Google Sheets integration
 /// - `DownloadProgress`: Progress tracking and reporting
+/// - `archive`: yt-dlp compatible record of downloaded videos
+/// - `bandwidth`: Global, time-scheduled bandwidth limit
+/// - `formats`: Policy choosing the streams to download
+/// - `input`: Local URL files in the input directory
+/// - `journal`: Durable job states for resuming interrupted runs
+/// - `limits`: Per-site concurrency caps and request rates
+/// - `manifest`: HLS and DASH stream downloads with the managed yt-dlp
+/// - `orchestrator`: Concurrent execution of all sources
+/// - `retry`: Backoff and classification of failed download stages
+/// - `template`: Safe output file names rendered from a template
+/// - `transcode`: Merging, remuxing and audio conversion with the managed ffmpeg
+/// - `transfer`: Bandwidth-limited HTTP downloads of single streams
+/// - `watch`: Long-running watching of the sheet and input directory
 ///
 /// # Example
 /// ```no_run
@@ -23,11 +36,26 @@
 /// }
 /// ```
 // Move shared structs, traits and functions here
+pub mod archive;
+pub mod bandwidth;
 pub mod config;
 pub mod downloader;
 pub mod error;
+pub mod formats;
+pub mod input;
+pub mod journal;
+pub mod limits;
+pub mod manifest;
+#[cfg(test)]
+mod mock_server;
+pub mod orchestrator;
 pub mod progress;
+pub mod retry;
 pub mod sheet;
+pub mod template;
+pub mod transcode;
+pub mod transfer;
+pub mod watch;
 
 // Re-export commonly used items
 pub use config::Config;
@@ -0,0 +1,466 @@
+//! Per-site concurrency caps and request rate limits.
+//!
+//! Every request to a site, whether a video information fetch or a stream
+//! download, first takes one of the site's concurrency permits and a token
+//! from its requests-per-minute bucket. A stream fetched in several range
+//! requests holds one permit and takes a token for each request. Sites are
+//! told apart by the extractor of the video; before its information is
+//! fetched, the extractor is guessed from well-known hosts, and other hosts
+//! stand for themselves.
+//! When a site answers with HTTP 429, its rate is halved for a while.
+
+use crate::error::{AppError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use url::Url;
+
+/// Lowest fraction of the configured rate that 429 responses can slow a site down to
+const MIN_RATE_FACTOR: f64 = 1.0 / 16.0;
+
+/// Hosts, with their subdomains, served by one extractor, so a video has
+/// the same site before and after its information is fetched
+const KNOWN_EXTRACTORS: &[(&str, &str)] = &[
+    ("youtube.com", "youtube"),
+    ("youtu.be", "youtube"),
+    ("youtube-nocookie.com", "youtube"),
+    ("dailymotion.com", "dailymotion"),
+    ("dai.ly", "dailymotion"),
+];
+
+/// Limits of one site
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SiteLimit {
+    /// Requests to the site running at the same time
+    pub concurrency: usize,
+    /// Requests started per minute, 0 for no limit
+    pub requests_per_minute: u32,
+}
+
+impl Default for SiteLimit {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            requests_per_minute: 60,
+        }
+    }
+}
+
+/// Rate limit settings, the `[limits]` table of the configuration
+///
+/// Keys of `sites` are extractor names, e.g. `youtube`, or domains, e.g.
+/// `vimeo.com`, which also cover their subdomains. Sites without an entry
+/// get the `default` limits, each host on its own.
+///
+/// ```toml
+/// [limits]
+/// slowdown_secs = 120
+///
+/// [limits.sites.youtube]
+/// concurrency = 2
+/// requests_per_minute = 30
+/// ```
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub default: SiteLimit,
+    pub sites: BTreeMap<String, SiteLimit>,
+    /// Seconds a site stays slowed down after a 429 before its rate doubles again
+    pub slowdown_secs: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            default: SiteLimit::default(),
+            sites: BTreeMap::new(),
+            slowdown_secs: 60,
+        }
+    }
+}
+
+impl LimitsConfig {
+    /// Checks that every site can run at least one request
+    ///
+    /// # Errors
+    /// * If a concurrency is 0
+    pub fn validate(&self) -> Result<()> {
+        if self.default.concurrency == 0 {
+            return Err(AppError::Config(
+                "limits.default.concurrency must be greater than 0".to_string(),
+            ));
+        }
+        for (site, limit) in &self.sites {
+            if limit.concurrency == 0 {
+                return Err(AppError::Config(format!(
+                    "limits.sites.{}.concurrency must be greater than 0",
+                    site
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Applies the configured limits of every site
+pub struct RateLimiter {
+    config: LimitsConfig,
+    sites: Mutex<HashMap<String, Arc<Site>>>,
+}
+
+/// Runtime state of one site
+struct Site {
+    permits: Arc<Semaphore>,
+    bucket: Mutex<Bucket>,
+}
+
+/// Token bucket refilled at the site's rate, scaled down after 429s
+struct Bucket {
+    /// Tokens per second at full speed, 0 for no limit
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled: Instant,
+    factor: f64,
+    slowed_until: Option<Instant>,
+}
+
+/// Permission to send one request to a site, held until the request ends
+pub struct SitePermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl RateLimiter {
+    pub fn new(config: LimitsConfig) -> Self {
+        Self {
+            config,
+            sites: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the site a request belongs to
+    ///
+    /// # Arguments
+    /// * `url` - Page URL of the video
+    /// * `extractor` - Extractor of the video, once its information is fetched
+    ///
+    /// # Returns
+    /// * `String` - The configured extractor or domain matching the request,
+    ///   else the extractor of a well-known host, else the host of the URL
+    pub fn site(&self, url: &str, extractor: Option<&str>) -> String {
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_lowercase))
+            .unwrap_or_default();
+        let host = host.trim_start_matches("www.");
+        let known = KNOWN_EXTRACTORS
+            .iter()
+            .find(|(domain, _)| in_domain(host, domain))
+            .map(|(_, extractor)| extractor.to_string());
+
+        if let Some(extractor) = extractor
+            .map(str::to_lowercase)
+            .into_iter()
+            .chain(known.clone())
+            .find(|extractor| self.config.sites.contains_key(extractor))
+        {
+            return extractor;
+        }
+        self.config
+            .sites
+            .keys()
+            .find(|domain| in_domain(host, domain))
+            .cloned()
+            .or(known)
+            .unwrap_or_else(|| host.to_string())
+    }
+
+    /// Waits until a request to the site may start
+    ///
+    /// Takes one of the site's concurrency permits, then waits for a token
+    /// of its rate limit.
+    pub async fn acquire(&self, site: &str) -> SitePermit {
+        let state = self.state(site);
+        let permit = Arc::clone(&state.permits)
+            .acquire_owned()
+            .await
+            .expect("semaphores are never closed");
+        self.take_token(&state).await;
+        SitePermit { _permit: permit }
+    }
+
+    /// Waits for a token of the site's rate limit, without a concurrency permit
+    ///
+    /// Used for the requests after the first of work already holding a
+    /// `SitePermit`, like the range requests of one stream.
+    pub async fn pace(&self, site: &str) {
+        self.take_token(&self.state(site)).await;
+    }
+
+    async fn take_token(&self, site: &Site) {
+        loop {
+            let wait = site
+                .bucket
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .take(Instant::now(), self.slowdown());
+            match wait {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => return,
+            }
+        }
+    }
+
+    /// Halves the rate of a site that answered with HTTP 429
+    ///
+    /// The rate doubles again every `slowdown_secs` without another 429. A
+    /// site without a rate limit is paused for `slowdown_secs` instead.
+    pub fn throttled(&self, site: &str) {
+        let state = self.state(site);
+        let mut bucket = state.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        bucket.slow_down(Instant::now(), self.slowdown());
+        if bucket.rate > 0.0 {
+            eprintln!(
+                "Warning: {} is rate limiting, slowing down to {:.1} requests per minute",
+                site,
+                bucket.rate * bucket.factor * 60.0
+            );
+        } else {
+            eprintln!(
+                "Warning: {} is rate limiting, pausing for {}s",
+                site, self.config.slowdown_secs
+            );
+        }
+    }
+
+    fn slowdown(&self) -> Duration {
+        Duration::from_secs(self.config.slowdown_secs)
+    }
+
+    fn state(&self, site: &str) -> Arc<Site> {
+        let mut sites = self.sites.lock().unwrap_or_else(|e| e.into_inner());
+        let state = sites.entry(site.to_string()).or_insert_with(|| {
+            let limit = self.config.sites.get(site).unwrap_or(&self.config.default);
+            Arc::new(Site::new(limit))
+        });
+        Arc::clone(state)
+    }
+}
+
+/// Tells whether `host` is `domain` or one of its subdomains
+fn in_domain(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+impl Site {
+    fn new(limit: &SiteLimit) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(limit.concurrency)),
+            bucket: Mutex::new(Bucket::new(
+                limit.requests_per_minute,
+                limit.concurrency as f64,
+                Instant::now(),
+            )),
+        }
+    }
+}
+
+impl Bucket {
+    fn new(requests_per_minute: u32, capacity: f64, now: Instant) -> Self {
+        Self {
+            rate: f64::from(requests_per_minute) / 60.0,
+            capacity,
+            tokens: capacity,
+            refilled: now,
+            factor: 1.0,
+            slowed_until: None,
+        }
+    }
+
+    /// Halves the rate, down to `MIN_RATE_FACTOR`, for `slowdown`
+    fn slow_down(&mut self, now: Instant, slowdown: Duration) {
+        self.slowed_until = Some(now + slowdown);
+        if self.rate > 0.0 {
+            self.factor = (self.factor / 2.0).max(MIN_RATE_FACTOR);
+        }
+    }
+
+    /// Takes a token, or returns how long to wait before trying again
+    fn take(&mut self, now: Instant, slowdown: Duration) -> Option<Duration> {
+        if let Some(until) = self.slowed_until {
+            if self.rate == 0.0 && now < until {
+                return Some(until - now);
+            }
+            if now >= until {
+                // Recover one step at a time, as long as no new 429 arrives
+                self.factor = (self.factor * 2.0).min(1.0);
+                self.slowed_until = (self.factor < 1.0).then(|| now + slowdown);
+            }
+        }
+        if self.rate == 0.0 {
+            return None;
+        }
+
+        let rate = self.rate * self.factor;
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(self.capacity);
+        self.refilled = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SLOWDOWN: Duration = Duration::from_secs(60);
+
+    fn secs(secs: f64) -> Duration {
+        Duration::from_secs_f64(secs)
+    }
+
+    /// Takes tokens at the given offsets from `start`, returning each wait
+    fn take_at(bucket: &mut Bucket, start: Instant, offsets: &[f64]) -> Vec<Option<Duration>> {
+        offsets
+            .iter()
+            .map(|offset| bucket.take(start + secs(*offset), SLOWDOWN))
+            .collect()
+    }
+
+    #[test]
+    fn bucket_bursts_to_capacity_then_refills_at_rate() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(60, 2.0, start);
+
+        assert_eq!(
+            take_at(&mut bucket, start, &[0.0, 0.0, 0.0, 0.5, 1.0, 1.0]),
+            [
+                None,
+                None,
+                Some(secs(1.0)),
+                Some(secs(0.5)),
+                None,
+                Some(secs(1.0))
+            ]
+        );
+        // Idle time refills up to the capacity only
+        assert_eq!(
+            take_at(&mut bucket, start, &[10.0, 10.0, 10.0]),
+            [None, None, Some(secs(1.0))]
+        );
+    }
+
+    #[test]
+    fn bucket_without_rate_never_waits() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(0, 1.0, start);
+        assert_eq!(take_at(&mut bucket, start, &[0.0, 0.0, 0.0]), [None; 3]);
+    }
+
+    #[test]
+    fn slow_down_halves_the_rate_and_recovers_step_by_step() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(60, 1.0, start);
+        assert_eq!(bucket.take(start, SLOWDOWN), None);
+
+        bucket.slow_down(start, SLOWDOWN);
+        assert_eq!(bucket.factor, 0.5);
+        assert_eq!(bucket.take(start, SLOWDOWN), Some(secs(2.0)));
+        bucket.slow_down(start, SLOWDOWN);
+        assert_eq!(bucket.take(start, SLOWDOWN), Some(secs(4.0)));
+
+        // Every slowdown period without a 429 doubles the rate again
+        let later = start + SLOWDOWN;
+        assert_eq!(bucket.take(later, SLOWDOWN), None);
+        assert_eq!(bucket.factor, 0.5);
+        let recovered = later + SLOWDOWN;
+        assert_eq!(bucket.take(recovered, SLOWDOWN), None);
+        assert_eq!(bucket.factor, 1.0);
+        assert_eq!(bucket.slowed_until, None);
+    }
+
+    #[test]
+    fn slow_down_is_bounded() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(60, 1.0, start);
+        for _ in 0..10 {
+            bucket.slow_down(start, SLOWDOWN);
+        }
+        assert_eq!(bucket.factor, MIN_RATE_FACTOR);
+    }
+
+    #[test]
+    fn slow_down_pauses_sites_without_rate() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(0, 1.0, start);
+        bucket.slow_down(start, SLOWDOWN);
+        assert_eq!(bucket.take(start + secs(20.0), SLOWDOWN), Some(secs(40.0)));
+        assert_eq!(bucket.take(start + SLOWDOWN, SLOWDOWN), None);
+    }
+
+    #[test]
+    fn site_prefers_configured_extractors_and_domains() {
+        let mut sites = BTreeMap::new();
+        sites.insert("youtube".to_string(), SiteLimit::default());
+        sites.insert("vimeo.com".to_string(), SiteLimit::default());
+        let limiter = RateLimiter::new(LimitsConfig {
+            sites,
+            ..LimitsConfig::default()
+        });
+
+        let cases = [
+            (
+                "https://www.youtube.com/watch?v=a",
+                Some("Youtube"),
+                "youtube",
+            ),
+            ("https://youtu.be/a", None, "youtube"),
+            ("https://m.youtube.com/watch?v=a", None, "youtube"),
+            ("https://www.youtube.com/watch?v=a", None, "youtube"),
+            ("https://vimeo.com/1", None, "vimeo.com"),
+            (
+                "https://player.vimeo.com/video/1",
+                Some("VimeoPlayer"),
+                "vimeo.com",
+            ),
+            ("https://notvimeo.com/1", None, "notvimeo.com"),
+            ("https://WWW.Example.com/v", Some("Generic"), "example.com"),
+            ("not a url", None, ""),
+        ];
+        for (url, extractor, site) in cases {
+            assert_eq!(limiter.site(url, extractor), site, "{}", url);
+        }
+    }
+
+    #[test]
+    fn site_groups_known_hosts_without_configuration() {
+        let limiter = RateLimiter::new(LimitsConfig::default());
+
+        let cases = [
+            ("https://youtu.be/a", None, "youtube"),
+            (
+                "https://www.youtube.com/watch?v=a",
+                Some("Youtube"),
+                "youtube",
+            ),
+            ("https://dai.ly/x1", None, "dailymotion"),
+            ("https://notyoutube.com/a", None, "notyoutube.com"),
+            ("https://Example.com/v", Some("Generic"), "example.com"),
+        ];
+        for (url, extractor, site) in cases {
+            assert_eq!(limiter.site(url, extractor), site, "{}", url);
+        }
+    }
+}
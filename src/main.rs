@@ -1,165 +1,389 @@
-use application::error::Result;
-use application::{Config, Downloader, SheetClient};
-use std::fs::File;
-use std::io::{self, BufRead};
-use std::path::PathBuf;
+mod cli;
+
+use application::archive::{self, DownloadArchive};
+use application::config::{ConfigLoader, LoadedConfig};
+use application::downloader::DownloadJob;
+use application::error::{AppError, Result};
+use application::input;
+use application::orchestrator::{run_sources, Source};
+use application::progress::{DownloadSummary, FailureFilter};
+use application::watch::{InputWatcher, SheetWatcher};
+use application::{Config, DownloadProgress, Downloader};
+use chrono::Utc;
+use clap::Parser;
+use cli::{ArchiveAction, Cli, Command, DepsAction, GlobalArgs};
+use std::path::Path;
+use std::process::ExitCode;
 use tracing::{error, info};
 
 /// Main entry point for the application.
 ///
 /// # Steps
 /// 1. Initializes logging with file, line numbers and thread IDs
-/// 2. Creates a default configuration
-/// 3. Initializes the downloader with required directories
-/// 4. Runs the main application logic
+/// 2. Parses the command line
+/// 3. Loads the configuration from `ytb.toml`, `YTB_*` variables and CLI flags
+/// 4. Runs the selected subcommand
 ///
-/// # Errors
-/// Returns error if:
-/// - Logging initialization fails
-/// - Downloader creation fails
-/// - Application processing fails
+/// # Exit Codes
+/// - `0`: Every download succeeded
+/// - `1`: Some downloads failed or a dependency is missing
+/// - `2`: Invalid command-line usage
+/// - `3`: Configuration is invalid
+/// - `4`: The run was aborted by an error or a source could not be read
+/// - `5`: The run was aborted because the sheet could not be read
+/// - `6`: The run was aborted because the input directory could not be watched
 #[tokio::main]
-async fn main() -> Result<()> {
-    info!("Starting application...");
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_file(true)
+        .with_line_number(true)
+        .with_thread_ids(true)
+        .init();
 
-    let config = Config::default();
-    let downloader = Downloader::new(config).await?;
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            let code = if e.use_stderr() {
+                cli::EXIT_USAGE
+            } else {
+                cli::EXIT_OK
+            };
+            let _ = e.print();
+            return ExitCode::from(code);
+        }
+    };
 
-    if let Err(e) = run_application(&downloader).await {
-        error!("Application error: {}", e);
-        std::process::exit(1);
+    let loaded = match load_config(&cli.global) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("{}", e);
+            return ExitCode::from(cli::EXIT_CONFIG);
+        }
+    };
+    for (key, value, source) in loaded.report() {
+        info!("config {} = {} ({})", key, value, source);
     }
 
-    info!("Application completed successfully");
-    Ok(())
+    info!("Starting application...");
+    match run_command(
+        cli.command.unwrap_or(Command::Run { watch: false }),
+        loaded.config,
+    )
+    .await
+    {
+        Ok(code) => {
+            info!("Application completed");
+            ExitCode::from(code)
+        }
+        Err(e) => {
+            error!("Application error [{}]: {}", e.code(), e);
+            ExitCode::from(error_exit_code(&e))
+        }
+    }
 }
 
-/// Orchestrates concurrent processing of video downloads from multiple sources.
+/// Resolves the configuration using the global `--config`, `--set`, `--force` and `--mode` flags.
 ///
-/// # Processing Flow
-/// 1. Creates a vector of concurrent tasks
-/// 2. If configured, adds Google Sheet processing task
-/// 3. Adds local file processing task
-/// 4. Executes all tasks concurrently
+/// # Errors
+/// Returns error if an override is malformed or the configuration is invalid
+fn load_config(global: &GlobalArgs) -> Result<LoadedConfig> {
+    let mut loader = ConfigLoader::new();
+    if let Some(path) = &global.config {
+        loader = loader.file(path);
+    }
+    for pair in &global.overrides {
+        loader = loader.set_pair(pair)?;
+    }
+    if global.force {
+        loader = loader.set("force", "true");
+    }
+    if let Some(mode) = global.mode {
+        loader = loader.set("mode", mode.to_string());
+    }
+    loader.load()
+}
+
+/// Executes a subcommand and returns the process exit code.
 ///
 /// # Arguments
-/// * `downloader` - Handles video download operations and configuration
+/// * `command` - Subcommand selected on the command line
+/// * `config` - Resolved configuration
 ///
 /// # Errors
-/// Returns error if either:
-/// - Sheet processing fails
-/// - Local file processing fails
-/// - Task joining fails
-async fn run_application(downloader: &Downloader) -> Result<()> {
-    let mut tasks: Vec<futures::future::BoxFuture<'_, Result<()>>> = Vec::new();
-
-    // Process Google Sheet if configured
-    if let Some(sheet_url) = &downloader.config().sheet_url {
-        let sheet_client = SheetClient::new();
-        // tasks.push(Box::pin());
-        process_sheet(downloader, sheet_client, sheet_url).await;
-    }
-
-    // Process local files
-    // tasks.push(Box::pin(process_local_files(downloader)));
-    process_local_files(downloader).await;
-    
-
-    // Run all tasks concurrently
-    futures::future::try_join_all(tasks).await?;
-    Ok(())
+/// Returns error if the downloader cannot be created or the URLs to
+/// download cannot be read
+async fn run_command(command: Command, config: Config) -> Result<u8> {
+    if let Command::Deps { action } = command {
+        return match action {
+            DepsAction::Check => Ok(check_dependencies(&config)),
+            DepsAction::Update => {
+                Downloader::update_dependencies(&config).await?;
+                Ok(check_dependencies(&config))
+            }
+        };
+    }
+    if let Command::Archive { action } = command {
+        return manage_archive(&config, action);
+    }
+
+    let downloader = Downloader::new(config).await?;
+    let summary = match command {
+        Command::Run { watch: true } => run_daemon(&downloader).await?,
+        Command::Run { watch: false } => {
+            let sources = Source::configured(downloader.config());
+            return Ok(run_application(&downloader, sources).await);
+        }
+        Command::Download { inputs } => {
+            let urls = collect_urls(&inputs).await?;
+            downloader.process_urls(&urls).await?
+        }
+        Command::Sheet { url, watch } => {
+            let sheet_url = url
+                .or_else(|| downloader.config().sheet_url.clone())
+                .ok_or_else(|| {
+                    AppError::Config("no sheet URL given and sheet_url is not set".to_string())
+                })?;
+            if watch {
+                SheetWatcher::new(&downloader, &sheet_url).run().await?
+            } else {
+                return Ok(run_application(&downloader, vec![Source::Sheet(sheet_url)]).await);
+            }
+        }
+        Command::RetryFailed {
+            report,
+            kinds,
+            codes,
+            max_age,
+            min_age,
+        } => {
+            let filter = FailureFilter {
+                kinds,
+                codes,
+                max_age,
+                min_age,
+            };
+            let report = report.unwrap_or_else(|| downloader.config().failure_report());
+            let now = Utc::now();
+            let jobs: Vec<DownloadJob> = DownloadProgress::load_failures(&report)?
+                .into_iter()
+                .filter(|failure| filter.matches(failure, now))
+                .map(|failure| failure.job)
+                .collect();
+            info!("Retrying {} jobs from {}", jobs.len(), report.display());
+
+            let summary = downloader.process_jobs(&jobs).await?;
+            let recovered: Vec<DownloadJob> = jobs
+                .into_iter()
+                .filter(|job| !summary.has_failed(job))
+                .collect();
+            DownloadProgress::remove_failures(&report, &recovered)?;
+            summary
+        }
+        Command::Resume => {
+            let jobs = downloader.jobs().unfinished();
+            info!(
+                "Resuming {} unfinished jobs from {}",
+                jobs.len(),
+                downloader.jobs().path().display()
+            );
+            downloader.process_jobs(&jobs).await?
+        }
+        Command::Info { url } => {
+            print_info(&downloader, &url).await?;
+            return Ok(cli::EXIT_OK);
+        }
+        Command::Deps { .. } | Command::Archive { .. } => {
+            unreachable!("handled before creating the downloader")
+        }
+    };
+
+    Ok(exit_code(&summary))
 }
 
-/// Processes video URLs from a Google Sheet source.
+/// Prints the state of the managed binaries.
 ///
-/// # Processing Steps
-/// 1. Fetches URLs from the provided Google Sheet
-/// 2. Downloads videos for all valid URLs
-///
-/// # Arguments
-/// * `downloader` - Handles video download operations
-/// * `sheet_client` - Manages Google Sheet interactions
-/// * `sheet_url` - Complete URL to the Google Sheet
+/// # Returns
+/// `EXIT_OK` when every binary is installed, `EXIT_FAILURES` otherwise
+fn check_dependencies(config: &Config) -> u8 {
+    let mut code = cli::EXIT_OK;
+    for (name, path, installed) in Downloader::dependency_status(config) {
+        if installed {
+            println!("{:<8} ok       {}", name, path.display());
+        } else {
+            println!("{:<8} missing  {}", name, path.display());
+            code = cli::EXIT_FAILURES;
+        }
+    }
+    code
+}
+
+/// Lists, prunes or imports download archive entries.
 ///
 /// # Errors
-/// Returns error if:
-/// - Sheet URL is invalid
-/// - Sheet access fails
-/// - URL fetching fails
-/// - Video downloading fails
-async fn process_sheet(
-    downloader: &Downloader,
-    sheet_client: SheetClient,
-    sheet_url: &String,
-) -> Result<()> {
-    let urls = sheet_client.fetch_urls(&sheet_url).await?;
-    let reuslt = downloader.process_urls(&urls).await?;
-    Ok(reuslt)
+/// Returns error if `download_archive` is unset or the archive cannot be
+/// read or written
+fn manage_archive(config: &Config, action: ArchiveAction) -> Result<u8> {
+    let path = config
+        .archive_path()
+        .ok_or_else(|| AppError::Config("download_archive is not set".to_string()))?;
+    let archive = DownloadArchive::open(&path)?;
+
+    match action {
+        ArchiveAction::List => {
+            for entry in archive.entries() {
+                println!("{}", entry);
+            }
+        }
+        ArchiveAction::Prune { videos } => {
+            let entries: Vec<String> = videos
+                .iter()
+                .map(|video| {
+                    archive::entry_for_url(video)
+                        .or_else(|| archive::parse_entry(video))
+                        .unwrap_or_else(|| archive::entry("youtube", video))
+                })
+                .collect();
+            let removed = archive.remove(&entries)?;
+            println!(
+                "Removed {} of {} entries from {}",
+                removed,
+                entries.len(),
+                archive.path().display()
+            );
+        }
+        ArchiveAction::Import { file } => {
+            let added = archive.import(&file)?;
+            println!(
+                "Imported {} new entries into {}, {} in total",
+                added,
+                archive.path().display(),
+                archive.len()
+            );
+        }
+    }
+    Ok(cli::EXIT_OK)
 }
 
-/// Processes video URLs from local text files.
+/// Prints the metadata and formats of a single video.
 ///
-/// # Processing Steps
-/// 1. Reads the input directory
-/// 2. Processes each .txt file found
-/// 3. Downloads videos from URLs in each file
+/// # Errors
+/// Returns error if the video information cannot be fetched
+async fn print_info(downloader: &Downloader, url: &str) -> Result<()> {
+    let video = downloader.fetch_info(url).await?;
+
+    println!("Title:   {}", video.title);
+    println!("ID:      {}", video.id);
+    if let Some(channel) = &video.channel {
+        println!("Channel: {}", channel);
+    }
+    println!("Formats: {}", video.formats.len());
+    for format in &video.formats {
+        println!("  {}", format.format);
+    }
+    if let Some(format) = video.best_audio_format() {
+        println!("Best audio: {}", format.format_id);
+    }
+    if let Some(format) = video.best_video_format() {
+        println!("Best video: {}", format.format_id);
+    }
+
+    Ok(())
+}
+
+/// Expands `download` arguments into a list of URLs.
 ///
-/// # Arguments
-/// * `downloader` - Handles video download operations and configuration
+/// Arguments naming an existing file are read with `input::read_urls`, anything
+/// else must parse as a URL.
 ///
 /// # Errors
-/// Returns error if:
-/// - Directory reading fails
-/// - File reading fails
-/// - URL parsing fails
-/// - Video downloading fails
-async fn process_local_files(downloader: &Downloader) -> Result<()> {
-    // Then process local files
-    let input_dir = &downloader.config().input_dir;
-    let entries = std::fs::read_dir(input_dir)?;
-    for entry in entries {
-        let entry = entry?;
-        let path = entry.path();
-        if path.extension().and_then(|ext| ext.to_str()) == Some("txt") {
-            println!("Processing file: {:?}", path);
-            let urls = read_urls(&path).await?;
-            let reuslt = downloader.process_urls(&urls).await?;
-            return Ok(reuslt);
+/// Returns error if an argument is neither a readable file nor a valid URL
+async fn collect_urls(inputs: &[String]) -> Result<Vec<String>> {
+    let mut urls = Vec::new();
+    for arg in inputs {
+        let path = Path::new(arg);
+        if path.is_file() {
+            urls.extend(input::read_urls(path).await?);
+        } else {
+            url::Url::parse(arg).map_err(|e| AppError::InvalidInput {
+                input: arg.clone(),
+                source: e,
+            })?;
+            urls.push(arg.clone());
         }
     }
-    Ok(())
+    Ok(urls)
 }
 
-/// Reads and validates URLs from a text file.
+/// Orchestrates concurrent processing of video downloads from multiple sources.
 ///
-/// # Format
-/// - One URL per line
-/// - Empty lines are ignored
-/// - Lines are trimmed of whitespace
+/// # Processing Flow
+/// 1. Runs every source concurrently against the shared downloader
+/// 2. Logs the error of each source that could not be read
+/// 3. Prints the per-source and combined summary
 ///
 /// # Arguments
-/// * `path` - Path to the text file containing URLs
+/// * `downloader` - Handles video download operations and configuration
+/// * `sources` - Sources to download from
 ///
 /// # Returns
-/// A vector of validated URLs as strings
+/// `EXIT_ERROR` if a source failed, otherwise the exit code of the combined summary
+async fn run_application(downloader: &Downloader, sources: Vec<Source>) -> u8 {
+    let report = run_sources(downloader, sources).await;
+    for (source, result) in &report.results {
+        if let Err(e) = result {
+            error!("Processing {} failed: {}", source, e);
+        }
+    }
+    report.print();
+
+    if report.has_source_errors() {
+        cli::EXIT_ERROR
+    } else {
+        exit_code(&report.summary())
+    }
+}
+
+/// Runs the watch mode of every source until interrupted.
+///
+/// Polls the configured sheet, if any, while watching the input directory
+/// for new URL files.
 ///
 /// # Errors
-/// Returns error if:
-/// - File cannot be opened
-/// - File reading fails
-/// - Line parsing fails
-async fn read_urls(path: &PathBuf) -> Result<Vec<String>> {
-    let file = File::open(path)?;
-    let reader = io::BufReader::new(file);
-    let mut urls = Vec::new();
+/// Returns error if the input directory cannot be watched
+async fn run_daemon(downloader: &Downloader) -> Result<DownloadSummary> {
+    let input = InputWatcher::new(downloader).run();
+    let Some(sheet_url) = downloader.config().sheet_url.clone() else {
+        return input.await;
+    };
 
-    for line in reader.lines() {
-        let line = line?;
-        let trimmed = line.trim();
-        if !trimmed.is_empty() {
-            urls.push(trimmed.to_string());
-        }
+    let (mut summary, input_summary) =
+        tokio::try_join!(SheetWatcher::new(downloader, &sheet_url).run(), input)?;
+    summary.merge(input_summary);
+    Ok(summary)
+}
+
+/// Maps an error that aborted the run to the process exit code.
+///
+/// Configuration problems found after loading, such as a missing sheet URL,
+/// share `EXIT_CONFIG` with invalid configuration files. Every other fatal
+/// error has its own code.
+fn error_exit_code(error: &AppError) -> u8 {
+    match error {
+        AppError::SheetFetch { .. } | AppError::SheetStatus { .. } => cli::EXIT_SHEET,
+        _ => match error.code() {
+            "config" => cli::EXIT_CONFIG,
+            "sheet" => cli::EXIT_SHEET,
+            "watch" => cli::EXIT_WATCH,
+            _ => cli::EXIT_ERROR,
+        },
     }
+}
 
-    Ok(urls)
+/// Maps a batch summary to the process exit code.
+fn exit_code(summary: &DownloadSummary) -> u8 {
+    if summary.is_success() {
+        cli::EXIT_OK
+    } else {
+        cli::EXIT_FAILURES
+    }
 }
@@ -0,0 +1,157 @@
+//! Downloads of manifest based streams with the managed yt-dlp.
+//!
+//! HLS and DASH formats are made of fragments listed in a manifest, which
+//! `Transfer` cannot fetch. They are downloaded by running the yt-dlp binary
+//! of the libraries directory on the video URL, selecting the format by its
+//! id, so yt-dlp resolves the fragments and sends the site's headers itself.
+//! yt-dlp prints the bytes it has downloaded as it goes, for progress and
+//! bandwidth accounting.
+
+use crate::error::{AppError, Result};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::process::Command;
+
+/// Lines of yt-dlp's error output kept in `AppError::YtDlp`
+const STDERR_LINES: usize = 5;
+
+/// Start of the progress lines yt-dlp is asked to print
+const PROGRESS_PREFIX: &str = "ytb-downloaded ";
+
+/// Runs the managed yt-dlp binary
+pub struct YtDlp {
+    path: PathBuf,
+    ffmpeg: PathBuf,
+}
+
+impl YtDlp {
+    /// Uses the yt-dlp and ffmpeg binaries of the libraries directory
+    pub fn new(libraries_dir: &Path) -> Self {
+        Self {
+            path: libraries_dir.join("yt-dlp"),
+            ffmpeg: libraries_dir.join("ffmpeg"),
+        }
+    }
+
+    /// Downloads one format of a video to a file
+    ///
+    /// # Arguments
+    /// * `url` - URL of the video
+    /// * `format_id` - Format to download, as listed by yt-dlp
+    /// * `output` - File to write, replaced if it exists
+    /// * `rate` - Bytes per second yt-dlp may use, `None` for no limit
+    /// * `on_progress` - Called with the bytes downloaded so far, as yt-dlp
+    ///   reports them
+    ///
+    /// # Errors
+    /// * `AppError::YtDlp` if yt-dlp cannot be started or fails
+    pub async fn download_format(
+        &self,
+        url: &str,
+        format_id: &str,
+        output: &Path,
+        rate: Option<u64>,
+        mut on_progress: impl FnMut(u64),
+    ) -> Result<()> {
+        let mut command = Command::new(&self.path);
+        command
+            .args(["--no-playlist", "--newline", "--force-overwrites"])
+            .arg("--progress-template")
+            .arg(format!(
+                "download:{}%(progress.downloaded_bytes)s",
+                PROGRESS_PREFIX
+            ))
+            .arg("--ffmpeg-location")
+            .arg(&self.ffmpeg)
+            .args(["--format", format_id])
+            .arg("--output")
+            .arg(output_template(output));
+        if let Some(rate) = rate {
+            command.args(["--limit-rate", &rate.to_string()]);
+        }
+        command
+            .arg("--")
+            .arg(url)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        let cannot_run = |e: std::io::Error| AppError::YtDlp {
+            message: format!("cannot run {}: {}", self.path.display(), e),
+        };
+        let mut child = command.spawn().map_err(cannot_run)?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+
+        let read_progress = async {
+            let mut lines = BufReader::new(stdout).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if let Some(downloaded) = parse_progress(&line) {
+                    on_progress(downloaded);
+                }
+            }
+        };
+        let read_errors = async {
+            let mut errors = Vec::new();
+            let _ = stderr.read_to_end(&mut errors).await;
+            errors
+        };
+        let ((), errors) = tokio::join!(read_progress, read_errors);
+        let status = child.wait().await.map_err(cannot_run)?;
+        if status.success() {
+            return Ok(());
+        }
+
+        let stderr = String::from_utf8_lossy(&errors);
+        let lines: Vec<&str> = stderr.lines().collect();
+        let tail = lines[lines.len().saturating_sub(STDERR_LINES)..].join("\n");
+        Err(AppError::YtDlp {
+            message: format!("{}: {}", status, tail),
+        })
+    }
+}
+
+/// Reads the downloaded bytes of one of yt-dlp's progress lines
+///
+/// yt-dlp prints `NA` while the size is unknown; other output is ignored.
+fn parse_progress(line: &str) -> Option<u64> {
+    let downloaded: f64 = line.strip_prefix(PROGRESS_PREFIX)?.trim().parse().ok()?;
+    (downloaded >= 0.0).then_some(downloaded as u64)
+}
+
+/// Escapes a path for `--output`, where `%` starts a template field
+fn output_template(path: &Path) -> String {
+    path.to_string_lossy().replace('%', "%%")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_template_escapes_fields() {
+        let cases = [
+            ("/out/video.mp4", "/out/video.mp4"),
+            ("/out/100% live.mp4", "/out/100%% live.mp4"),
+            ("/out/%(title)s.mp4", "/out/%%(title)s.mp4"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(output_template(Path::new(path)), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn parse_progress_reads_prefixed_lines_only() {
+        let cases = [
+            ("ytb-downloaded 1024", Some(1024)),
+            ("ytb-downloaded 1536.0", Some(1536)),
+            ("ytb-downloaded NA", None),
+            ("[download] Destination: video.mp4", None),
+            ("1024", None),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(parse_progress(line), expected, "{}", line);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Local HTTP server answering tests with canned responses.
+//!
+//! Serves each response on its own connection, in order, and hands every
+//! request it receives to the test, so HTTP clients can be tested without
+//! reaching a real service.
+
+use std::collections::HashMap;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+/// A request received by the server
+#[derive(Debug)]
+pub(crate) struct Request {
+    /// Request line, e.g. `POST /hook HTTP/1.1`
+    pub line: String,
+    /// Headers, named in lowercase
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+/// A canned response
+pub(crate) struct Response {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: u16, body: &str) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: &str) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+}
+
+/// Starts a server answering with `responses`, one per request
+///
+/// # Returns
+/// * `String` - Base URL of the server, ending with `/`
+/// * `UnboundedReceiver<Request>` - Requests in the order they arrived
+pub(crate) async fn serve(responses: Vec<Response>) -> (String, UnboundedReceiver<Request>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let (requests, received) = unbounded_channel();
+    tokio::spawn(async move {
+        for response in responses {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let _ = requests.send(read_request(&mut socket).await);
+
+            let mut head = format!(
+                "HTTP/1.1 {} Mock\r\ncontent-length: {}\r\nconnection: close\r\n",
+                response.status,
+                response.body.len()
+            );
+            for (name, value) in &response.headers {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            head.push_str("\r\n");
+            socket.write_all(head.as_bytes()).await.unwrap();
+            socket.write_all(response.body.as_bytes()).await.unwrap();
+            let _ = socket.shutdown().await;
+        }
+    });
+    (url, received)
+}
+
+async fn read_request(socket: &mut TcpStream) -> Request {
+    let mut data = Vec::new();
+    let mut buffer = [0; 4096];
+    let head_end = loop {
+        let read = socket.read(&mut buffer).await.unwrap();
+        data.extend_from_slice(&buffer[..read]);
+        if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        assert!(read > 0, "connection closed before the end of the headers");
+    };
+
+    let head = String::from_utf8_lossy(&data[..head_end]).to_string();
+    let mut lines = head.lines();
+    let line = lines.next().unwrap_or_default().to_string();
+    let headers: HashMap<String, String> = lines
+        .filter_map(|header| header.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let length: usize = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    let mut body = data[head_end + 4..].to_vec();
+    while body.len() < length {
+        let read = socket.read(&mut buffer).await.unwrap();
+        assert!(read > 0, "connection closed before the end of the body");
+        body.extend_from_slice(&buffer[..read]);
+    }
+
+    Request {
+        line,
+        headers,
+        body: String::from_utf8_lossy(&body).to_string(),
+    }
+}
@@ -0,0 +1,167 @@
+//! Concurrent execution of download sources.
+//!
+//! Runs every configured source (Google Sheet, local URL files) at the same
+//! time against one shared `Downloader`, so they compete for the same
+//! fetch, download and merge limits, and collects a per-source result into
+//! one report.
+
+use crate::error::Result;
+use crate::input;
+use crate::progress::DownloadSummary;
+use crate::sheet::{SheetRow, SheetWriter};
+use crate::{Config, Downloader, SheetClient};
+use std::fmt;
+use tokio::sync::mpsc;
+
+/// A place URLs are read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// Google Sheet identified by its URL
+    Sheet(String),
+    /// The `.txt` URL files of the input directory
+    LocalFiles,
+}
+
+impl Source {
+    /// Returns the sources enabled by the configuration
+    ///
+    /// The sheet is included when `sheet_url` is set, local files always are.
+    pub fn configured(config: &Config) -> Vec<Source> {
+        let mut sources = Vec::new();
+        if let Some(sheet_url) = &config.sheet_url {
+            sources.push(Source::Sheet(sheet_url.clone()));
+        }
+        sources.push(Source::LocalFiles);
+        sources
+    }
+
+    /// Downloads every URL of this source
+    ///
+    /// Sheet rows get their status written back when `sheet.write_back_url` is set.
+    ///
+    /// # Errors
+    /// Returns error if the source itself cannot be read; individual
+    /// download failures are counted in the summary instead
+    async fn run(&self, downloader: &Downloader) -> Result<DownloadSummary> {
+        match self {
+            Source::Sheet(sheet_url) => {
+                let rows = SheetClient::new()
+                    .with_config(downloader.config().sheet.clone())
+                    .fetch_rows(sheet_url)
+                    .await?;
+                if rows.is_empty() {
+                    return Ok(DownloadSummary::default());
+                }
+                process_sheet_rows(downloader, sheet_url, &rows).await
+            }
+            Source::LocalFiles => input::process_local_files(downloader).await,
+        }
+    }
+}
+
+/// Downloads the given rows of a sheet
+///
+/// # Arguments
+/// * `downloader` - Shared downloader
+/// * `sheet_url` - URL of the sheet the rows were read from
+/// * `rows` - Rows to download
+///
+/// # Details
+/// Writes each row's status back when `sheet.write_back_url` is set.
+///
+/// # Errors
+/// Returns error if the write-back endpoint is not a valid URL
+pub async fn process_sheet_rows(
+    downloader: &Downloader,
+    sheet_url: &str,
+    rows: &[SheetRow],
+) -> Result<DownloadSummary> {
+    let jobs: Vec<_> = rows.iter().map(SheetRow::to_job).collect();
+
+    let Some(endpoint) = &downloader.config().sheet.write_back_url else {
+        return downloader.process_jobs(&jobs).await;
+    };
+    let writer = SheetWriter::new(endpoint, sheet_url)?;
+    let (events, receiver) = mpsc::unbounded_channel();
+    let (summary, ()) = tokio::join!(
+        downloader.process_jobs_with_events(&jobs, Some(events)),
+        writer.follow(rows, receiver)
+    );
+    summary
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Sheet(url) => write!(f, "sheet {}", url),
+            Source::LocalFiles => write!(f, "local files"),
+        }
+    }
+}
+
+/// Outcome of every source of a run
+pub struct RunReport {
+    pub results: Vec<(Source, Result<DownloadSummary>)>,
+}
+
+impl RunReport {
+    /// Combined counts of all sources that could be read
+    pub fn summary(&self) -> DownloadSummary {
+        let mut summary = DownloadSummary::default();
+        for (_, result) in &self.results {
+            if let Ok(source_summary) = result {
+                summary.merge(source_summary.clone());
+            }
+        }
+        summary
+    }
+
+    /// Returns true if at least one source could not be read
+    pub fn has_source_errors(&self) -> bool {
+        self.results.iter().any(|(_, result)| result.is_err())
+    }
+
+    /// Prints one line per source followed by the combined totals
+    pub fn print(&self) {
+        println!("\nRun Summary:");
+        for (source, result) in &self.results {
+            match result {
+                Ok(summary) => println!(
+                    "{}: {} URLs, {} succeeded, {} failed",
+                    source, summary.total, summary.succeeded, summary.failed
+                ),
+                Err(e) => println!("{}: error: {}", source, e),
+            }
+        }
+
+        let summary = self.summary();
+        println!(
+            "Total: {} URLs, {} succeeded, {} failed",
+            summary.total, summary.succeeded, summary.failed
+        );
+    }
+}
+
+/// Runs all sources concurrently against one shared downloader
+///
+/// # Arguments
+/// * `downloader` - Shared downloader, its stage limits bound work across all sources
+/// * `sources` - Sources to process
+///
+/// # Returns
+/// * `RunReport` - Result of every source, in the order given
+///
+/// # Details
+/// A failing source does not cancel the others; its error is kept in the report.
+pub async fn run_sources(downloader: &Downloader, sources: Vec<Source>) -> RunReport {
+    let results = futures::future::join_all(
+        sources
+            .iter()
+            .map(|source| async move { source.run(downloader).await }),
+    )
+    .await;
+
+    RunReport {
+        results: sources.into_iter().zip(results).collect(),
+    }
+}
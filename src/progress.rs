@@ -1,11 +1,27 @@
-use std::fs::OpenOptions;
-use std::io::Write;
+//! Progress tracking and reporting functionality.
+//!
+//! Provides mechanisms to track and display download progress,
+//! including completion rates, time estimates, and error counts.
+//!
+//! Byte counts are kept per job and per stream while they download or
+//! merge, so long videos show progress before they complete and the batch
+//! estimate is weighted by the bytes left rather than the videos left.
+
+use crate::bandwidth::{format_rate, format_size};
+use crate::downloader::DownloadJob;
+use crate::error::{AppError, ErrorKind};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-/// Progress tracking and reporting functionality.
-///
-/// Provides mechanisms to track and display download progress,
-/// including completion rates, time estimates, and error counts.
+/// Name of the failure report appended to by every batch, one JSON object
+/// per line, in `Config::reports_dir`
+pub const FAILURE_REPORT: &str = "failed.jsonl";
 
 /// Tracks and reports progress for batch video downloads.
 ///
@@ -13,14 +29,16 @@ use std::time::{Duration, Instant};
 /// - Total number of videos
 /// - Completed downloads
 /// - Error counts
-/// - Time estimates
+/// - Time estimates, weighted by the bytes left when they are known
+/// - Byte counts of the running jobs
+/// - Bandwidth limit in force
 ///
 /// # Examples
 ///
 /// ```
 /// use application::DownloadProgress;
 ///
-/// let progress = DownloadProgress::new(10);
+/// let mut progress = DownloadProgress::new(10);
 /// progress.update(true); // Update with successful download
 /// ```
 pub struct DownloadProgress {
@@ -28,7 +46,11 @@ pub struct DownloadProgress {
     pub completed: usize,
     pub start_time: Instant,
     pub errors: usize,
-    failed_urls: Vec<(String, String)>, // (URL, error message)
+    /// Global bandwidth limit in bytes per second, `None` for no limit
+    pub bandwidth_limit: Option<u64>,
+    /// Byte counts of the batch, updated by the running jobs
+    pub bytes: Arc<ByteProgress>,
+    failures: Vec<FailureRecord>,
 }
 
 impl DownloadProgress {
@@ -38,7 +60,9 @@ impl DownloadProgress {
             completed: 0,
             start_time: Instant::now(),
             errors: 0,
-            failed_urls: Vec::new(),
+            bandwidth_limit: None,
+            bytes: Arc::new(ByteProgress::new()),
+            failures: Vec::new(),
         }
     }
 
@@ -50,6 +74,11 @@ impl DownloadProgress {
         self.print_progress();
     }
 
+    /// Sets the bandwidth limit shown by the next progress lines
+    pub fn set_bandwidth_limit(&mut self, limit: Option<u64>) {
+        self.bandwidth_limit = limit;
+    }
+
     pub fn print_progress(&self) {
         let elapsed = self.start_time.elapsed();
         let avg_time_per_video = if self.completed > 0 {
@@ -58,8 +87,12 @@ impl DownloadProgress {
             Duration::from_secs(0)
         };
 
+        // Bytes left over throughput, or the average time per video without byte counts
         let remaining_videos = self.total_videos - self.completed;
-        let est_remaining_time = avg_time_per_video.mul_f64(remaining_videos as f64);
+        let est_remaining_time = self
+            .bytes
+            .eta(remaining_videos)
+            .unwrap_or_else(|| avg_time_per_video.mul_f64(remaining_videos as f64));
 
         println!(
             "Progress: {}/{} videos completed ({:.1}%)",
@@ -77,41 +110,872 @@ impl DownloadProgress {
             self.completed - self.errors,
             self.errors
         );
+        println!(
+            "Downloaded: {} at {}",
+            format_size(self.bytes.downloaded()),
+            format_rate(Some(self.bytes.throughput() as u64))
+        );
+        println!("Bandwidth limit: {}", format_rate(self.bandwidth_limit));
         println!("----------------------------------------");
     }
 
-    pub fn record_failure(&mut self, url: &String, error: String) {
-        self.failed_urls.push((url.to_string(), error));
+    /// Returns the final counts of the batch
+    pub fn summary(&self) -> DownloadSummary {
+        DownloadSummary {
+            total: self.total_videos,
+            succeeded: self.completed - self.errors,
+            failed: self.errors,
+            failed_jobs: self
+                .failures
+                .iter()
+                .map(|failure| failure.job.clone())
+                .collect(),
+        }
+    }
+
+    /// Records a failed job with the code, kind and attempts of its error
+    pub fn record_failure(&mut self, job: &DownloadJob, error: &AppError) {
+        self.failures.push(FailureRecord::new(job, error));
     }
 
     /// Exports failed download information to a file
     ///
-    /// Creates or appends to 'output/failed.txt' with details of each failed download
-    pub fn export_failures(&self) -> std::io::Result<()> {
-        if self.failed_urls.is_empty() {
+    /// Appends one `FailureRecord` per failed download to the report at `path`,
+    /// see `Config::failure_report`
+    pub fn export_failures(&self, path: &Path) -> std::io::Result<()> {
+        if self.failures.is_empty() {
             return Ok(());
         }
 
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open("output/failed.txt")?;
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
 
         let mut writer = std::io::BufWriter::new(file);
+        for failure in &self.failures {
+            serde_json::to_writer(&mut writer, failure)?;
+            writeln!(writer)?;
+        }
 
-        writeln!(
-            writer,
-            "\n=== Failed Downloads Report {} ===",
-            chrono::Local::now().format("%Y-%m-%d %H:%M:%S")
-        )?;
+        writer.flush()?;
+        Ok(())
+    }
 
-        for (url, error) in &self.failed_urls {
-            writeln!(writer, "URL: {}", url)?;
-            writeln!(writer, "Error: {}", error)?;
-            writeln!(writer, "---")?;
+    /// Reads back the failures recorded by `export_failures`
+    ///
+    /// # Returns
+    /// * `Vec<FailureRecord>` - The latest failure of each job, in report order
+    ///
+    /// # Details
+    /// Lines that are not valid records are skipped with a warning.
+    pub fn load_failures(path: &Path) -> std::io::Result<Vec<FailureRecord>> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut failures: Vec<FailureRecord> = Vec::new();
+
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<FailureRecord>(&line) {
+                Ok(failure) => {
+                    let id = failure.job.id();
+                    failures.retain(|known| known.job.id() != id);
+                    failures.push(failure);
+                }
+                Err(e) => eprintln!("Warning: skipping line {} of {:?}: {}", number + 1, path, e),
+            }
         }
 
-        writer.flush()?;
+        Ok(failures)
+    }
+
+    /// Removes every record of the given jobs from a failure report
+    ///
+    /// Used once retried jobs succeeded, so they are not retried again.
+    pub fn remove_failures(path: &Path, jobs: &[DownloadJob]) -> std::io::Result<()> {
+        if jobs.is_empty() {
+            return Ok(());
+        }
+        let ids: Vec<String> = jobs.iter().map(DownloadJob::id).collect();
+
+        let content = std::fs::read_to_string(path)?;
+        let kept: String = content
+            .lines()
+            .filter(|line| {
+                serde_json::from_str::<FailureRecord>(line)
+                    .map_or(true, |failure| !ids.contains(&failure.job.id()))
+            })
+            .map(|line| format!("{}\n", line))
+            .collect();
+
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, kept)?;
+        std::fs::rename(&temp, path)
+    }
+}
+
+/// Work of a job whose bytes are counted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    Audio,
+    Video,
+    /// Output written by ffmpeg, merging or converting the streams
+    Merge,
+}
+
+impl fmt::Display for StreamKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StreamKind::Audio => "audio",
+            StreamKind::Video => "video",
+            StreamKind::Merge => "merge",
+        })
+    }
+}
+
+/// Byte counter of one stream download or merge
+///
+/// Updated by the task doing the work, read by the progress reports.
+///
+/// # Examples
+///
+/// ```
+/// use application::progress::StreamProgress;
+///
+/// let stream = StreamProgress::default();
+/// stream.start(0, Some(1000));
+/// stream.advance(250);
+/// let snapshot = stream.snapshot();
+/// assert_eq!(snapshot.done, 250);
+/// assert_eq!(snapshot.remaining(), Some(750));
+/// ```
+#[derive(Debug)]
+pub struct StreamProgress {
+    state: Mutex<StreamState>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct StreamState {
+    done: u64,
+    total: Option<u64>,
+    /// Bytes already there when counting started, left out of the speed
+    base: u64,
+    started: Instant,
+    finished: bool,
+}
+
+/// State of a `StreamProgress` at one point in time
+#[derive(Debug, Clone, Copy)]
+pub struct StreamSnapshot {
+    pub done: u64,
+    /// Complete size, when known
+    pub total: Option<u64>,
+    /// Bytes transferred since counting started, without the resumed part
+    pub transferred: u64,
+    /// Average speed in bytes per second since counting started
+    pub speed: f64,
+    pub finished: bool,
+}
+
+impl Default for StreamProgress {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(StreamState {
+                done: 0,
+                total: None,
+                base: 0,
+                started: Instant::now(),
+                finished: false,
+            }),
+        }
+    }
+}
+
+impl StreamProgress {
+    /// Starts counting again from `done` bytes, e.g. the part a resumed download kept
+    pub fn start(&self, done: u64, total: Option<u64>) {
+        *self.lock() = StreamState {
+            done,
+            total,
+            base: done,
+            started: Instant::now(),
+            finished: false,
+        };
+    }
+
+    /// Sets the complete size, once it is learned
+    pub fn set_total(&self, total: Option<u64>) {
+        self.lock().total = total;
+    }
+
+    /// Counts bytes received
+    pub fn advance(&self, bytes: u64) {
+        self.lock().done += bytes;
+    }
+
+    /// Sets the count, for work only observed from outside, like a merge
+    pub fn set(&self, done: u64) {
+        self.lock().done = done;
+    }
+
+    /// Marks the work complete, its count becoming its size
+    pub fn finish(&self) {
+        let mut state = self.lock();
+        state.total = Some(state.done);
+        state.finished = true;
+    }
+
+    pub fn snapshot(&self) -> StreamSnapshot {
+        let state = *self.lock();
+        let transferred = state.done.saturating_sub(state.base);
+        let elapsed = state.started.elapsed().as_secs_f64();
+        StreamSnapshot {
+            done: state.done,
+            total: state.total,
+            transferred,
+            speed: if elapsed > 0.0 {
+                transferred as f64 / elapsed
+            } else {
+                0.0
+            },
+            finished: state.finished,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, StreamState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl StreamSnapshot {
+    /// Returns the bytes left, when the size is known
+    pub fn remaining(&self) -> Option<u64> {
+        self.total.map(|total| total.saturating_sub(self.done))
+    }
+
+    /// Returns the time left at the current speed
+    pub fn eta(&self) -> Option<Duration> {
+        let remaining = self.remaining()?;
+        estimate(remaining, self.speed)
+    }
+}
+
+impl fmt::Display for StreamSnapshot {
+    /// Writes e.g. `120.5 MiB/300.0 MiB (40.2%) at 2.1 MiB/s, 85s left`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format_size(self.done))?;
+        if let Some(total) = self.total {
+            write!(f, "/{}", format_size(total))?;
+            if total > 0 {
+                let percent = (self.done as f64 / total as f64 * 100.0).min(100.0);
+                write!(f, " ({:.1}%)", percent)?;
+            }
+        }
+        if self.finished {
+            return write!(f, " done");
+        }
+        write!(f, " at {}", format_rate(Some(self.speed as u64)))?;
+        if let Some(eta) = self.eta() {
+            write!(f, ", {:.0}s left", eta.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
+
+/// Byte counters of the streams of one job
+#[derive(Debug)]
+pub struct JobProgress {
+    /// Position of the job in its batch, starting at 1
+    pub index: usize,
+    streams: Mutex<Vec<(StreamKind, Arc<StreamProgress>)>>,
+}
+
+impl JobProgress {
+    pub fn new(index: usize) -> Self {
+        Self {
+            index,
+            streams: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the counter of a stream, created on first use
+    pub fn stream(&self, kind: StreamKind) -> Arc<StreamProgress> {
+        let mut streams = self.lock();
+        if let Some((_, stream)) = streams.iter().find(|(known, _)| *known == kind) {
+            return Arc::clone(stream);
+        }
+        let stream = Arc::new(StreamProgress::default());
+        streams.push((kind, Arc::clone(&stream)));
+        stream
+    }
+
+    /// Returns the snapshots of the downloaded streams, merges left out
+    fn downloads(&self) -> Vec<StreamSnapshot> {
+        self.lock()
+            .iter()
+            .filter(|(kind, _)| *kind != StreamKind::Merge)
+            .map(|(_, stream)| stream.snapshot())
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<(StreamKind, Arc<StreamProgress>)>> {
+        self.streams.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl fmt::Display for JobProgress {
+    /// Writes e.g. `Video 3: video 120.5 MiB/300.0 MiB (40.2%) at 2.1 MiB/s, 85s left`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Video {}:", self.index)?;
+        let streams = self.lock();
+        if streams.is_empty() {
+            return write!(f, " waiting");
+        }
+        for (i, (kind, stream)) in streams.iter().enumerate() {
+            let separator = if i == 0 { "" } else { ";" };
+            write!(f, "{} {} {}", separator, kind, stream.snapshot())?;
+        }
         Ok(())
     }
 }
+
+/// Byte counts of a batch: its running jobs and the totals of the finished ones
+#[derive(Debug)]
+pub struct ByteProgress {
+    start_time: Instant,
+    running: Mutex<Vec<Arc<JobProgress>>>,
+    finished: Mutex<FinishedBytes>,
+}
+
+/// Totals of the jobs that stopped running
+#[derive(Debug, Default)]
+struct FinishedBytes {
+    /// Size of the downloaded streams
+    size: u64,
+    /// Bytes actually received, without resumed or reused parts
+    transferred: u64,
+    /// Jobs that downloaded anything
+    jobs: usize,
+}
+
+impl Default for ByteProgress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ByteProgress {
+    pub fn new() -> Self {
+        Self {
+            start_time: Instant::now(),
+            running: Mutex::new(Vec::new()),
+            finished: Mutex::new(FinishedBytes::default()),
+        }
+    }
+
+    /// Registers a starting job, reported until `finish_job`
+    pub fn start_job(&self, index: usize) -> Arc<JobProgress> {
+        let job = Arc::new(JobProgress::new(index));
+        lock(&self.running).push(Arc::clone(&job));
+        job
+    }
+
+    /// Stops reporting a job, adding its bytes to the batch totals
+    pub fn finish_job(&self, job: &JobProgress) {
+        lock(&self.running).retain(|running| !std::ptr::eq(running.as_ref(), job));
+        let streams = job.downloads();
+        let mut finished = lock(&self.finished);
+        if streams.iter().any(|stream| stream.done > 0) {
+            finished.jobs += 1;
+        }
+        finished.size += streams.iter().map(|stream| stream.done).sum::<u64>();
+        finished.transferred += streams.iter().map(|stream| stream.transferred).sum::<u64>();
+    }
+
+    /// Returns the bytes the batch downloaded so far
+    pub fn downloaded(&self) -> u64 {
+        let running: u64 = self
+            .running_downloads()
+            .iter()
+            .map(|stream| stream.done)
+            .sum();
+        lock(&self.finished).size + running
+    }
+
+    /// Returns the bytes per second the batch received since it started
+    pub fn throughput(&self) -> f64 {
+        let running: u64 = self
+            .running_downloads()
+            .iter()
+            .map(|stream| stream.transferred)
+            .sum();
+        let transferred = lock(&self.finished).transferred + running;
+        let elapsed = self.start_time.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            transferred as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    /// Estimates the time left for the batch from the bytes left and the throughput
+    ///
+    /// # Arguments
+    /// * `remaining_jobs` - Jobs of the batch not finished yet, running ones included
+    ///
+    /// # Details
+    /// The bytes left are those of the running streams of known size, plus
+    /// the average size of the finished jobs for each job not started yet.
+    /// `None` until a size and a throughput are known.
+    pub fn eta(&self, remaining_jobs: usize) -> Option<Duration> {
+        let running = lock(&self.running).len();
+        let streams = self.running_downloads();
+        let mut remaining: u64 = streams.iter().filter_map(|s| s.remaining()).sum();
+
+        let waiting = remaining_jobs.saturating_sub(running) as u64;
+        if waiting > 0 {
+            let finished = lock(&self.finished);
+            if finished.jobs == 0 {
+                return None;
+            }
+            let average = finished.size / finished.jobs as u64;
+            remaining = remaining.saturating_add(average.saturating_mul(waiting));
+        } else if streams.iter().all(|stream| stream.total.is_none()) {
+            return None;
+        }
+
+        estimate(remaining, self.throughput())
+    }
+
+    /// Prints the byte progress of every running job and of the batch
+    pub fn print(&self) {
+        let mut running = lock(&self.running).clone();
+        if running.is_empty() {
+            return;
+        }
+        running.sort_by_key(|job| job.index);
+        for job in &running {
+            println!("{}", job);
+        }
+        println!(
+            "Batch: {} downloaded at {}",
+            format_size(self.downloaded()),
+            format_rate(Some(self.throughput() as u64))
+        );
+    }
+
+    fn running_downloads(&self) -> Vec<StreamSnapshot> {
+        lock(&self.running)
+            .iter()
+            .flat_map(|job| job.downloads())
+            .collect()
+    }
+}
+
+/// Returns the time to receive `remaining` bytes at `speed` bytes per second
+///
+/// `None` without a speed, or when the time does not fit a `Duration`.
+fn estimate(remaining: u64, speed: f64) -> Option<Duration> {
+    if speed > 0.0 {
+        Duration::try_from_secs_f64(remaining as f64 / speed).ok()
+    } else {
+        None
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    // Counters stay meaningful after a panic elsewhere, keep going
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// One failed download in the failure report
+///
+/// Carries the whole job, so a retry keeps the title, folder and mode of
+/// its source. Reports that only hold a URL load as jobs without overrides.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailureRecord {
+    #[serde(flatten)]
+    pub job: DownloadJob,
+    /// Stable error code, see `AppError::code`
+    pub code: String,
+    pub kind: ErrorKind,
+    pub message: String,
+    /// RFC 3339 time of the failure
+    pub timestamp: String,
+    /// Number of times the failing stage was tried
+    pub attempts: u32,
+}
+
+impl FailureRecord {
+    /// Describes the failure of `job` with `error`, timestamped now
+    pub fn new(job: &DownloadJob, error: &AppError) -> Self {
+        Self {
+            job: job.clone(),
+            code: error.code().to_string(),
+            kind: error.kind(),
+            message: error.to_string(),
+            timestamp: Utc::now().to_rfc3339(),
+            attempts: error.attempts(),
+        }
+    }
+
+    /// Returns how long before `now` the failure happened
+    ///
+    /// `None` if the timestamp cannot be parsed.
+    pub fn age(&self, now: DateTime<Utc>) -> Option<Duration> {
+        let time = DateTime::parse_from_rfc3339(&self.timestamp).ok()?;
+        (now - time.with_timezone(&Utc)).to_std().ok()
+    }
+}
+
+/// Selects the failures of a report to retry
+///
+/// An empty list or unset age accepts every failure.
+#[derive(Debug, Clone, Default)]
+pub struct FailureFilter {
+    pub kinds: Vec<ErrorKind>,
+    pub codes: Vec<String>,
+    /// Only failures that happened at most this long ago
+    pub max_age: Option<Duration>,
+    /// Only failures that happened at least this long ago
+    pub min_age: Option<Duration>,
+}
+
+impl FailureFilter {
+    /// Returns true if the failure passes every criterion
+    ///
+    /// Failures with an unreadable timestamp fail any age criterion.
+    pub fn matches(&self, failure: &FailureRecord, now: DateTime<Utc>) -> bool {
+        if !self.kinds.is_empty() && !self.kinds.contains(&failure.kind) {
+            return false;
+        }
+        if !self.codes.is_empty() && !self.codes.contains(&failure.code) {
+            return false;
+        }
+        if self.max_age.is_none() && self.min_age.is_none() {
+            return true;
+        }
+        let Some(age) = failure.age(now) else {
+            return false;
+        };
+        self.max_age.is_none_or(|max| age <= max) && self.min_age.is_none_or(|min| age >= min)
+    }
+}
+
+/// Final counts of a finished batch, used for summaries and exit codes
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DownloadSummary {
+    pub total: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub failed_jobs: Vec<DownloadJob>,
+}
+
+impl DownloadSummary {
+    /// Returns true when no download in the batch failed
+    pub fn is_success(&self) -> bool {
+        self.failed == 0
+    }
+
+    /// Adds the counts of another batch to this one
+    pub fn merge(&mut self, other: DownloadSummary) {
+        self.total += other.total;
+        self.succeeded += other.succeeded;
+        self.failed += other.failed;
+        self.failed_jobs.extend(other.failed_jobs);
+    }
+
+    /// Restricts the summary to the given URLs of the batch
+    ///
+    /// Used to report on one input when several were merged into a single batch.
+    pub fn subset(&self, urls: &[String]) -> DownloadSummary {
+        let failed_jobs: Vec<DownloadJob> = self
+            .failed_jobs
+            .iter()
+            .filter(|job| urls.contains(&job.url))
+            .cloned()
+            .collect();
+        let failed = urls
+            .iter()
+            .filter(|url| failed_jobs.iter().any(|job| &job.url == *url))
+            .count();
+        DownloadSummary {
+            total: urls.len(),
+            succeeded: urls.len() - failed,
+            failed,
+            failed_jobs,
+        }
+    }
+
+    /// Returns true when the job, told apart by its id, failed in the batch
+    pub fn has_failed(&self, job: &DownloadJob) -> bool {
+        let id = job.id();
+        self.failed_jobs.iter().any(|failed| failed.id() == id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::DownloadMode;
+    use std::path::PathBuf;
+
+    fn job(url: &str) -> DownloadJob {
+        DownloadJob::from(url.to_string())
+    }
+
+    /// A batch started `secs` seconds ago, so its throughput is bytes / `secs`
+    fn batch_started(secs: u64) -> ByteProgress {
+        ByteProgress {
+            start_time: Instant::now() - Duration::from_secs(secs),
+            ..ByteProgress::new()
+        }
+    }
+
+    fn assert_about(eta: Option<Duration>, secs: f64) {
+        let eta = eta.expect("an estimate").as_secs_f64();
+        assert!((eta - secs).abs() < 0.1, "{} is not about {}", eta, secs);
+    }
+
+    #[test]
+    fn throughput_counts_transferred_bytes_only() {
+        assert_eq!(ByteProgress::new().throughput(), 0.0);
+
+        let progress = batch_started(10);
+        let job = progress.start_job(1);
+        // A resumed stream: the 1000 bytes kept are not received now
+        let video = job.stream(StreamKind::Video);
+        video.start(1000, Some(3000));
+        video.advance(500);
+        assert!((progress.throughput() - 50.0).abs() < 1.0);
+
+        progress.finish_job(&job);
+        let next = progress.start_job(2);
+        next.stream(StreamKind::Audio).advance(500);
+        assert!((progress.throughput() - 100.0).abs() < 1.0);
+        assert_eq!(progress.downloaded(), 2000);
+    }
+
+    #[test]
+    fn eta_weights_bytes_left_of_known_sizes() {
+        let progress = batch_started(10);
+        let job = progress.start_job(1);
+        let video = job.stream(StreamKind::Video);
+        video.start(0, Some(1000));
+        video.advance(500);
+        // Unknown size: its bytes count in the throughput only
+        let audio = job.stream(StreamKind::Audio);
+        audio.start(0, None);
+        audio.advance(500);
+        // 500 bytes left at 100 B/s
+        assert_about(progress.eta(1), 5.0);
+
+        let finished = progress.start_job(2);
+        finished.stream(StreamKind::Video).advance(2000);
+        progress.finish_job(&finished);
+        // 500 bytes + 2 waiting jobs of 2000 bytes on average, at 300 B/s
+        assert_about(progress.eta(3), 15.0);
+    }
+
+    #[test]
+    fn eta_needs_a_size_and_a_throughput() {
+        // Nothing running or left
+        assert_eq!(batch_started(10).eta(0), None);
+
+        // Zero bytes transferred
+        let progress = batch_started(10);
+        let job = progress.start_job(1);
+        job.stream(StreamKind::Video).start(0, Some(1000));
+        assert_eq!(progress.throughput(), 0.0);
+        assert_eq!(progress.eta(1), None);
+
+        // Only a resumed part
+        job.stream(StreamKind::Video).start(1000, Some(2000));
+        assert_eq!(progress.eta(1), None);
+
+        // Every running size unknown
+        let progress = batch_started(10);
+        progress.start_job(1).stream(StreamKind::Video).advance(500);
+        assert_eq!(progress.eta(1), None);
+
+        // Waiting jobs, but no finished job with bytes to average
+        let empty = progress.start_job(2);
+        progress.finish_job(&empty);
+        assert_eq!(progress.eta(3), None);
+    }
+
+    #[test]
+    fn eta_does_not_overflow() {
+        let progress = batch_started(10);
+        let job = progress.start_job(1);
+        let video = job.stream(StreamKind::Video);
+        video.start(0, Some(u64::MAX));
+        video.advance(1);
+        assert_eq!(progress.eta(1), None);
+
+        let finished = progress.start_job(2);
+        finished.stream(StreamKind::Video).advance(u64::MAX / 2);
+        progress.finish_job(&finished);
+        // Bytes left saturate instead of wrapping to a small estimate
+        assert!(progress
+            .eta(usize::MAX)
+            .is_some_and(|eta| eta > Duration::from_secs(1)));
+
+        let stream = StreamSnapshot {
+            done: 0,
+            total: Some(u64::MAX),
+            transferred: 1,
+            speed: f64::MIN_POSITIVE,
+            finished: false,
+        };
+        assert_eq!(stream.eta(), None);
+    }
+
+    fn failure(url: &str, code: &str, kind: ErrorKind, timestamp: &str) -> FailureRecord {
+        FailureRecord {
+            job: job(url),
+            code: code.to_string(),
+            kind,
+            message: String::new(),
+            timestamp: timestamp.to_string(),
+            attempts: 1,
+        }
+    }
+
+    #[test]
+    fn failure_filter_combines_every_criterion() {
+        let now = DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let hour = Duration::from_secs(3600);
+        let recent = failure(
+            "https://youtu.be/a",
+            "rate_limited",
+            ErrorKind::Retryable,
+            "2024-05-01T11:30:00Z",
+        );
+        let old = failure(
+            "https://youtu.be/b",
+            "video_private",
+            ErrorKind::Permanent,
+            "2024-04-01T12:00:00Z",
+        );
+        let undated = failure("https://youtu.be/c", "network", ErrorKind::Retryable, "?");
+
+        let cases = [
+            (FailureFilter::default(), [true, true, true]),
+            (
+                FailureFilter {
+                    kinds: vec![ErrorKind::Retryable],
+                    ..FailureFilter::default()
+                },
+                [true, false, true],
+            ),
+            (
+                FailureFilter {
+                    codes: vec!["video_private".to_string(), "network".to_string()],
+                    ..FailureFilter::default()
+                },
+                [false, true, true],
+            ),
+            (
+                FailureFilter {
+                    max_age: Some(hour),
+                    ..FailureFilter::default()
+                },
+                [true, false, false],
+            ),
+            (
+                FailureFilter {
+                    min_age: Some(hour),
+                    ..FailureFilter::default()
+                },
+                [false, true, false],
+            ),
+            (
+                FailureFilter {
+                    kinds: vec![ErrorKind::Retryable],
+                    min_age: Some(hour),
+                    ..FailureFilter::default()
+                },
+                [false, false, false],
+            ),
+        ];
+        for (filter, expected) in cases {
+            let matched = [&recent, &old, &undated].map(|failure| filter.matches(failure, now));
+            assert_eq!(matched, expected, "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn failure_reports_keep_the_job_and_its_latest_failure() {
+        let path = std::env::temp_dir().join(format!("ytb-failures-{}.jsonl", std::process::id()));
+        let job = DownloadJob {
+            url: "https://youtu.be/a".to_string(),
+            title: Some("Title".to_string()),
+            folder: Some(PathBuf::from("music")),
+            mode: Some(DownloadMode::Audio),
+        };
+        let mut progress = DownloadProgress::new(3);
+        progress.record_failure(&job, &AppError::Config("first".to_string()));
+        progress.record_failure(&job, &AppError::Sheet("second".to_string()));
+        progress.export_failures(&path).unwrap();
+        // Reports written before jobs were recorded only have the URL
+        let mut content = std::fs::read_to_string(&path).unwrap();
+        content.push_str(
+            r#"{"url":"https://youtu.be/b","code":"network","kind":"retryable","message":"","timestamp":"2024-05-01T11:30:00Z","attempts":2}"#,
+        );
+        content.push_str("\nnot json\n");
+        std::fs::write(&path, content).unwrap();
+
+        let failures = DownloadProgress::load_failures(&path).unwrap();
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[0].job, job);
+        assert_eq!(failures[0].code, "sheet");
+        assert_eq!(
+            failures[1].job,
+            DownloadJob::from("https://youtu.be/b".to_string())
+        );
+
+        DownloadProgress::remove_failures(&path, &[job]).unwrap();
+        let failures = DownloadProgress::load_failures(&path).unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].job.url, "https://youtu.be/b");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn summary_subset_counts_only_the_given_urls() {
+        let summary = DownloadSummary {
+            total: 3,
+            succeeded: 1,
+            failed: 2,
+            failed_jobs: vec![job("a"), job("c")],
+        };
+        let subset = summary.subset(&["a".to_string(), "b".to_string()]);
+        assert_eq!(subset.total, 2);
+        assert_eq!(subset.succeeded, 1);
+        assert_eq!(subset.failed_jobs, [job("a")]);
+        assert!(!subset.is_success());
+    }
+
+    #[test]
+    fn has_failed_tells_jobs_of_one_url_apart() {
+        let in_folder = DownloadJob {
+            folder: Some(PathBuf::from("music")),
+            ..job("a")
+        };
+        let as_audio = DownloadJob {
+            mode: Some(DownloadMode::Audio),
+            ..job("a")
+        };
+        let summary = DownloadSummary {
+            total: 3,
+            succeeded: 2,
+            failed: 1,
+            failed_jobs: vec![in_folder.clone()],
+        };
+        assert!(summary.has_failed(&in_folder));
+        assert!(!summary.has_failed(&as_audio));
+        assert!(!summary.has_failed(&job("a")));
+    }
+}
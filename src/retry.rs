@@ -0,0 +1,237 @@
+//! Retrying of failed download stages.
+//!
+//! Only errors whose `AppError::kind` is retryable (network trouble, server
+//! errors, rate limiting) are retried, with an exponentially growing delay.
+//! Permanent ones, like private, removed or geo-blocked videos, fail at once.
+
+use crate::error::{AppError, ErrorKind, Result, Stage};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::time::Duration;
+
+/// Retry settings, the `[retry]` table of the configuration
+///
+/// The delay before retry `n` is `base_delay_ms * 2^(n-1)`, capped at
+/// `max_delay_ms`, then shortened by a random fraction of up to `jitter`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Attempts per stage, including the first one; 1 disables retrying
+    pub max_attempts: u32,
+    /// Delay before the first retry
+    pub base_delay_ms: u64,
+    /// Upper bound of the delay between two attempts
+    pub max_delay_ms: u64,
+    /// Fraction of the delay, between 0 and 1, that is randomized
+    pub jitter: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay_ms: 1000,
+            max_delay_ms: 30_000,
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Checks that the settings describe a usable policy
+    ///
+    /// # Errors
+    /// * If `max_attempts` is 0 or `jitter` is outside `0..=1`
+    pub fn validate(&self) -> Result<()> {
+        if self.max_attempts == 0 {
+            return Err(AppError::Config(
+                "retry.max_attempts must be greater than 0".to_string(),
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err(AppError::Config(
+                "retry.jitter must be between 0 and 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the delay to wait after the given failed attempt
+    ///
+    /// # Arguments
+    /// * `attempt` - Number of the attempt that failed, starting at 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u64.saturating_pow(attempt.saturating_sub(1));
+        let delay = self
+            .base_delay_ms
+            .saturating_mul(factor)
+            .min(self.max_delay_ms);
+        let jitter = rand::thread_rng().gen_range(0.0..=self.jitter);
+        Duration::from_millis(delay).mul_f64(1.0 - jitter)
+    }
+}
+
+/// Runs an operation, retrying it while it fails with transient errors
+///
+/// # Arguments
+/// * `policy` - Number of attempts and delays between them
+/// * `url` - URL of the job the operation belongs to
+/// * `stage` - Stage of the job the operation performs
+/// * `operation` - Creates the future of one attempt
+///
+/// # Returns
+/// * `Result<T>` - Value of the first successful attempt
+///
+/// # Errors
+/// Returns the error of the last attempt, right away if it is not retryable,
+/// wrapped in `AppError::Job` with the URL and stage
+pub async fn retry<T, F, Fut>(
+    policy: &RetryConfig,
+    url: &str,
+    stage: Stage,
+    mut operation: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < policy.max_attempts && e.kind() == ErrorKind::Retryable => {
+                let delay = policy.delay(attempt);
+                eprintln!(
+                    "Warning: {} {} failed (attempt {}/{}), retrying in {:.1}s: {}",
+                    stage,
+                    url,
+                    attempt,
+                    policy.max_attempts,
+                    delay.as_secs_f64(),
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e.after_attempts(url, stage, attempt)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn policy(jitter: f64) -> RetryConfig {
+        RetryConfig {
+            max_attempts: 4,
+            base_delay_ms: 100,
+            max_delay_ms: 1000,
+            jitter,
+        }
+    }
+
+    fn failure(message: &str) -> AppError {
+        AppError::YtDlp {
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        let policy = policy(0.0);
+        let delays: Vec<u128> = (1..=6)
+            .map(|attempt| policy.delay(attempt).as_millis())
+            .collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+    }
+
+    #[test]
+    fn delay_saturates_at_large_attempts() {
+        let policy = policy(0.0);
+        for attempt in [64, 65, 1000, u32::MAX] {
+            assert_eq!(
+                policy.delay(attempt),
+                Duration::from_millis(1000),
+                "{}",
+                attempt
+            );
+        }
+        let unbounded = RetryConfig {
+            max_delay_ms: u64::MAX,
+            ..policy
+        };
+        // Precision is lost converting to f64 for the jitter, not range
+        assert!(unbounded.delay(u32::MAX) >= Duration::from_secs(u64::MAX / 1000));
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_delay() {
+        let policy = policy(0.5);
+        for attempt in 1..=5 {
+            let full = policy.delay(attempt).as_secs_f64();
+            let bounded = RetryConfig {
+                jitter: 0.0,
+                ..policy.clone()
+            }
+            .delay(attempt)
+            .as_secs_f64();
+            assert!(full <= bounded, "{} > {}", full, bounded);
+            assert!(full >= bounded * 0.5, "{} < {}", full, bounded * 0.5);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn permanent_errors_fail_at_once() {
+        let calls = Cell::new(0);
+        let error = retry(&policy(0.0), "u", Stage::FetchingInfo, || {
+            calls.set(calls.get() + 1);
+            async { Err::<(), _>(failure("Private video")) }
+        })
+        .await
+        .unwrap_err();
+
+        assert_eq!(calls.get(), 1);
+        assert_eq!(error.attempts(), 1);
+        assert_eq!(error.code(), "video_private");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retryable_errors_use_every_attempt() {
+        let calls = Cell::new(0);
+        let start = tokio::time::Instant::now();
+        let error = retry(&policy(0.0), "u", Stage::DownloadingAudio, || {
+            calls.set(calls.get() + 1);
+            async { Err::<(), _>(failure("HTTP Error 503")) }
+        })
+        .await
+        .unwrap_err();
+
+        assert_eq!(calls.get(), 4);
+        assert_eq!(error.attempts(), 4);
+        assert_eq!(error.kind(), ErrorKind::Retryable);
+        // Three delays between four attempts, all on the paused clock
+        assert_eq!(start.elapsed(), Duration::from_millis(100 + 200 + 400));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_returns_the_first_success() {
+        let calls = Cell::new(0);
+        let value = retry(&policy(0.0), "u", Stage::Merging, || {
+            calls.set(calls.get() + 1);
+            let attempt = calls.get();
+            async move {
+                if attempt < 3 {
+                    Err(failure("connection reset"))
+                } else {
+                    Ok(attempt)
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(value, 3);
+    }
+}
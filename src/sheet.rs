@@ -1,21 +1,314 @@
-use crate::error::Result;
-use serde::Deserialize;
+//! Google Sheets integration for URL sourcing.
+//!
+//! Provides functionality to fetch video URLs from published Google Sheets,
+//! handling authentication, parsing, and error recovery.
+
+use crate::downloader::{DownloadJob, JobEvent};
+use crate::error::{AppError, Result};
+use crate::formats::DownloadMode;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use tokio::sync::mpsc::UnboundedReceiver;
 use url::Url;
 
-#[derive(Debug, Deserialize)]
+/// Row statuses that exclude a row from downloading
+pub const SKIPPED_STATUSES: [&str; 3] = ["done", "skip", "skipped"];
+
+/// A data row of the sheet mapped through the configured column names
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SheetRow {
-    #[serde(default)]
+    /// Tab (`gid`) the row was read from
+    pub gid: String,
+    /// 1-based row number in the tab, the header being row 1
+    pub row: usize,
     pub url: String,
-    #[serde(default)]
     pub status: String,
-    #[serde(default, rename = "")]
-    pub _extra: Vec<String>,
+    pub title: Option<String>,
+    pub folder: Option<String>,
+    /// Download mode of the row, e.g. `audio`
+    pub mode: Option<String>,
+}
+
+impl SheetRow {
+    /// Returns true if the status marks the row as already handled
+    pub fn is_skipped(&self) -> bool {
+        SKIPPED_STATUSES
+            .iter()
+            .any(|status| self.status.trim().eq_ignore_ascii_case(status))
+    }
+
+    /// Converts the row into a download job carrying its title, folder and mode
+    ///
+    /// An unknown mode is reported and the configured one used instead.
+    pub fn to_job(&self) -> DownloadJob {
+        let mode = self
+            .mode
+            .as_deref()
+            .and_then(|mode| match mode.parse::<DownloadMode>() {
+                Ok(mode) => Some(mode),
+                Err(e) => {
+                    eprintln!("Warning: row {} of tab {}: {}", self.row, self.gid, e);
+                    None
+                }
+            });
+        DownloadJob {
+            url: self.url.clone(),
+            title: self.title.clone(),
+            folder: self.folder.as_ref().map(PathBuf::from),
+            mode,
+        }
+    }
+}
+
+/// Sheet settings, the `[sheet]` table of the configuration
+///
+/// Column settings are header names, matched case-insensitively. Only the
+/// URL column is required to exist in the sheet.
+///
+/// Tabs are identified by their `gid`, the number after `gid=` in the sheet
+/// URL. When `tabs` is empty, the tab of the sheet URL is read, or the
+/// first tab if the URL names none.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SheetConfig {
+    pub url_column: String,
+    pub status_column: String,
+    pub title_column: String,
+    pub folder_column: String,
+    /// Column holding a per-row download mode: `audio-video`, `audio` or `video`
+    pub mode_column: String,
+    /// Endpoint receiving row status updates, see `SheetWriter`
+    pub write_back_url: Option<String>,
+    /// Tabs to read, in order
+    pub tabs: Vec<String>,
+    /// Output folder of each tab, used for rows without a folder cell
+    pub tab_folders: BTreeMap<String, String>,
+    /// Seconds between two fetches in watch mode
+    pub poll_interval_secs: u64,
+}
+
+impl Default for SheetConfig {
+    fn default() -> Self {
+        Self {
+            url_column: String::from("url"),
+            status_column: String::from("status"),
+            title_column: String::from("title"),
+            folder_column: String::from("folder"),
+            mode_column: String::from("mode"),
+            write_back_url: None,
+            tabs: Vec::new(),
+            tab_folders: BTreeMap::new(),
+            poll_interval_secs: 60,
+        }
+    }
+}
+
+/// Positions of the configured columns in a CSV header
+struct ColumnMap {
+    url: usize,
+    status: Option<usize>,
+    title: Option<usize>,
+    folder: Option<usize>,
+    mode: Option<usize>,
+}
+
+impl ColumnMap {
+    fn from_headers(headers: &csv::StringRecord, config: &SheetConfig) -> Result<Self> {
+        let find = |name: &str| {
+            headers
+                .iter()
+                .position(|header| header.trim().eq_ignore_ascii_case(name.trim()))
+        };
+
+        let url = find(&config.url_column).ok_or_else(|| {
+            AppError::Sheet(format!(
+                "URL column `{}` not found in header: {}",
+                config.url_column,
+                headers.iter().collect::<Vec<_>>().join(", ")
+            ))
+        })?;
+
+        Ok(Self {
+            url,
+            status: find(&config.status_column),
+            title: find(&config.title_column),
+            folder: find(&config.folder_column),
+            mode: find(&config.mode_column),
+        })
+    }
+
+    fn row(&self, gid: &str, row: usize, record: &csv::StringRecord) -> SheetRow {
+        let cell = |index: Option<usize>| {
+            index
+                .and_then(|i| record.get(i))
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+
+        SheetRow {
+            gid: gid.to_string(),
+            row,
+            url: cell(Some(self.url)).unwrap_or_default(),
+            status: cell(self.status).unwrap_or_default(),
+            title: cell(self.title),
+            folder: cell(self.folder),
+            mode: cell(self.mode),
+        }
+    }
 }
 
-/// Google Sheets integration for URL sourcing.
+/// Parses the CSV export of a sheet into rows to download
+///
+/// # Arguments
+/// * `content` - CSV body, the first line being the header
+/// * `config` - Column names to read
+/// * `gid` - Tab the CSV was exported from
+///
+/// # Returns
+/// * `Result<Vec<SheetRow>>` - Rows with a valid URL whose status is not `done` or `skip`
+///
+/// # Errors
+/// * If the CSV is malformed or has no URL column
+pub fn parse_rows(content: &str, config: &SheetConfig, gid: &str) -> Result<Vec<SheetRow>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(content.as_bytes());
+    let headers = reader
+        .headers()
+        .map_err(|e| AppError::Sheet(format!("Failed to read sheet header: {}", e)))?
+        .clone();
+    let columns = ColumnMap::from_headers(&headers, config)?;
+
+    let mut rows = Vec::new();
+    for (index, record) in reader.records().enumerate() {
+        let record =
+            record.map_err(|e| AppError::Sheet(format!("Failed to parse sheet row: {}", e)))?;
+        let row = columns.row(gid, index + 2, &record);
+
+        if row.url.is_empty() || row.is_skipped() {
+            continue;
+        }
+        if let Err(e) = Url::parse(&row.url) {
+            eprintln!(
+                "Warning: skipping row {} of tab {} with invalid URL `{}`: {}",
+                row.row, row.gid, row.url, e
+            );
+            continue;
+        }
+        rows.push(row);
+    }
+
+    Ok(rows)
+}
+
+/// A parsed Google Sheets URL
+///
+/// Accepts the shapes Google hands out:
+/// - `https://docs.google.com/spreadsheets/d/{id}/edit#gid={gid}`
+/// - `https://docs.google.com/spreadsheets/d/{id}/edit?gid={gid}`
+/// - `https://docs.google.com/spreadsheets/d/{id}/export?format=csv&gid={gid}`
+/// - `https://docs.google.com/spreadsheets/u/{n}/d/{id}/...`
+/// - `https://docs.google.com/spreadsheets/d/e/{published_id}/pub?gid={gid}&output=csv`
+///
+/// # Examples
 ///
-/// Provides functionality to fetch video URLs from published Google Sheets,
-/// handling authentication, parsing, and error recovery.
+/// ```
+/// use application::sheet::SheetRef;
+///
+/// let sheet = SheetRef::parse("https://docs.google.com/spreadsheets/d/abc123/edit#gid=42").unwrap();
+/// assert_eq!(sheet.id, "abc123");
+/// assert_eq!(sheet.gid.as_deref(), Some("42"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SheetRef {
+    /// Spreadsheet id, or the publication id of a published sheet
+    pub id: String,
+    /// Tab named by the URL, if any
+    pub gid: Option<String>,
+    /// Whether the URL is a "publish to the web" link (`/d/e/...`)
+    pub published: bool,
+}
+
+impl SheetRef {
+    /// Parses a Google Sheets URL
+    ///
+    /// # Errors
+    /// * `AppError::Sheet` if the URL is not a Google Sheets document URL
+    pub fn parse(sheet_url: &str) -> Result<Self> {
+        let malformed = |reason: &str| {
+            AppError::Sheet(format!("Invalid sheet URL `{}`: {}", sheet_url, reason))
+        };
+
+        let url = Url::parse(sheet_url).map_err(|e| malformed(&e.to_string()))?;
+        if url.host_str() != Some("docs.google.com") {
+            return Err(malformed("host is not docs.google.com"));
+        }
+
+        let segments: Vec<&str> = url
+            .path_segments()
+            .map(|segments| segments.filter(|s| !s.is_empty()).collect())
+            .unwrap_or_default();
+        if segments.first() != Some(&"spreadsheets") {
+            return Err(malformed("path does not start with /spreadsheets"));
+        }
+
+        let d = segments
+            .iter()
+            .position(|segment| *segment == "d")
+            .ok_or_else(|| malformed("no /d/ segment"))?;
+        let (published, id) = match segments.get(d + 1) {
+            Some(&"e") => (true, segments.get(d + 2)),
+            id => (false, id),
+        };
+        let id = id
+            .filter(|id| {
+                id.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            })
+            .ok_or_else(|| malformed("missing or invalid spreadsheet id"))?;
+
+        let fragment_gid = url
+            .fragment()
+            .and_then(|fragment| fragment.split('&').find_map(|p| p.strip_prefix("gid=")));
+        let query_gid = url
+            .query_pairs()
+            .find(|(key, _)| key == "gid")
+            .map(|(_, value)| value.into_owned());
+        let gid = fragment_gid.map(str::to_string).or(query_gid);
+        if let Some(gid) = &gid {
+            if gid.is_empty() || !gid.chars().all(|c| c.is_ascii_digit()) {
+                return Err(malformed("gid must be numeric"));
+            }
+        }
+
+        Ok(Self {
+            id: id.to_string(),
+            gid,
+            published,
+        })
+    }
+
+    /// Returns the CSV export URL of one tab
+    pub fn csv_url(&self, gid: &str) -> String {
+        if self.published {
+            format!(
+                "https://docs.google.com/spreadsheets/d/e/{}/pub?output=csv&gid={}",
+                self.id, gid
+            )
+        } else {
+            format!(
+                "https://docs.google.com/spreadsheets/d/{}/export?format=csv&gid={}",
+                self.id, gid
+            )
+        }
+    }
+}
 
 /// Client for interacting with Google Sheets.
 ///
@@ -37,63 +330,670 @@ pub struct SheetRow {
 /// ```
 pub struct SheetClient {
     client: reqwest::Client,
+    config: SheetConfig,
+}
+
+impl Default for SheetClient {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SheetClient {
     pub fn new() -> Self {
         Self {
             client: reqwest::Client::new(),
+            config: SheetConfig::default(),
         }
     }
 
+    /// Uses the given column names instead of the defaults
+    pub fn with_config(mut self, config: SheetConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Fetches the URLs of every row still to download
     pub async fn fetch_urls(&self, sheet_url: &str) -> Result<Vec<String>> {
-        let url = Url::parse(sheet_url)?;
-        let segments: Vec<&str> = url.path_segments().unwrap().collect();
-        let sheet_id = segments.get(2).ok_or("error")?;
-        let csv_url = format!(
-            "https://docs.google.com/spreadsheets/d/{}/export?format=csv&gid=0",
-            sheet_id
-        );
+        let rows = self.fetch_rows(sheet_url).await?;
+        Ok(rows.into_iter().map(|row| row.url).collect())
+    }
+
+    /// Fetches the sheet and parses it into rows still to download
+    ///
+    /// Reads every tab of `SheetConfig::tabs`, or the tab named by the URL.
+    /// Rows without a folder cell get the folder mapped to their tab. The
+    /// list is empty when every row is done or skipped.
+    ///
+    /// # Errors
+    /// * If the sheet URL is malformed or a tab cannot be fetched
+    /// * If a tab has no URL column
+    pub async fn fetch_rows(&self, sheet_url: &str) -> Result<Vec<SheetRow>> {
+        let mut cache = FetchCache::default();
+        let rows = self
+            .fetch_rows_cached(sheet_url, &mut cache)
+            .await?
+            .unwrap_or_default();
+
+        if rows.is_empty() {
+            // Every row being done or skipped is the normal state of a processed sheet
+            println!("No rows left to download in the sheet");
+            return Ok(rows);
+        }
+
+        println!("Successfully loaded {} URLs from sheet", rows.len());
+
+        // Print first few URLs for debugging
+        for row in rows.iter().take(3) {
+            println!("Row {}: {}", row.row, row.url);
+        }
 
+        Ok(rows)
+    }
+
+    /// Fetches the rows of the sheet unless no tab changed since the last call
+    ///
+    /// # Arguments
+    /// * `sheet_url` - Complete URL to the Google Sheet
+    /// * `cache` - Validators and rows of the previous call, updated in place
+    ///
+    /// # Returns
+    /// * `Result<Option<Vec<SheetRow>>>` - All rows still to download, or `None`
+    ///   when every tab was unchanged
+    ///
+    /// # Details
+    /// Each tab is requested with `If-None-Match` / `If-Modified-Since` from
+    /// its previous response. A tab answered with `304 Not Modified`, or with
+    /// a body identical to the previous one, reuses its cached rows.
+    pub async fn fetch_rows_cached(
+        &self,
+        sheet_url: &str,
+        cache: &mut FetchCache,
+    ) -> Result<Option<Vec<SheetRow>>> {
+        let sheet = SheetRef::parse(sheet_url)?;
+        let tabs = if self.config.tabs.is_empty() {
+            vec![sheet.gid.clone().unwrap_or_else(|| String::from("0"))]
+        } else {
+            self.config.tabs.clone()
+        };
+
+        let mut changed = false;
+        let mut rows = Vec::new();
+        for gid in &tabs {
+            let cached = cache.tabs.entry(gid.clone()).or_default();
+            if let Some(content) = self.fetch_csv(&sheet.csv_url(gid), cached).await? {
+                let mut tab_rows = parse_rows(&content, &self.config, gid)?;
+                if let Some(folder) = self.config.tab_folders.get(gid) {
+                    for row in tab_rows.iter_mut().filter(|row| row.folder.is_none()) {
+                        row.folder = Some(folder.clone());
+                    }
+                }
+                println!("Loaded {} URLs from tab {}", tab_rows.len(), gid);
+                cached.rows = tab_rows;
+                changed = true;
+            }
+            rows.extend(cached.rows.iter().cloned());
+        }
+
+        Ok(changed.then_some(rows))
+    }
+
+    /// Downloads the CSV export of one tab
+    ///
+    /// # Returns
+    /// * `Result<Option<String>>` - The CSV body, or `None` if it did not
+    ///   change since the response recorded in `cached`
+    async fn fetch_csv(&self, csv_url: &str, cached: &mut TabCache) -> Result<Option<String>> {
         println!("Fetching data from URL: {}", csv_url);
 
+        let mut request = self.client.get(csv_url);
+        if let Some(etag) = &cached.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &cached.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+
         // Fetch CSV data with error handling
+        let response = request.send().await.map_err(|e| AppError::SheetFetch {
+            url: csv_url.to_string(),
+            source: e,
+        })?;
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(AppError::SheetStatus {
+                url: csv_url.to_string(),
+                status: response.status().as_u16(),
+            });
+        }
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+
+        let content = response.text().await.map_err(|e| AppError::SheetFetch {
+            url: csv_url.to_string(),
+            source: e,
+        })?;
+
+        println!("Received content length: {} bytes", content.len());
+
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        cached.etag = etag;
+        cached.last_modified = last_modified;
+        if cached.hash == Some(hash) {
+            return Ok(None);
+        }
+        cached.hash = Some(hash);
+        Ok(Some(content))
+    }
+}
+
+/// Responses of previous fetches, used to skip unchanged tabs
+#[derive(Debug, Default)]
+pub struct FetchCache {
+    tabs: HashMap<String, TabCache>,
+}
+
+/// Validators and parsed rows of one tab's last response
+#[derive(Debug, Default)]
+struct TabCache {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    hash: Option<u64>,
+    rows: Vec<SheetRow>,
+}
+
+/// Download status of a sheet row
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum RowStatus {
+    Downloading,
+    Done { filename: String },
+    Skipped,
+    Failed { code: &'static str, error: String },
+}
+
+/// Body of a status update request
+#[derive(Serialize)]
+struct StatusUpdate<'a> {
+    sheet_id: &'a str,
+    gid: &'a str,
+    row: usize,
+    #[serde(flatten)]
+    status: &'a RowStatus,
+}
+
+/// Writes row statuses back to a sheet through a webhook
+///
+/// Each update is a `POST` of a JSON object to the configured endpoint,
+/// typically a Google Apps Script web app bound to the sheet:
+///
+/// ```json
+/// { "sheet_id": "1AbC...", "gid": "0", "row": 5, "status": "done", "filename": "1_id_Title.mp4" }
+/// { "sheet_id": "1AbC...", "gid": "0", "row": 6, "status": "failed", "error": "Download error: ..." }
+/// { "sheet_id": "1AbC...", "gid": "0", "row": 7, "status": "downloading" }
+/// ```
+///
+/// `row` is the 1-based row of the tab `gid`, the header being row 1. Any endpoint
+/// answering with a 2xx status works, which allows testing against a local server.
+pub struct SheetWriter {
+    client: reqwest::Client,
+    endpoint: Url,
+    sheet_id: String,
+}
+
+impl SheetWriter {
+    /// Creates a writer posting updates for the given sheet to `endpoint`
+    ///
+    /// # Errors
+    /// * If the endpoint is not a valid URL
+    /// * If the sheet URL has no spreadsheet id
+    pub fn new(endpoint: &str, sheet_url: &str) -> Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::new(),
+            endpoint: Url::parse(endpoint)?,
+            sheet_id: SheetRef::parse(sheet_url)?.id,
+        })
+    }
+
+    /// Sends the status of one row of the tab `gid`
+    ///
+    /// # Errors
+    /// * If the request fails or the endpoint answers with a non-2xx status
+    pub async fn update(&self, gid: &str, row: usize, status: &RowStatus) -> Result<()> {
+        let body = StatusUpdate {
+            sheet_id: &self.sheet_id,
+            gid,
+            row,
+            status,
+        };
         let response = self
             .client
-            .get(csv_url)
+            .post(self.endpoint.clone())
+            .json(&body)
             .send()
-            .await
-            .map_err(|e| format!("Failed to fetch sheet: {}", e))?;
+            .await?;
 
         if !response.status().is_success() {
-            return Err(format!("Failed to fetch sheet. Status: {}", response.status()).into());
+            return Err(AppError::SheetStatus {
+                url: self.endpoint.to_string(),
+                status: response.status().as_u16(),
+            });
         }
+        Ok(())
+    }
+
+    /// Writes back the status of each row as its download progresses
+    ///
+    /// # Arguments
+    /// * `rows` - Rows in the order their jobs were given to the downloader
+    /// * `events` - Events of that batch, consumed until the channel closes
+    ///
+    /// # Details
+    /// Failed updates are logged and do not affect the downloads.
+    pub async fn follow(&self, rows: &[SheetRow], mut events: UnboundedReceiver<JobEvent>) {
+        while let Some(event) = events.recv().await {
+            let (index, status) = match event {
+                JobEvent::Started { index } => (index, RowStatus::Downloading),
+                JobEvent::Completed { index, output } => {
+                    (index, RowStatus::Done { filename: output })
+                }
+                JobEvent::Skipped { index } => (index, RowStatus::Skipped),
+                JobEvent::Failed { index, code, error } => {
+                    (index, RowStatus::Failed { code, error })
+                }
+            };
+            let Some(row) = rows.get(index) else {
+                continue;
+            };
+            if let Err(e) = self.update(&row.gid, row.row, &status).await {
+                eprintln!(
+                    "Warning: could not write back status of row {}: {}",
+                    row.row, e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{self, Response};
+
+    const CSV: &str = "\
+URL,Status,Title,Folder,Mode
+https://youtu.be/a,,First,music,audio
+https://youtu.be/b,done,,,
+https://youtu.be/c, Skip ,,,
+https://youtu.be/d,SKIPPED,,,
+https://youtu.be/e,failed,,,
+not a url,,,,
+,,orphan title,,
+https://youtu.be/f
+";
 
-        let content = response
-            .text()
+    #[test]
+    fn parse_rows_filters_handled_and_invalid_rows() {
+        let rows = parse_rows(CSV, &SheetConfig::default(), "7").unwrap();
+
+        let found: Vec<(usize, &str)> =
+            rows.iter().map(|row| (row.row, row.url.as_str())).collect();
+        assert_eq!(
+            found,
+            [
+                (2, "https://youtu.be/a"),
+                (6, "https://youtu.be/e"),
+                (9, "https://youtu.be/f"),
+            ]
+        );
+        assert_eq!(
+            rows[0],
+            SheetRow {
+                gid: "7".to_string(),
+                row: 2,
+                url: "https://youtu.be/a".to_string(),
+                status: String::new(),
+                title: Some("First".to_string()),
+                folder: Some("music".to_string()),
+                mode: Some("audio".to_string()),
+            }
+        );
+        assert_eq!(rows[2].title, None);
+    }
+
+    #[test]
+    fn is_skipped_matches_statuses_case_insensitively() {
+        let cases = [
+            ("", false),
+            ("done", true),
+            ("DONE", true),
+            (" skip ", true),
+            ("Skipped", true),
+            ("failed", false),
+            ("downloading", false),
+            ("done!", false),
+        ];
+        for (status, skipped) in cases {
+            let row = SheetRow {
+                gid: "0".to_string(),
+                row: 2,
+                url: "https://youtu.be/a".to_string(),
+                status: status.to_string(),
+                title: None,
+                folder: None,
+                mode: None,
+            };
+            assert_eq!(row.is_skipped(), skipped, "status `{}`", status);
+        }
+    }
+
+    #[test]
+    fn parse_rows_uses_configured_columns() {
+        let config = SheetConfig {
+            url_column: "Link".to_string(),
+            status_column: "State".to_string(),
+            ..SheetConfig::default()
+        };
+        let content = "state,LINK\ndone,https://youtu.be/a\n,https://youtu.be/b\n";
+        let rows = parse_rows(content, &config, "0").unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].url, "https://youtu.be/b");
+        assert_eq!(rows[0].row, 3);
+
+        let error = parse_rows("status,title\n,x\n", &SheetConfig::default(), "0").unwrap_err();
+        assert!(error.to_string().contains("URL column"), "{}", error);
+    }
+
+    #[test]
+    fn parse_rows_of_a_processed_sheet_is_empty() {
+        let content = "url,status\nhttps://youtu.be/a,done\nhttps://youtu.be/b,skip\n";
+        assert!(parse_rows(content, &SheetConfig::default(), "0")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn to_job_ignores_unknown_modes() {
+        let mut rows = parse_rows(CSV, &SheetConfig::default(), "0").unwrap();
+        let job = rows[0].to_job();
+        assert_eq!(job.mode, Some(DownloadMode::Audio));
+        assert_eq!(job.folder, Some(PathBuf::from("music")));
+        assert_eq!(job.title.as_deref(), Some("First"));
+
+        rows[0].mode = Some("hologram".to_string());
+        assert_eq!(rows[0].to_job().mode, None);
+    }
+
+    #[test]
+    fn status_updates_serialize_flat() {
+        let cases = [
+            (
+                RowStatus::Downloading,
+                r#"{"sheet_id":"abc","gid":"0","row":5,"status":"downloading"}"#,
+            ),
+            (
+                RowStatus::Done {
+                    filename: "1_a_Title.mp4".to_string(),
+                },
+                r#"{"sheet_id":"abc","gid":"0","row":5,"status":"done","filename":"1_a_Title.mp4"}"#,
+            ),
+            (
+                RowStatus::Skipped,
+                r#"{"sheet_id":"abc","gid":"0","row":5,"status":"skipped"}"#,
+            ),
+            (
+                RowStatus::Failed {
+                    code: "video_private",
+                    error: "boom".to_string(),
+                },
+                r#"{"sheet_id":"abc","gid":"0","row":5,"status":"failed","code":"video_private","error":"boom"}"#,
+            ),
+        ];
+        for (status, expected) in cases {
+            let update = StatusUpdate {
+                sheet_id: "abc",
+                gid: "0",
+                row: 5,
+                status: &status,
+            };
+            assert_eq!(serde_json::to_string(&update).unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn writer_posts_updates_to_the_endpoint() {
+        let (url, mut requests) =
+            mock_server::serve(vec![Response::new(200, ""), Response::new(204, "")]).await;
+        let writer = SheetWriter::new(
+            &format!("{}hook", url),
+            "https://docs.google.com/spreadsheets/d/abc/edit",
+        )
+        .unwrap();
+
+        writer
+            .update("7", 3, &RowStatus::Downloading)
             .await
-            .map_err(|e| format!("Failed to read response content: {}", e))?;
+            .unwrap();
+        let failed = RowStatus::Failed {
+            code: "video_private",
+            error: "Private video".to_string(),
+        };
+        writer.update("7", 4, &failed).await.unwrap();
 
-        println!("Received content length: {} bytes", content.len());
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.line, "POST /hook HTTP/1.1");
+        assert_eq!(request.headers["content-type"], "application/json");
+        assert_eq!(
+            request.body,
+            r#"{"sheet_id":"abc","gid":"7","row":3,"status":"downloading"}"#
+        );
+        let request = requests.recv().await.unwrap();
+        assert_eq!(
+            request.body,
+            r#"{"sheet_id":"abc","gid":"7","row":4,"status":"failed","code":"video_private","error":"Private video"}"#
+        );
+    }
 
-        // Simple parsing: split by lines and take non-empty URLs
-        let urls: Vec<String> = content
-            .lines()
-            .filter(|line| !line.trim().is_empty())
-            .map(|line| line.trim().to_string())
-            .collect();
+    #[tokio::test]
+    async fn writer_fails_on_non_2xx_responses() {
+        let (url, _requests) = mock_server::serve(vec![
+            Response::new(403, "forbidden"),
+            Response::new(503, ""),
+        ])
+        .await;
+        let writer =
+            SheetWriter::new(&url, "https://docs.google.com/spreadsheets/d/abc/edit").unwrap();
 
-        if urls.is_empty() {
-            return Err("No valid URLs found in the sheet".into());
+        for (status, code) in [(403, "http"), (503, "server_error")] {
+            let error = writer
+                .update("0", 2, &RowStatus::Skipped)
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(&error, AppError::SheetStatus { status: s, url: u } if *s == status && *u == url),
+                "{}",
+                error
+            );
+            assert_eq!(error.code(), code);
         }
+    }
 
-        println!("Successfully loaded {} URLs from sheet", urls.len());
+    #[tokio::test]
+    async fn fetch_csv_sends_validators_and_skips_unchanged_tabs() {
+        let csv = "URL\nhttps://youtu.be/a\n";
+        let (url, mut requests) = mock_server::serve(vec![
+            Response::new(200, csv)
+                .header("etag", "\"v1\"")
+                .header("last-modified", "Wed, 21 Oct 2026 07:28:00 GMT"),
+            Response::new(304, ""),
+            Response::new(200, csv).header("etag", "\"v2\""),
+            Response::new(200, "URL\nhttps://youtu.be/b\n"),
+        ])
+        .await;
+        let client = SheetClient::new();
+        let mut cached = TabCache::default();
 
-        // Print first few URLs for debugging
-        for (i, url) in urls.iter().take(3).enumerate() {
-            println!("URL {}: {}", i + 1, url);
+        let first = client.fetch_csv(&url, &mut cached).await.unwrap();
+        assert_eq!(first.as_deref(), Some(csv));
+        let request = requests.recv().await.unwrap();
+        assert!(!request.headers.contains_key("if-none-match"));
+
+        // Not modified
+        assert_eq!(client.fetch_csv(&url, &mut cached).await.unwrap(), None);
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.headers["if-none-match"], "\"v1\"");
+        assert_eq!(
+            request.headers["if-modified-since"],
+            "Wed, 21 Oct 2026 07:28:00 GMT"
+        );
+
+        // A new ETag with the same body is unchanged too
+        assert_eq!(client.fetch_csv(&url, &mut cached).await.unwrap(), None);
+        requests.recv().await.unwrap();
+        assert_eq!(cached.etag.as_deref(), Some("\"v2\""));
+        assert_eq!(cached.last_modified, None);
+
+        let changed = client.fetch_csv(&url, &mut cached).await.unwrap();
+        assert_eq!(changed.as_deref(), Some("URL\nhttps://youtu.be/b\n"));
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.headers["if-none-match"], "\"v2\"");
+    }
+
+    #[tokio::test]
+    async fn fetch_csv_fails_on_error_status() {
+        let (url, _requests) = mock_server::serve(vec![Response::new(404, "")]).await;
+        let error = SheetClient::new()
+            .fetch_csv(&url, &mut TabCache::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, AppError::SheetStatus { status: 404, .. }));
+    }
+
+    #[test]
+    fn writer_requires_valid_urls() {
+        let sheet = "https://docs.google.com/spreadsheets/d/abc/edit";
+        assert!(SheetWriter::new("https://script.google.com/macros/s/x/exec", sheet).is_ok());
+        assert!(SheetWriter::new("not a url", sheet).is_err());
+        assert!(SheetWriter::new("http://localhost:8080", "https://example.com/d/abc").is_err());
+    }
+
+    #[test]
+    fn sheet_ref_parses_every_url_shape() {
+        let cases = [
+            (
+                "https://docs.google.com/spreadsheets/d/abc-1_Z",
+                "abc-1_Z",
+                None,
+                false,
+            ),
+            (
+                "https://docs.google.com/spreadsheets/d/abc/edit",
+                "abc",
+                None,
+                false,
+            ),
+            (
+                "https://docs.google.com/spreadsheets/d/abc/edit#gid=42",
+                "abc",
+                Some("42"),
+                false,
+            ),
+            (
+                "https://docs.google.com/spreadsheets/d/abc/edit?usp=sharing#gid=0",
+                "abc",
+                Some("0"),
+                false,
+            ),
+            (
+                "https://docs.google.com/spreadsheets/d/abc/edit?gid=9",
+                "abc",
+                Some("9"),
+                false,
+            ),
+            (
+                "https://docs.google.com/spreadsheets/d/abc/export?format=csv&gid=12",
+                "abc",
+                Some("12"),
+                false,
+            ),
+            (
+                "https://docs.google.com/spreadsheets/u/1/d/abc/edit#gid=3",
+                "abc",
+                Some("3"),
+                false,
+            ),
+            (
+                "https://docs.google.com/spreadsheets/d/e/2PACX-pub/pub?gid=5&output=csv",
+                "2PACX-pub",
+                Some("5"),
+                true,
+            ),
+            (
+                "https://docs.google.com/spreadsheets/d/e/2PACX-pub/pubhtml",
+                "2PACX-pub",
+                None,
+                true,
+            ),
+        ];
+        for (url, id, gid, published) in cases {
+            let sheet = SheetRef::parse(url).unwrap_or_else(|e| panic!("{}: {}", url, e));
+            assert_eq!(
+                sheet,
+                SheetRef {
+                    id: id.to_string(),
+                    gid: gid.map(str::to_string),
+                    published,
+                },
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn sheet_ref_rejects_other_urls() {
+        let cases = [
+            "not a url",
+            "https://example.com/spreadsheets/d/abc",
+            "https://docs.google.com/document/d/abc/edit",
+            "https://docs.google.com/spreadsheets/",
+            "https://docs.google.com/spreadsheets/d/",
+            "https://docs.google.com/spreadsheets/d/e/",
+            "https://docs.google.com/spreadsheets/d/a%20b/edit",
+            "https://docs.google.com/spreadsheets/d/abc/edit#gid=",
+            "https://docs.google.com/spreadsheets/d/abc/edit#gid=x1",
+        ];
+        for url in cases {
+            assert!(SheetRef::parse(url).is_err(), "{}", url);
         }
+    }
 
-        Ok(urls)
+    #[test]
+    fn csv_url_depends_on_publication() {
+        let sheet = SheetRef::parse("https://docs.google.com/spreadsheets/d/abc/edit").unwrap();
+        assert_eq!(
+            sheet.csv_url("4"),
+            "https://docs.google.com/spreadsheets/d/abc/export?format=csv&gid=4"
+        );
+        let published =
+            SheetRef::parse("https://docs.google.com/spreadsheets/d/e/xyz/pubhtml").unwrap();
+        assert_eq!(
+            published.csv_url("0"),
+            "https://docs.google.com/spreadsheets/d/e/xyz/pub?output=csv&gid=0"
+        );
     }
 }
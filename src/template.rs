@@ -0,0 +1,506 @@
+//! Output filename templates.
+//!
+//! Output names are rendered from a template like `{index}_{id}_{title}.{ext}`.
+//! Field values are sanitized so they are valid file names on every
+//! platform and cannot add directories; `/` in the template itself creates
+//! subdirectories of the output directory. Names are truncated to a safe
+//! length, on UTF-8 boundaries, and numbered when they are already taken.
+
+use crate::error::{AppError, Result};
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Template reproducing the historical `{index}_{id}_{title}.mp4` names
+pub const DEFAULT_TEMPLATE: &str = "{index}_{id}_{title}.{ext}";
+
+/// Longest file or directory name written, in bytes
+///
+/// Leaves room below the 255 bytes most filesystems allow for the
+/// collision number and temporary suffixes.
+pub const MAX_NAME_BYTES: usize = 200;
+
+/// Value written for fields a video does not have, like yt-dlp
+const MISSING: &str = "NA";
+
+/// Fields a template can use
+const FIELDS: [&str; 11] = [
+    "index",
+    "playlist_index",
+    "id",
+    "title",
+    "uploader",
+    "channel",
+    "upload_date",
+    "extractor",
+    "resolution",
+    "format_id",
+    "ext",
+];
+
+/// Names that Windows reserves for devices, whatever their extension
+const RESERVED_NAMES: [&str; 22] = [
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// Values of the template fields for one job
+///
+/// Fields left `None` are written as `NA`.
+#[derive(Debug, Clone, Default)]
+pub struct TemplateFields {
+    /// Position of the job in its batch, starting at 1
+    pub index: usize,
+    pub id: String,
+    pub title: String,
+    pub uploader: Option<String>,
+    pub channel: Option<String>,
+    /// Upload date as `YYYYMMDD`
+    pub upload_date: Option<String>,
+    pub extractor: String,
+    /// Height of the video, e.g. `1080p`, or `audio` for audio files
+    pub resolution: String,
+    /// Selected format ids, e.g. `137+251`
+    pub format_id: String,
+    /// Extension of the output file, without the dot
+    pub ext: String,
+}
+
+impl TemplateFields {
+    fn get(&self, field: &str) -> String {
+        let value = match field {
+            "index" | "playlist_index" => Some(self.index.to_string()),
+            "id" => Some(self.id.clone()),
+            "title" => Some(self.title.clone()),
+            "uploader" => self.uploader.clone(),
+            "channel" => self.channel.clone(),
+            "upload_date" => self.upload_date.clone(),
+            "extractor" => Some(self.extractor.clone()),
+            "resolution" => Some(self.resolution.clone()),
+            "format_id" => Some(self.format_id.clone()),
+            "ext" => Some(self.ext.clone()),
+            _ => None,
+        };
+        value
+            .filter(|value| !value.is_empty())
+            .unwrap_or_else(|| MISSING.to_string())
+    }
+}
+
+/// A parsed output template
+///
+/// # Examples
+///
+/// ```
+/// use application::template::{OutputTemplate, TemplateFields};
+/// use std::path::Path;
+///
+/// let template = OutputTemplate::parse("{uploader}/{title} [{id}].{ext}").unwrap();
+/// let fields = TemplateFields {
+///     id: "dQw4w9WgXcQ".to_string(),
+///     title: "Never: Gonna / Give".to_string(),
+///     uploader: Some("Rick".to_string()),
+///     ext: "mp4".to_string(),
+///     ..TemplateFields::default()
+/// };
+/// assert_eq!(
+///     template.render(&fields).unwrap(),
+///     Path::new("Rick/Never_ Gonna _ Give [dQw4w9WgXcQ].mp4")
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct OutputTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Field(String),
+}
+
+impl OutputTemplate {
+    /// Parses a template
+    ///
+    /// # Errors
+    /// * If a field is unknown or a brace is not closed
+    /// * If the template does not end with `.{ext}`
+    /// * If it is an absolute path or contains `.` or `..` directories
+    pub fn parse(template: &str) -> Result<Self> {
+        let invalid = |message: String| {
+            AppError::Config(format!("output_template `{}`: {}", template, message))
+        };
+
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| invalid("unclosed `{`".to_string()))?;
+            let field = &rest[start + 1..start + end];
+            if !FIELDS.contains(&field) {
+                return Err(invalid(format!(
+                    "unknown field `{}`, expected one of {}",
+                    field,
+                    FIELDS.join(", ")
+                )));
+            }
+            parts.push(Part::Field(field.to_string()));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        if !template.ends_with(".{ext}") {
+            return Err(invalid("must end with `.{ext}`".to_string()));
+        }
+        let literal: String = parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(text) => text.as_str(),
+                Part::Field(_) => "x",
+            })
+            .collect();
+        if literal.starts_with(['/', '\\'])
+            || literal
+                .split(['/', '\\'])
+                .any(|component| component == "." || component == "..")
+        {
+            return Err(invalid("must stay inside the output directory".to_string()));
+        }
+
+        Ok(Self { parts })
+    }
+
+    /// Renders the path of an output file, relative to the output directory
+    ///
+    /// # Details
+    /// Every directory and file name is sanitized and truncated to
+    /// `MAX_NAME_BYTES`; the extension of the file name is kept whole.
+    ///
+    /// # Errors
+    /// * `AppError::InvalidFolder` if the result would leave the output directory
+    pub fn render(&self, fields: &TemplateFields) -> Result<PathBuf> {
+        let rendered: String = self
+            .parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(text) => text.clone(),
+                // Values cannot add directories
+                Part::Field(field) => fields.get(field).replace(['/', '\\'], "_"),
+            })
+            .collect();
+
+        let components: Vec<&str> = rendered
+            .split(['/', '\\'])
+            .filter(|component| !component.is_empty())
+            .collect();
+        let mut path = PathBuf::new();
+        for (i, component) in components.iter().enumerate() {
+            let name = if i + 1 == components.len() {
+                let stem = component
+                    .strip_suffix(&format!(".{}", sanitize(&fields.ext)))
+                    .unwrap_or(component);
+                file_name(stem, &sanitize(&fields.ext), MAX_NAME_BYTES)
+            } else {
+                truncate(&sanitize(component), MAX_NAME_BYTES).to_string()
+            };
+            path.push(name);
+        }
+
+        if path.as_os_str().is_empty()
+            || !path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(AppError::InvalidFolder { folder: path });
+        }
+        Ok(path)
+    }
+}
+
+/// Makes a string a valid file name on Windows, macOS and Linux
+///
+/// Replaces path separators, characters Windows forbids and control
+/// characters with `_`, removes leading dots and trailing dots and spaces,
+/// and suffixes names Windows reserves for devices.
+///
+/// # Examples
+///
+/// ```
+/// use application::template::sanitize;
+///
+/// assert_eq!(sanitize("AC/DC: Live?"), "AC_DC_ Live_");
+/// assert_eq!(sanitize("..hidden. "), "hidden");
+/// assert_eq!(sanitize("con"), "con_");
+/// assert_eq!(sanitize(""), "_");
+/// ```
+pub fn sanitize(name: &str) -> String {
+    let replaced: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let trimmed = replaced
+        .trim_start_matches(['.', ' '])
+        .trim_end_matches(['.', ' ']);
+
+    let stem = trimmed.split('.').next().unwrap_or_default();
+    if trimmed.is_empty() {
+        "_".to_string()
+    } else if RESERVED_NAMES.contains(&stem.to_lowercase().as_str()) {
+        format!("{}_", trimmed)
+    } else {
+        trimmed.to_string()
+    }
+}
+
+/// Cuts a string to at most `max_bytes` bytes without splitting a character
+///
+/// # Examples
+///
+/// ```
+/// use application::template::truncate;
+///
+/// assert_eq!(truncate("héllo", 2), "h");
+/// assert_eq!(truncate("héllo", 3), "hé");
+/// ```
+pub fn truncate(text: &str, max_bytes: usize) -> &str {
+    if text.len() <= max_bytes {
+        return text;
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Builds `stem.ext`, truncating the sanitized stem so the name fits in `max_bytes`
+fn file_name(stem: &str, ext: &str, max_bytes: usize) -> String {
+    let stem = sanitize(stem);
+    let room = max_bytes.saturating_sub(ext.len() + 1).max(1);
+    let stem = truncate(&stem, room).trim_end_matches(['.', ' ']);
+    let stem = if stem.is_empty() { "_" } else { stem };
+    format!("{}.{}", stem, ext)
+}
+
+/// Output names in use by running jobs, shared by every batch
+///
+/// Two jobs rendering the same name, or a name already on disk, get
+/// numbered names: `title (1).mp4`, `title (2).mp4`...
+#[derive(Debug, Default)]
+pub struct OutputNames {
+    taken: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+/// An output name reserved until dropped
+#[derive(Debug)]
+pub struct ReservedName {
+    path: PathBuf,
+    taken: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl OutputNames {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserves a name that no running job uses and no file has
+    ///
+    /// # Arguments
+    /// * `output_dir` - Directory the name is relative to
+    /// * `name` - Rendered name, numbered if taken
+    pub fn reserve(&self, output_dir: &Path, name: &Path) -> ReservedName {
+        let mut taken = self.taken.lock().unwrap_or_else(|e| e.into_inner());
+        let stem = name
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let ext = name
+            .extension()
+            .map(|ext| ext.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let mut candidate = name.to_path_buf();
+        let mut number = 0;
+        while taken.contains(&candidate) || output_dir.join(&candidate).exists() {
+            number += 1;
+            let suffix = format!(" ({})", number);
+            let room = MAX_NAME_BYTES.saturating_sub(suffix.len() + ext.len() + 1);
+            let numbered = file_name(
+                &format!("{}{}", truncate(&stem, room), suffix),
+                &ext,
+                usize::MAX,
+            );
+            candidate = name.with_file_name(numbered);
+        }
+        taken.insert(candidate.clone());
+
+        ReservedName {
+            path: candidate,
+            taken: Arc::clone(&self.taken),
+        }
+    }
+}
+
+impl ReservedName {
+    /// Returns the reserved path, relative to the output directory
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ReservedName {
+    fn drop(&mut self) {
+        self.taken
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(title: &str) -> TemplateFields {
+        TemplateFields {
+            index: 3,
+            id: "abc".to_string(),
+            title: title.to_string(),
+            ext: "mp4".to_string(),
+            ..TemplateFields::default()
+        }
+    }
+
+    #[test]
+    fn sanitize_makes_portable_names() {
+        let cases = [
+            ("plain name", "plain name"),
+            ("a/b\\c", "a_b_c"),
+            ("what? <now> | \"here\" *", "what_ _now_ _ _here_ _"),
+            ("tab\there", "tab_here"),
+            ("...dots...", "dots"),
+            ("  spaced  ", "spaced"),
+            ("NUL.txt", "NUL.txt_"),
+            ("Com1", "Com1_"),
+            ("console", "console"),
+            ("...", "_"),
+            ("日本語", "日本語"),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(sanitize(name), expected, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn truncate_keeps_whole_characters() {
+        let cases = [
+            ("short", 10, "short"),
+            ("exact", 5, "exact"),
+            ("héllo", 2, "h"),
+            ("héllo", 3, "hé"),
+            ("日本", 5, "日"),
+            ("日本", 2, ""),
+        ];
+        for (text, max, expected) in cases {
+            assert_eq!(truncate(text, max), expected, "{:?} {}", text, max);
+        }
+    }
+
+    #[test]
+    fn file_name_keeps_the_extension() {
+        let cases = [
+            ("title", "mp4", 20, "title.mp4"),
+            ("a long title here", "mp4", 10, "a long.mp4"),
+            ("title. . .", "mp4", 20, "title.mp4"),
+            ("", "mp4", 20, "_.mp4"),
+            ("title", "mp4", 3, "t.mp4"),
+        ];
+        for (stem, ext, max, expected) in cases {
+            assert_eq!(file_name(stem, ext, max), expected, "{:?} {}", stem, max);
+        }
+    }
+
+    #[test]
+    fn parse_rejects_invalid_templates() {
+        let cases = [
+            ("{title}.mp4", "must end with"),
+            ("{title", "unclosed"),
+            ("{name}.{ext}", "unknown field `name`"),
+            ("/abs/{title}.{ext}", "inside the output directory"),
+            ("../{title}.{ext}", "inside the output directory"),
+            ("a/./{title}.{ext}", "inside the output directory"),
+        ];
+        for (template, message) in cases {
+            let error = OutputTemplate::parse(template).unwrap_err().to_string();
+            assert!(error.contains(message), "{}: {}", template, error);
+        }
+    }
+
+    #[test]
+    fn render_sanitizes_each_component() {
+        let long = "x".repeat(300);
+        let cases = [
+            (DEFAULT_TEMPLATE, "Title", "3_abc_Title.mp4".to_string()),
+            (
+                "{uploader}/{title}.{ext}",
+                "Title",
+                "NA/Title.mp4".to_string(),
+            ),
+            (
+                "{title}.{ext}",
+                "AC/DC: Live",
+                "AC_DC_ Live.mp4".to_string(),
+            ),
+            ("{title}.{ext}", "..", "_.mp4".to_string()),
+            (
+                "{title}.{ext}",
+                long.as_str(),
+                format!("{}.mp4", "x".repeat(MAX_NAME_BYTES - 4)),
+            ),
+        ];
+        for (template, title, expected) in cases {
+            let template = OutputTemplate::parse(template).unwrap();
+            let path = template.render(&fields(title)).unwrap();
+            assert_eq!(path, Path::new(&expected), "{:?}", title);
+        }
+    }
+
+    #[test]
+    fn reserve_numbers_taken_names() {
+        let dir = std::env::temp_dir().join(format!("ytb-template-reserve-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("on disk.mp4"), b"").unwrap();
+        let names = OutputNames::new();
+
+        let first = names.reserve(&dir, Path::new("video.mp4"));
+        let second = names.reserve(&dir, Path::new("video.mp4"));
+        let third = names.reserve(&dir, Path::new("video.mp4"));
+        let existing = names.reserve(&dir, Path::new("on disk.mp4"));
+        assert_eq!(first.path(), Path::new("video.mp4"));
+        assert_eq!(second.path(), Path::new("video (1).mp4"));
+        assert_eq!(third.path(), Path::new("video (2).mp4"));
+        assert_eq!(existing.path(), Path::new("on disk (1).mp4"));
+
+        drop(second);
+        let again = names.reserve(&dir, Path::new("video.mp4"));
+        assert_eq!(again.path(), Path::new("video (1).mp4"));
+
+        let long = format!("{}.mp4", "x".repeat(MAX_NAME_BYTES - 4));
+        let _long = names.reserve(&dir, Path::new(&long));
+        let numbered = names.reserve(&dir, Path::new(&long));
+        assert!(numbered.path().to_string_lossy().len() <= MAX_NAME_BYTES);
+        assert!(numbered.path().to_string_lossy().ends_with(" (1).mp4"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
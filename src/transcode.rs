@@ -0,0 +1,558 @@
+//! Conversion of downloaded streams with the managed ffmpeg.
+//!
+//! Merging streams into the chosen container, extracting and re-encoding
+//! audio run the ffmpeg binary of the libraries directory directly. Streams
+//! the container can hold are copied as they are; only the others are
+//! re-encoded.
+
+use crate::error::{AppError, Result};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::process::Command;
+
+/// Lines of ffmpeg's error output kept in `AppError::Ffmpeg`
+const STDERR_LINES: usize = 5;
+
+/// Audio codec of the files written in audio-only mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioCodec {
+    Mp3,
+    M4a,
+    Opus,
+    Flac,
+}
+
+impl AudioCodec {
+    /// Returns the extension of the files holding this codec
+    pub fn extension(self) -> &'static str {
+        match self {
+            AudioCodec::Mp3 => "mp3",
+            AudioCodec::M4a => "m4a",
+            AudioCodec::Opus => "opus",
+            AudioCodec::Flac => "flac",
+        }
+    }
+
+    /// Returns the ffmpeg encoder of the codec
+    fn encoder(self) -> &'static str {
+        match self {
+            AudioCodec::Mp3 => "libmp3lame",
+            AudioCodec::M4a => "aac",
+            AudioCodec::Opus => "libopus",
+            AudioCodec::Flac => "flac",
+        }
+    }
+
+    /// Returns the ffmpeg muxer of the files holding this codec
+    fn muxer(self) -> &'static str {
+        match self {
+            AudioCodec::Mp3 => "mp3",
+            AudioCodec::M4a => "ipod",
+            AudioCodec::Opus => "opus",
+            AudioCodec::Flac => "flac",
+        }
+    }
+}
+
+impl fmt::Display for AudioCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+impl FromStr for AudioCodec {
+    type Err = String;
+
+    fn from_str(codec: &str) -> std::result::Result<Self, Self::Err> {
+        match codec.trim().to_lowercase().as_str() {
+            "mp3" => Ok(AudioCodec::Mp3),
+            "m4a" | "aac" => Ok(AudioCodec::M4a),
+            "opus" => Ok(AudioCodec::Opus),
+            "flac" => Ok(AudioCodec::Flac),
+            _ => Err(format!(
+                "unknown audio codec `{}`, expected mp3, m4a, opus or flac",
+                codec
+            )),
+        }
+    }
+}
+
+/// Audio-only settings, the `[audio]` table of the configuration
+///
+/// ```toml
+/// mode = "audio"
+///
+/// [audio]
+/// codec = "mp3"
+/// bitrate = "192k"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    /// Codec the audio is converted to, `None` keeps the downloaded stream as is
+    pub codec: Option<AudioCodec>,
+    /// Target bitrate of lossy codecs, e.g. `"192k"`; the encoder's default when unset
+    pub bitrate: Option<String>,
+}
+
+impl AudioConfig {
+    /// Checks that the bitrate is a number of bits or kilobits per second
+    ///
+    /// # Errors
+    /// * If `bitrate` is not like `192k` or `192000`
+    pub fn validate(&self) -> Result<()> {
+        if let Some(bitrate) = &self.bitrate {
+            let digits = bitrate.strip_suffix(['k', 'K']).unwrap_or(bitrate);
+            if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+                return Err(AppError::Config(format!(
+                    "audio.bitrate: invalid bitrate `{}`, expected e.g. 192k",
+                    bitrate
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Container of the files written in the audio-video and video-only modes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    #[default]
+    Mp4,
+    Mkv,
+    Webm,
+}
+
+impl Container {
+    /// Returns the extension of the files in this container
+    pub fn extension(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "mkv",
+            Container::Webm => "webm",
+        }
+    }
+
+    /// Returns true if a video stream of the codec can be copied into the container
+    fn holds_video(self, codec: &str) -> bool {
+        let prefixes: &[&str] = match self {
+            Container::Mp4 => &["avc1", "avc3", "hvc1", "hev1", "av01", "vp09", "vp9"],
+            Container::Mkv => return true,
+            Container::Webm => &["vp8", "vp9", "vp09", "av01"],
+        };
+        prefixes.iter().any(|prefix| codec.starts_with(prefix))
+    }
+
+    /// Returns true if an audio stream of the codec can be copied into the container
+    fn holds_audio(self, codec: &str) -> bool {
+        let prefixes: &[&str] = match self {
+            Container::Mp4 => &["mp4a", "mp3", "ac-3", "ec-3"],
+            Container::Mkv => return true,
+            Container::Webm => &["opus", "vorbis"],
+        };
+        prefixes.iter().any(|prefix| codec.starts_with(prefix))
+    }
+
+    /// Returns the ffmpeg muxer of the container
+    fn muxer(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "matroska",
+            Container::Webm => "webm",
+        }
+    }
+
+    fn video_encoder(self) -> &'static str {
+        match self {
+            Container::Mp4 | Container::Mkv => "libx264",
+            Container::Webm => "libvpx-vp9",
+        }
+    }
+
+    fn audio_encoder(self) -> &'static str {
+        match self {
+            Container::Mp4 | Container::Mkv => "aac",
+            Container::Webm => "libopus",
+        }
+    }
+}
+
+/// Video settings, the `[video]` table of the configuration
+///
+/// ```toml
+/// [video]
+/// container = "mkv"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoConfig {
+    /// Container the streams are merged or remuxed into
+    pub container: Container,
+}
+
+/// A downloaded file given to ffmpeg, with the codec of the stream taken from it
+#[derive(Debug, Clone, Copy)]
+pub struct Input<'a> {
+    pub path: &'a Path,
+    /// Codec as reported by yt-dlp, e.g. `avc1.640028`
+    pub codec: Option<&'a str>,
+}
+
+/// Runs the managed ffmpeg binary
+pub struct Ffmpeg {
+    path: PathBuf,
+}
+
+impl Ffmpeg {
+    /// Uses the ffmpeg binary of the libraries directory
+    pub fn new(libraries_dir: &Path) -> Self {
+        Self {
+            path: libraries_dir.join("ffmpeg"),
+        }
+    }
+
+    /// Writes the audio of a file in another codec
+    ///
+    /// # Arguments
+    /// * `input` - Audio stream, or a file holding audio and video
+    /// * `output` - File to write, replaced if it exists, whatever its extension
+    /// * `codec` - Codec of the output
+    /// * `bitrate` - Target bitrate, ignored for lossless codecs
+    ///
+    /// # Errors
+    /// * `AppError::Ffmpeg` if ffmpeg cannot be started or fails
+    pub async fn extract_audio(
+        &self,
+        input: &Path,
+        output: &Path,
+        codec: AudioCodec,
+        bitrate: Option<&str>,
+    ) -> Result<()> {
+        let mut command = Command::new(&self.path);
+        command.args(extract_audio_args(input, output, codec, bitrate));
+        self.run(command).await
+    }
+
+    /// Writes the video and audio of the inputs into one file of a container
+    ///
+    /// # Arguments
+    /// * `video` - File to take the first video stream from
+    /// * `audio` - File to take the first audio stream from, may be the video file
+    /// * `output` - File to write, replaced if it exists, whatever its extension
+    /// * `container` - Container of the output
+    ///
+    /// # Details
+    /// Each stream is copied when the container can hold its codec, and
+    /// re-encoded otherwise.
+    ///
+    /// # Errors
+    /// * `AppError::Ffmpeg` if ffmpeg cannot be started or fails
+    pub async fn mux(
+        &self,
+        video: Option<Input<'_>>,
+        audio: Option<Input<'_>>,
+        output: &Path,
+        container: Container,
+    ) -> Result<()> {
+        let mut command = Command::new(&self.path);
+        command.args(mux_args(video, audio, output, container));
+        self.run(command).await
+    }
+
+    async fn run(&self, mut command: Command) -> Result<()> {
+        let output = command.output().await.map_err(|e| AppError::Ffmpeg {
+            message: format!("cannot run {}: {}", self.path.display(), e),
+        })?;
+        if output.status.success() {
+            return Ok(());
+        }
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let lines: Vec<&str> = stderr.lines().collect();
+        let tail = lines[lines.len().saturating_sub(STDERR_LINES)..].join("\n");
+        Err(AppError::Ffmpeg {
+            message: format!("{}: {}", output.status, tail),
+        })
+    }
+}
+
+/// Returns the ffmpeg arguments of `Ffmpeg::extract_audio`
+fn extract_audio_args(
+    input: &Path,
+    output: &Path,
+    codec: AudioCodec,
+    bitrate: Option<&str>,
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec!["-y".into(), "-i".into(), input.into()];
+    args.extend(["-vn", "-c:a", codec.encoder()].map(OsString::from));
+    if let Some(bitrate) = bitrate.filter(|_| codec != AudioCodec::Flac) {
+        args.extend(["-b:a", bitrate].map(OsString::from));
+    }
+    args.extend(["-f", codec.muxer()].map(OsString::from));
+    args.push(output.into());
+    args
+}
+
+/// Returns the ffmpeg arguments of `Ffmpeg::mux`
+fn mux_args(
+    video: Option<Input<'_>>,
+    audio: Option<Input<'_>>,
+    output: &Path,
+    container: Container,
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec!["-y".into()];
+    for input in video.iter().chain(audio.iter()) {
+        args.extend(["-i".into(), input.path.into()]);
+    }
+    if let Some(video) = video {
+        let copy = video
+            .codec
+            .is_some_and(|codec| container.holds_video(codec));
+        let encoder = if copy {
+            "copy"
+        } else {
+            container.video_encoder()
+        };
+        args.extend(["-map", "0:v:0", "-c:v", encoder].map(OsString::from));
+    }
+    if let Some(audio) = audio {
+        let copy = audio
+            .codec
+            .is_some_and(|codec| container.holds_audio(codec));
+        let encoder = if copy {
+            "copy"
+        } else {
+            container.audio_encoder()
+        };
+        let index = usize::from(video.is_some());
+        let map = format!("{}:a:0", index);
+        args.extend(["-map", &map, "-c:a", encoder].map(OsString::from));
+    }
+    args.extend(["-f", container.muxer()].map(OsString::from));
+    args.push(output.into());
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: Vec<OsString>) -> Vec<String> {
+        args.into_iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn input<'a>(path: &'a str, codec: &'a str) -> Option<Input<'a>> {
+        Some(Input {
+            path: Path::new(path),
+            codec: Some(codec),
+        })
+    }
+
+    #[test]
+    fn containers_hold_their_codecs() {
+        let cases = [
+            (Container::Mp4, "avc1.640028", true, false),
+            (Container::Mp4, "vp09.00.40.08", true, false),
+            (Container::Mp4, "mp4a.40.2", false, true),
+            (Container::Mp4, "opus", false, false),
+            (Container::Webm, "vp9", true, false),
+            (Container::Webm, "av01.0.08M.08", true, false),
+            (Container::Webm, "avc1.640028", false, false),
+            (Container::Webm, "opus", false, true),
+            (Container::Webm, "mp4a.40.2", false, false),
+            (Container::Mkv, "avc1.640028", true, true),
+            (Container::Mkv, "anything", true, true),
+        ];
+        for (container, codec, video, audio) in cases {
+            assert_eq!(
+                (container.holds_video(codec), container.holds_audio(codec)),
+                (video, audio),
+                "{:?} {}",
+                container,
+                codec
+            );
+        }
+    }
+
+    #[test]
+    fn mux_copies_streams_the_container_holds() {
+        let cases = [
+            (Container::Mp4, "avc1.640028", "mp4a.40.2", "copy", "copy"),
+            (Container::Mp4, "vp9", "opus", "copy", "aac"),
+            (Container::Webm, "vp9", "opus", "copy", "copy"),
+            (
+                Container::Webm,
+                "avc1.640028",
+                "mp4a.40.2",
+                "libvpx-vp9",
+                "libopus",
+            ),
+            (Container::Mkv, "avc1.640028", "opus", "copy", "copy"),
+        ];
+        for (container, video, audio, video_codec, audio_codec) in cases {
+            let args = mux_args(
+                input("v.part", video),
+                input("a.part", audio),
+                Path::new("out.part"),
+                container,
+            );
+            assert_eq!(
+                strings(args),
+                [
+                    "-y",
+                    "-i",
+                    "v.part",
+                    "-i",
+                    "a.part",
+                    "-map",
+                    "0:v:0",
+                    "-c:v",
+                    video_codec,
+                    "-map",
+                    "1:a:0",
+                    "-c:a",
+                    audio_codec,
+                    "-f",
+                    container.muxer(),
+                    "out.part"
+                ],
+                "{:?} {} {}",
+                container,
+                video,
+                audio
+            );
+        }
+    }
+
+    #[test]
+    fn mux_maps_audio_of_a_single_input() {
+        let args = mux_args(
+            None,
+            input("a.part", "opus"),
+            Path::new("out.part"),
+            Container::Webm,
+        );
+        assert_eq!(
+            strings(args),
+            ["-y", "-i", "a.part", "-map", "0:a:0", "-c:a", "copy", "-f", "webm", "out.part"]
+        );
+
+        let args = mux_args(
+            input("v.part", "avc1.640028"),
+            None,
+            Path::new("out.part"),
+            Container::Mp4,
+        );
+        assert_eq!(
+            strings(args),
+            ["-y", "-i", "v.part", "-map", "0:v:0", "-c:v", "copy", "-f", "mp4", "out.part"]
+        );
+    }
+
+    #[test]
+    fn mux_encodes_streams_of_unknown_codec() {
+        let unknown = Some(Input {
+            path: Path::new("v.part"),
+            codec: None,
+        });
+        let args = mux_args(unknown, None, Path::new("out.part"), Container::Mkv);
+        assert!(strings(args).contains(&"libx264".to_string()));
+    }
+
+    #[test]
+    fn audio_codec_parses_names_and_aliases() {
+        let cases = [
+            ("mp3", Some(AudioCodec::Mp3)),
+            (" MP3 ", Some(AudioCodec::Mp3)),
+            ("m4a", Some(AudioCodec::M4a)),
+            ("aac", Some(AudioCodec::M4a)),
+            ("opus", Some(AudioCodec::Opus)),
+            ("Flac", Some(AudioCodec::Flac)),
+            ("wav", None),
+            ("", None),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(name.parse::<AudioCodec>().ok(), expected, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn audio_config_validates_bitrates() {
+        let cases = [
+            (None, true),
+            (Some("192k"), true),
+            (Some("192K"), true),
+            (Some("192000"), true),
+            (Some("k"), false),
+            (Some("12x"), false),
+            (Some("1.5k"), false),
+            (Some("-192k"), false),
+            (Some(""), false),
+        ];
+        for (bitrate, valid) in cases {
+            let config = AudioConfig {
+                codec: Some(AudioCodec::Mp3),
+                bitrate: bitrate.map(str::to_string),
+            };
+            assert_eq!(config.validate().is_ok(), valid, "{:?}", bitrate);
+        }
+    }
+
+    #[test]
+    fn extract_audio_encodes_to_the_codec() {
+        let args = extract_audio_args(
+            Path::new("in.webm"),
+            Path::new("out.part"),
+            AudioCodec::Mp3,
+            Some("192k"),
+        );
+        assert_eq!(
+            strings(args),
+            [
+                "-y",
+                "-i",
+                "in.webm",
+                "-vn",
+                "-c:a",
+                "libmp3lame",
+                "-b:a",
+                "192k",
+                "-f",
+                "mp3",
+                "out.part"
+            ]
+        );
+
+        let args = extract_audio_args(
+            Path::new("in.mp4"),
+            Path::new("out.part"),
+            AudioCodec::M4a,
+            None,
+        );
+        assert_eq!(
+            strings(args),
+            ["-y", "-i", "in.mp4", "-vn", "-c:a", "aac", "-f", "ipod", "out.part"]
+        );
+    }
+
+    #[test]
+    fn extract_audio_ignores_the_bitrate_of_flac() {
+        let args = extract_audio_args(
+            Path::new("in.webm"),
+            Path::new("out.part"),
+            AudioCodec::Flac,
+            Some("192k"),
+        );
+        assert_eq!(
+            strings(args),
+            ["-y", "-i", "in.webm", "-vn", "-c:a", "flac", "-f", "flac", "out.part"]
+        );
+    }
+}
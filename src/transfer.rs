@@ -0,0 +1,516 @@
+//! HTTP downloads of single streams.
+//!
+//! Plain HTTPS formats are fetched here rather than by yt-dlp, so every
+//! received chunk can be accounted against the global bandwidth limit.
+//! Streams are requested in ranges of the format's chunk size, like yt-dlp
+//! does, since some sites throttle connections that read a whole file.
+//!
+//! While a stream downloads, a sidecar file next to it records the format
+//! and the expected size, so an interrupted download is resumed from where
+//! it stopped when the same format is downloaded again.
+
+use crate::bandwidth::BandwidthLimiter;
+use crate::error::Result;
+use crate::limits::RateLimiter;
+use crate::progress::StreamProgress;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use yt_dlp::model::format::{Format, HttpHeaders, Protocol};
+
+/// Range size used when the format does not tell one
+const DEFAULT_CHUNK_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Suffix of the sidecar of a partial download
+const SIDECAR_SUFFIX: &str = ".resume";
+
+/// What a partial download is a part of
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Sidecar {
+    format_id: String,
+    /// Complete size in bytes, once the format or the server told it
+    size: Option<u64>,
+}
+
+/// Downloads streams within the global bandwidth limit and per-site request rates
+pub struct Transfer {
+    client: reqwest::Client,
+    bandwidth: BandwidthLimiter,
+    limiter: Arc<RateLimiter>,
+}
+
+impl Transfer {
+    pub fn new(bandwidth: BandwidthLimiter, limiter: Arc<RateLimiter>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            bandwidth,
+            limiter,
+        }
+    }
+
+    /// Returns the limiter shared by every download
+    pub fn bandwidth(&self) -> &BandwidthLimiter {
+        &self.bandwidth
+    }
+
+    /// Returns true if the format is a single file this module can download
+    ///
+    /// Manifest based formats (HLS, DASH) are left to yt-dlp.
+    pub fn supports(format: &Format) -> bool {
+        matches!(format.protocol, Protocol::Https) && format.download_info.url.is_some()
+    }
+
+    /// Downloads a format to a file, resuming a partial download of it
+    ///
+    /// # Arguments
+    /// * `format` - Format to download, see `Transfer::supports`
+    /// * `path` - Destination file
+    /// * `progress` - Counts the bytes of the file as they arrive
+    /// * `site` - Site of the video, whose `SitePermit` the caller holds
+    ///
+    /// # Returns
+    /// * `Result<u64>` - Size of the complete file
+    ///
+    /// # Details
+    /// An existing file is resumed with range requests when its sidecar
+    /// names the same format and size. It is downloaded again from the
+    /// start when the sidecar is missing or different, or when the server
+    /// ignores the range or reports another size. The sidecar is removed
+    /// once the file is complete.
+    ///
+    /// Every request carries the HTTP headers yt-dlp lists for the format.
+    /// The caller's permit covers the first request; every further range
+    /// request waits for a token of the site's rate limit.
+    ///
+    /// # Errors
+    /// * If a request fails or answers with an error status
+    /// * If the server sends less than the expected size
+    /// * If the file or its sidecar cannot be written
+    pub async fn download(
+        &self,
+        format: &Format,
+        path: &Path,
+        progress: &StreamProgress,
+        site: &str,
+    ) -> Result<u64> {
+        let info = &format.download_info;
+        let url = info.url.as_deref().unwrap_or_default();
+        let chunk_size = info
+            .downloader_options
+            .as_ref()
+            .and_then(|options| u64::try_from(options.http_chunk_size).ok())
+            .filter(|size| *size > 0)
+            .unwrap_or(DEFAULT_CHUNK_SIZE);
+
+        let headers = request_headers(&info.http_headers);
+
+        let sidecar_path = sidecar_path(path);
+        let mut sidecar = Sidecar {
+            format_id: format.format_id.clone(),
+            size: format
+                .file_info
+                .filesize
+                .and_then(|size| u64::try_from(size).ok()),
+        };
+        let mut written = resumable_length(path, &sidecar_path, &mut sidecar).await;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(path)
+            .await?;
+        file.set_len(written).await?;
+        file.seek(SeekFrom::Start(written)).await?;
+        if written > 0 {
+            println!("Resuming format {} at {} bytes", format.format_id, written);
+        }
+        write_sidecar(&sidecar_path, &sidecar).await?;
+        progress.start(written, sidecar.size);
+
+        let mut requests = 0;
+        while sidecar.size.is_none_or(|size| written < size) {
+            if requests > 0 {
+                self.limiter.pace(site).await;
+            }
+            requests += 1;
+            let mut response = self
+                .client
+                .get(url)
+                .headers(headers.clone())
+                .header(
+                    RANGE,
+                    format!("bytes={}-{}", written, written + chunk_size - 1),
+                )
+                .send()
+                .await?
+                .error_for_status()?;
+
+            let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+            if !partial && written > 0 {
+                // The server ignored the range and sends the whole file again
+                file.seek(SeekFrom::Start(0)).await?;
+                file.set_len(0).await?;
+                written = 0;
+                progress.start(0, sidecar.size);
+            }
+            let total = partial.then(|| total_size(&response)).flatten();
+            if total.is_some() && total != sidecar.size {
+                if written > 0 && sidecar.size.is_some() {
+                    // The file changed on the server, the part we have is stale
+                    println!(
+                        "Format {} changed on the server, downloading it again",
+                        format.format_id
+                    );
+                    file.seek(SeekFrom::Start(0)).await?;
+                    file.set_len(0).await?;
+                    written = 0;
+                    sidecar.size = total;
+                    write_sidecar(&sidecar_path, &sidecar).await?;
+                    progress.start(0, total);
+                    continue;
+                }
+                sidecar.size = total;
+                write_sidecar(&sidecar_path, &sidecar).await?;
+                progress.set_total(total);
+            }
+
+            let mut received: u64 = 0;
+            while let Some(chunk) = response.chunk().await? {
+                self.bandwidth.consume(chunk.len()).await;
+                file.write_all(&chunk).await?;
+                received += chunk.len() as u64;
+                progress.advance(chunk.len() as u64);
+            }
+            written += received;
+
+            let finished = match total {
+                Some(total) => written >= total,
+                None => !partial || received < chunk_size,
+            };
+            if finished || received == 0 {
+                break;
+            }
+        }
+        file.flush().await?;
+        if sidecar.size.is_some_and(|size| written < size) {
+            // Kept with its sidecar, so the retry resumes it
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("format {} ended after {} bytes", format.format_id, written),
+            )
+            .into());
+        }
+        file.sync_data().await?;
+        tokio::fs::remove_file(&sidecar_path).await?;
+        progress.finish();
+        Ok(written)
+    }
+}
+
+/// Returns true if the file is a download that did not complete
+///
+/// Only downloads made by `Transfer::download` are recognized.
+pub fn is_partial(path: &Path) -> bool {
+    sidecar_path(path).exists()
+}
+
+fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(SIDECAR_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Returns how many bytes of an existing file can be kept
+///
+/// # Details
+/// The file is kept when its sidecar names the same format and a size
+/// agreeing with `sidecar`'s, if both are known, and the file is not
+/// larger. `sidecar` then takes the recorded size when it had none.
+async fn resumable_length(path: &Path, sidecar_path: &Path, sidecar: &mut Sidecar) -> u64 {
+    let Ok(content) = tokio::fs::read_to_string(sidecar_path).await else {
+        return 0;
+    };
+    let Ok(recorded) = serde_json::from_str::<Sidecar>(&content) else {
+        return 0;
+    };
+    let length = match tokio::fs::metadata(path).await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return 0,
+    };
+
+    let same_size = match (recorded.size, sidecar.size) {
+        (Some(recorded), Some(expected)) => recorded == expected,
+        _ => true,
+    };
+    let fits = recorded.size.is_none_or(|size| length <= size);
+    if recorded.format_id != sidecar.format_id || !same_size || !fits {
+        return 0;
+    }
+    sidecar.size = sidecar.size.or(recorded.size);
+    length
+}
+
+async fn write_sidecar(path: &Path, sidecar: &Sidecar) -> Result<()> {
+    let content = serde_json::to_string(sidecar).map_err(std::io::Error::from)?;
+    tokio::fs::write(path, content).await?;
+    Ok(())
+}
+
+/// Returns the headers yt-dlp would send for a format
+///
+/// Every header of `HttpHeaders` with a value is kept, under the name yt-dlp
+/// gives it; names or values HTTP does not allow are left out.
+fn request_headers(headers: &HttpHeaders) -> HeaderMap {
+    let Ok(serde_json::Value::Object(fields)) = serde_json::to_value(headers) else {
+        return HeaderMap::new();
+    };
+    fields
+        .iter()
+        .filter_map(|(name, value)| {
+            let value = value.as_str().filter(|value| !value.is_empty())?;
+            Some((
+                HeaderName::from_bytes(name.as_bytes()).ok()?,
+                HeaderValue::from_str(value).ok()?,
+            ))
+        })
+        .collect()
+}
+
+/// Reads the complete size from a `Content-Range: bytes a-b/total` header
+fn total_size(response: &reqwest::Response) -> Option<u64> {
+    let range = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    range.rsplit_once('/')?.1.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bandwidth::BandwidthConfig;
+    use crate::error::AppError;
+    use crate::limits::LimitsConfig;
+    use crate::mock_server::{self, Response};
+
+    /// Range size of the test formats
+    const CHUNK: u64 = 100;
+
+    fn format(url: &str, format_id: &str, filesize: Option<u64>) -> Format {
+        serde_json::from_value(serde_json::json!({
+            "format": format_id,
+            "format_id": format_id,
+            "protocol": "https",
+            "url": url,
+            "vcodec": "avc1.640028",
+            "acodec": "none",
+            "filesize": filesize,
+            "downloader_options": { "http_chunk_size": CHUNK },
+            "http_headers": {
+                "User-Agent": "",
+                "Accept": "",
+                "Accept-Language": "",
+                "Sec-Fetch-Mode": "",
+            },
+        }))
+        .unwrap()
+    }
+
+    fn transfer() -> Transfer {
+        Transfer::new(
+            BandwidthLimiter::new(&BandwidthConfig::default()).unwrap(),
+            Arc::new(RateLimiter::new(LimitsConfig::default())),
+        )
+    }
+
+    /// Creates an empty directory for the stream, returning the stream's path
+    fn stream_path(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ytb-transfer-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("video.mp4")
+    }
+
+    /// Leaves a partial download of `format_id` behind
+    fn partial(path: &Path, content: &str, format_id: &str, size: Option<u64>) {
+        std::fs::write(path, content).unwrap();
+        let sidecar = Sidecar {
+            format_id: format_id.to_string(),
+            size,
+        };
+        std::fs::write(sidecar_path(path), serde_json::to_string(&sidecar).unwrap()).unwrap();
+    }
+
+    fn partial_content(range: &str, body: &str) -> Response {
+        Response::new(206, body).header("content-range", &format!("bytes {}", range))
+    }
+
+    fn cleanup(path: &Path) {
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn download_resumes_a_matching_part() {
+        let path = stream_path("resume");
+        partial(&path, "hello", "137", Some(10));
+        let (url, mut requests) =
+            mock_server::serve(vec![partial_content("5-9/10", "world")]).await;
+        let progress = StreamProgress::default();
+
+        let size = transfer()
+            .download(&format(&url, "137", Some(10)), &path, &progress, "example")
+            .await
+            .unwrap();
+
+        assert_eq!(size, 10);
+        assert_eq!(
+            requests.recv().await.unwrap().headers["range"],
+            "bytes=5-104"
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "helloworld");
+        assert_eq!(progress.snapshot().transferred, 5);
+        assert!(!is_partial(&path));
+        cleanup(&path);
+    }
+
+    #[tokio::test]
+    async fn download_restarts_when_the_range_is_ignored() {
+        let path = stream_path("ignored");
+        partial(&path, "HELLO", "137", Some(10));
+        let (url, mut requests) = mock_server::serve(vec![Response::new(200, "helloworld")]).await;
+
+        let size = transfer()
+            .download(
+                &format(&url, "137", Some(10)),
+                &path,
+                &StreamProgress::default(),
+                "example",
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(size, 10);
+        assert_eq!(
+            requests.recv().await.unwrap().headers["range"],
+            "bytes=5-104"
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "helloworld");
+        assert!(!is_partial(&path));
+        cleanup(&path);
+    }
+
+    #[tokio::test]
+    async fn download_discards_a_part_of_another_size() {
+        let path = stream_path("changed");
+        partial(&path, "HELLO", "137", Some(10));
+        let (url, mut requests) = mock_server::serve(vec![
+            partial_content("5-11/12", "WORLD!!"),
+            partial_content("0-11/12", "helloworld!!"),
+        ])
+        .await;
+
+        let size = transfer()
+            .download(
+                &format(&url, "137", None),
+                &path,
+                &StreamProgress::default(),
+                "example",
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(size, 12);
+        assert_eq!(
+            requests.recv().await.unwrap().headers["range"],
+            "bytes=5-104"
+        );
+        assert_eq!(
+            requests.recv().await.unwrap().headers["range"],
+            "bytes=0-99"
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "helloworld!!");
+        cleanup(&path);
+    }
+
+    #[tokio::test]
+    async fn download_starts_over_for_another_format() {
+        let path = stream_path("format");
+        partial(&path, "HELLO", "22", Some(10));
+        let (url, mut requests) =
+            mock_server::serve(vec![partial_content("0-9/10", "helloworld")]).await;
+
+        transfer()
+            .download(
+                &format(&url, "137", Some(10)),
+                &path,
+                &StreamProgress::default(),
+                "example",
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            requests.recv().await.unwrap().headers["range"],
+            "bytes=0-99"
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "helloworld");
+        cleanup(&path);
+    }
+
+    #[tokio::test]
+    async fn download_keeps_a_short_part_for_the_retry() {
+        let path = stream_path("short");
+        let (url, _requests) = mock_server::serve(vec![
+            partial_content("0-4/10", "hello"),
+            partial_content("5-9/10", ""),
+        ])
+        .await;
+
+        let error = transfer()
+            .download(
+                &format(&url, "137", Some(10)),
+                &path,
+                &StreamProgress::default(),
+                "example",
+            )
+            .await
+            .unwrap_err();
+
+        assert!(
+            matches!(&error, AppError::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof),
+            "{}",
+            error
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello");
+        let sidecar: Sidecar =
+            serde_json::from_str(&std::fs::read_to_string(sidecar_path(&path)).unwrap()).unwrap();
+        assert_eq!(
+            sidecar,
+            Sidecar {
+                format_id: "137".to_string(),
+                size: Some(10),
+            }
+        );
+        cleanup(&path);
+    }
+
+    #[test]
+    fn request_headers_keeps_every_header_with_a_value() {
+        let headers: HttpHeaders = serde_json::from_value(serde_json::json!({
+            "User-Agent": "Mozilla/5.0",
+            "Accept": "text/html",
+            "Accept-Language": "en-us,en;q=0.5",
+            "Sec-Fetch-Mode": "",
+        }))
+        .unwrap();
+        let map = request_headers(&headers);
+
+        assert_eq!(map.len(), 3);
+        assert_eq!(map["user-agent"], "Mozilla/5.0");
+        assert_eq!(map["accept"], "text/html");
+        assert_eq!(map["accept-language"], "en-us,en;q=0.5");
+        assert!(!map.contains_key("sec-fetch-mode"));
+    }
+}
@@ -0,0 +1,678 @@
+//! Long-running watch mode.
+//!
+//! Polls a Google Sheet on an interval and watches the input directory for
+//! new URL files, feeding new work into the running `Downloader` until
+//! interrupted.
+
+use crate::error::Result;
+use crate::input::{archive_file, list_url_files, read_urls};
+use crate::orchestrator::process_sheet_rows;
+use crate::progress::DownloadSummary;
+use crate::sheet::{FetchCache, SheetRow};
+use crate::{Downloader, SheetClient};
+use futures::future::LocalBoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
+
+/// Polls a sheet and downloads each new row exactly once
+///
+/// Rows are identified by their URL. A URL is remembered as soon as its
+/// download is started, so it is never queued again during the watch, even
+/// if it fails or moves to another row.
+///
+/// # Examples
+///
+/// ```no_run
+/// use application::watch::SheetWatcher;
+/// use application::{Config, Downloader};
+///
+/// async fn example() {
+///     let downloader = Downloader::new(Config::default()).await.unwrap();
+///     let watcher = SheetWatcher::new(&downloader, "https://docs.google.com/spreadsheets/d/...");
+///     let summary = watcher.run().await.unwrap();
+/// }
+/// ```
+pub struct SheetWatcher<'a> {
+    downloader: &'a Downloader,
+    sheet_url: String,
+    interval: Duration,
+    poll: SheetPoll,
+}
+
+/// What a poll of the sheet needs, moved into the poll while it runs
+struct SheetPoll {
+    client: SheetClient,
+    sheet_url: String,
+    cache: FetchCache,
+    seen: SeenRows,
+}
+
+/// URLs of the rows a watch has started, so each row is queued once
+#[derive(Debug, Default)]
+struct SeenRows(HashSet<String>);
+
+impl SeenRows {
+    /// Returns the rows whose URL was not seen before, remembering them
+    ///
+    /// # Arguments
+    /// * `rows` - Rows of a poll, `None` when the sheet did not change
+    fn new_rows(&mut self, rows: Option<Vec<SheetRow>>) -> Vec<SheetRow> {
+        rows.unwrap_or_default()
+            .into_iter()
+            .filter(|row| self.0.insert(row.url.clone()))
+            .collect()
+    }
+}
+
+impl<'a> SheetWatcher<'a> {
+    /// Creates a watcher using the `[sheet]` settings of the downloader's configuration
+    pub fn new(downloader: &'a Downloader, sheet_url: &str) -> Self {
+        let config = &downloader.config().sheet;
+        Self {
+            downloader,
+            sheet_url: sheet_url.to_string(),
+            interval: Duration::from_secs(config.poll_interval_secs.max(1)),
+            poll: SheetPoll {
+                client: SheetClient::new().with_config(config.clone()),
+                sheet_url: sheet_url.to_string(),
+                cache: FetchCache::default(),
+                seen: SeenRows::default(),
+            },
+        }
+    }
+
+    /// Polls until Ctrl-C, then waits for the running batches to finish
+    ///
+    /// # Returns
+    /// * `Result<DownloadSummary>` - Combined counts of every batch started by the watch
+    ///
+    /// # Details
+    /// A failed poll is logged and retried at the next tick. Each poll that
+    /// finds new rows starts a batch right away, without waiting for the
+    /// batches of earlier polls. Batches keep running while a poll waits for
+    /// the sheet; ticks during a poll are skipped.
+    pub async fn run(self) -> Result<DownloadSummary> {
+        println!(
+            "Watching {} every {}s, press Ctrl-C to stop",
+            self.sheet_url,
+            self.interval.as_secs()
+        );
+
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut idle = Some(self.poll);
+        let mut polls: FuturesUnordered<LocalBoxFuture<'a, (SheetPoll, Result<Vec<SheetRow>>)>> =
+            FuturesUnordered::new();
+        let mut batches: FuturesUnordered<LocalBoxFuture<'a, Result<DownloadSummary>>> =
+            FuturesUnordered::new();
+        let mut summary = DownloadSummary::default();
+        let shutdown = tokio::signal::ctrl_c();
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => {
+                    println!("Stopping watch, waiting for {} running batches", batches.len());
+                    break;
+                }
+                _ = ticker.tick() => {
+                    if let Some(poll) = idle.take() {
+                        polls.push(Box::pin(poll.run()));
+                    }
+                }
+                Some((poll, result)) = polls.next() => {
+                    idle = Some(poll);
+                    match result {
+                        Ok(rows) if !rows.is_empty() => {
+                            println!("Found {} new rows in the sheet", rows.len());
+                            let downloader = self.downloader;
+                            let sheet_url = self.sheet_url.clone();
+                            batches.push(Box::pin(async move {
+                                process_sheet_rows(downloader, &sheet_url, &rows).await
+                            }));
+                        }
+                        Ok(_) => {}
+                        Err(e) => eprintln!("Warning: failed to poll sheet: {}", e),
+                    }
+                }
+                Some(result) = batches.next() => record_batch(&mut summary, result),
+            }
+        }
+
+        while let Some(result) = batches.next().await {
+            record_batch(&mut summary, result);
+        }
+        Ok(summary)
+    }
+}
+
+impl SheetPoll {
+    /// Fetches the sheet and returns itself with the rows not seen before
+    async fn run(mut self) -> (Self, Result<Vec<SheetRow>>) {
+        let rows = self
+            .client
+            .fetch_rows_cached(&self.sheet_url, &mut self.cache)
+            .await
+            .map(|rows| self.seen.new_rows(rows));
+        (self, rows)
+    }
+}
+
+/// Watches the input directory and downloads each URL file dropped into it
+///
+/// A file is read once it is complete: right away when it was closed after
+/// writing or renamed into the directory, otherwise once its size stayed
+/// the same for `input_settle_secs`. When its batch finishes, the file is
+/// moved to `done/` or `failed/` if `archive_input_files` is set, otherwise
+/// it stays in place and is ignored until it is removed or replaced.
+///
+/// Files already present when the watch starts are processed as well.
+pub struct InputWatcher<'a> {
+    downloader: &'a Downloader,
+    files: InputFiles,
+}
+
+/// State of the URL files of the input directory during a watch
+struct InputFiles {
+    input_dir: PathBuf,
+    settle: Duration,
+    archive: bool,
+    pending: HashMap<PathBuf, PendingFile>,
+    active: HashSet<PathBuf>,
+    /// Files processed and left in the input directory
+    processed: HashSet<PathBuf>,
+}
+
+/// A URL file seen in the input directory but not read yet
+struct PendingFile {
+    size: u64,
+    since: Instant,
+    complete: bool,
+}
+
+impl<'a> InputWatcher<'a> {
+    /// Creates a watcher for the input directory of the downloader's configuration
+    pub fn new(downloader: &'a Downloader) -> Self {
+        let config = downloader.config();
+        Self {
+            downloader,
+            files: InputFiles::new(
+                config.input_dir.clone(),
+                Duration::from_secs(config.input_settle_secs),
+                config.archive_input_files,
+            ),
+        }
+    }
+
+    /// Watches until Ctrl-C, then waits for the running batches to finish
+    ///
+    /// # Returns
+    /// * `Result<DownloadSummary>` - Combined counts of every file processed
+    ///
+    /// # Errors
+    /// * If the input directory cannot be listed or watched
+    pub async fn run(mut self) -> Result<DownloadSummary> {
+        // Events carry absolute paths
+        let files = &mut self.files;
+        files.input_dir = files.input_dir.canonicalize()?;
+
+        let (sender, mut events) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = sender.send(event);
+        })?;
+        watcher.watch(&files.input_dir, RecursiveMode::NonRecursive)?;
+        println!(
+            "Watching {:?} for URL files, press Ctrl-C to stop",
+            files.input_dir
+        );
+
+        for path in list_url_files(&files.input_dir)? {
+            files.track(path, false);
+        }
+
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut checks: FuturesUnordered<LocalBoxFuture<'a, HashMap<PathBuf, u64>>> =
+            FuturesUnordered::new();
+        let mut batches: FuturesUnordered<LocalBoxFuture<'a, (PathBuf, Result<DownloadSummary>)>> =
+            FuturesUnordered::new();
+        let mut summary = DownloadSummary::default();
+        let shutdown = tokio::signal::ctrl_c();
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => {
+                    println!("Stopping input watch, waiting for {} running files", batches.len());
+                    break;
+                }
+                Some(event) = events.recv() => match event {
+                    Ok(event) => files.handle(event),
+                    Err(e) => eprintln!("Warning: input watch error: {}", e),
+                },
+                _ = ticker.tick() => {
+                    if checks.is_empty() && !files.pending.is_empty() {
+                        checks.push(Box::pin(file_sizes(files.pending_paths())));
+                    }
+                }
+                Some(sizes) = checks.next() => {
+                    for path in files.ready_files(&sizes) {
+                        println!("Processing file: {:?}", path);
+                        batches.push(Box::pin(process_file(self.downloader, path)));
+                    }
+                }
+                Some((path, result)) = batches.next() => {
+                    files.finish(&path, result, &mut summary);
+                }
+            }
+        }
+
+        while let Some((path, result)) = batches.next().await {
+            files.finish(&path, result, &mut summary);
+        }
+        Ok(summary)
+    }
+}
+
+impl InputFiles {
+    fn new(input_dir: PathBuf, settle: Duration, archive: bool) -> Self {
+        Self {
+            input_dir,
+            settle,
+            archive,
+            pending: HashMap::new(),
+            active: HashSet::new(),
+            processed: HashSet::new(),
+        }
+    }
+
+    /// Updates the pending files from a filesystem event
+    fn handle(&mut self, event: Event) {
+        if matches!(
+            event.kind,
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both))
+        ) {
+            // One event for both names: the old one, then the new one
+            if let [from, to] = &event.paths[..] {
+                self.removed(from);
+                self.replaced(to.clone());
+            }
+            return;
+        }
+
+        let complete = matches!(
+            event.kind,
+            EventKind::Access(AccessKind::Close(AccessMode::Write))
+        );
+        let removed = matches!(
+            event.kind,
+            EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From))
+        );
+        let replaced = matches!(
+            event.kind,
+            EventKind::Modify(ModifyKind::Name(RenameMode::To))
+        );
+        let changed = complete || matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_));
+
+        for path in event.paths {
+            if removed {
+                self.removed(&path);
+            } else if replaced {
+                self.replaced(path);
+            } else if changed && self.is_url_file(&path) {
+                self.track(path, complete);
+            }
+        }
+    }
+
+    /// Forgets a file that left the input directory
+    fn removed(&mut self, path: &Path) {
+        self.processed.remove(path);
+        self.pending.remove(path);
+    }
+
+    /// Tracks a file renamed into place as complete, even over a processed name
+    fn replaced(&mut self, path: PathBuf) {
+        if self.is_url_file(&path) {
+            self.processed.remove(&path);
+            self.track(path, true);
+        }
+    }
+
+    fn is_url_file(&self, path: &Path) -> bool {
+        path.parent() == Some(self.input_dir.as_path())
+            && path.extension().and_then(|ext| ext.to_str()) == Some("txt")
+    }
+
+    /// Records a write to `path`, restarting its settle delay
+    fn track(&mut self, path: PathBuf, complete: bool) {
+        if self.active.contains(&path) || self.processed.contains(&path) {
+            return;
+        }
+        let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        let entry = self.pending.entry(path).or_insert(PendingFile {
+            size,
+            since: Instant::now(),
+            complete,
+        });
+        if entry.size != size {
+            entry.size = size;
+            entry.since = Instant::now();
+        }
+        entry.complete |= complete;
+    }
+
+    fn pending_paths(&self) -> Vec<PathBuf> {
+        self.pending.keys().cloned().collect()
+    }
+
+    /// Removes and returns the pending files that are completely written
+    ///
+    /// # Arguments
+    /// * `sizes` - Sizes of the pending files, see `file_sizes`; files
+    ///   missing from it are left pending
+    fn ready_files(&mut self, sizes: &HashMap<PathBuf, u64>) -> Vec<PathBuf> {
+        let mut ready = Vec::new();
+        for (path, file) in self.pending.iter_mut() {
+            let Some(&size) = sizes.get(path) else {
+                continue;
+            };
+            if size != file.size {
+                file.size = size;
+                file.since = Instant::now();
+                file.complete = false;
+            } else if file.complete || file.since.elapsed() >= self.settle {
+                ready.push(path.clone());
+            }
+        }
+        ready.sort();
+
+        for path in &ready {
+            self.pending.remove(path);
+            self.active.insert(path.clone());
+        }
+        ready
+    }
+
+    /// Archives a processed file, if enabled, and adds its counts to the summary
+    fn finish(
+        &mut self,
+        path: &Path,
+        result: Result<DownloadSummary>,
+        summary: &mut DownloadSummary,
+    ) {
+        self.active.remove(path);
+        let success = match result {
+            Ok(file_summary) => {
+                println!(
+                    "{:?}: {} URLs, {} succeeded, {} failed",
+                    path, file_summary.total, file_summary.succeeded, file_summary.failed
+                );
+                let success = file_summary.is_success();
+                summary.merge(file_summary);
+                success
+            }
+            Err(e) => {
+                eprintln!("Warning: failed to process {:?}: {}", path, e);
+                false
+            }
+        };
+
+        if !self.archive {
+            self.processed.insert(path.to_path_buf());
+            return;
+        }
+        match archive_file(path, success) {
+            Ok(target) => println!("  moved to {:?}", target),
+            Err(e) => {
+                eprintln!("Warning: could not archive {:?}: {}", path, e);
+                self.processed.insert(path.to_path_buf());
+            }
+        }
+    }
+}
+
+/// Returns the size of each of the files that still exist
+///
+/// Measured outside the watch loop, so a slow filesystem does not hold up
+/// the running batches.
+async fn file_sizes(paths: Vec<PathBuf>) -> HashMap<PathBuf, u64> {
+    let mut sizes = HashMap::new();
+    for path in paths {
+        if let Ok(metadata) = tokio::fs::metadata(&path).await {
+            sizes.insert(path, metadata.len());
+        }
+    }
+    sizes
+}
+
+/// Downloads the URLs of one file, returning the file with the outcome
+async fn process_file(
+    downloader: &Downloader,
+    path: PathBuf,
+) -> (PathBuf, Result<DownloadSummary>) {
+    let result = match read_urls(&path).await {
+        Ok(urls) => downloader.process_urls(&urls).await,
+        Err(e) => Err(e),
+    };
+    (path, result)
+}
+
+fn record_batch(summary: &mut DownloadSummary, result: Result<DownloadSummary>) {
+    match result {
+        Ok(batch) => summary.merge(batch),
+        Err(e) => eprintln!("Warning: sheet batch failed: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::CreateKind;
+
+    fn row(row: usize, url: &str) -> SheetRow {
+        SheetRow {
+            gid: "0".to_string(),
+            row,
+            url: url.to_string(),
+            status: String::new(),
+            title: None,
+            folder: None,
+            mode: None,
+        }
+    }
+
+    fn urls(rows: &[SheetRow]) -> Vec<&str> {
+        rows.iter().map(|row| row.url.as_str()).collect()
+    }
+
+    #[test]
+    fn seen_rows_returns_rows_added_between_polls() {
+        let mut seen = SeenRows::default();
+        let a = "https://youtu.be/a";
+        let b = "https://youtu.be/b";
+        let c = "https://youtu.be/c";
+
+        let first = seen.new_rows(Some(vec![row(2, a), row(3, b)]));
+        assert_eq!(urls(&first), [a, b]);
+
+        // An unchanged sheet (304 or same body) brings nothing new
+        assert!(seen.new_rows(None).is_empty());
+
+        let added = seen.new_rows(Some(vec![row(2, a), row(3, b), row(4, c)]));
+        assert_eq!(urls(&added), [c]);
+
+        // Rows moving or coming back after a failure are not queued again
+        let moved = seen.new_rows(Some(vec![row(2, c), row(5, a)]));
+        assert!(moved.is_empty());
+    }
+
+    #[test]
+    fn seen_rows_queues_duplicate_urls_once() {
+        let mut seen = SeenRows::default();
+        let a = "https://youtu.be/a";
+        let rows = seen.new_rows(Some(vec![row(2, a), row(3, a)]));
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].row, 2);
+    }
+
+    #[tokio::test]
+    async fn sheet_poll_returns_its_state_on_failure() {
+        let mut seen = SeenRows::default();
+        seen.new_rows(Some(vec![row(2, "https://youtu.be/a")]));
+        let poll = SheetPoll {
+            client: SheetClient::new(),
+            sheet_url: "https://example.com/not-a-sheet".to_string(),
+            cache: FetchCache::default(),
+            seen,
+        };
+
+        let (poll, result) = poll.run().await;
+        assert!(result.is_err());
+        // The next tick polls again, still knowing the rows already started
+        assert!(poll.seen.0.contains("https://youtu.be/a"));
+    }
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir.canonicalize().unwrap())
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn event(kind: EventKind, path: &Path) -> Event {
+        Event::new(kind).add_path(path.to_path_buf())
+    }
+
+    fn closed(path: &Path) -> Event {
+        event(
+            EventKind::Access(AccessKind::Close(AccessMode::Write)),
+            path,
+        )
+    }
+
+    /// Measures the pending files and returns the ready ones
+    async fn ready(files: &mut InputFiles) -> Vec<PathBuf> {
+        let sizes = file_sizes(files.pending_paths()).await;
+        files.ready_files(&sizes)
+    }
+
+    fn finished() -> Result<DownloadSummary> {
+        Ok(DownloadSummary::default())
+    }
+
+    #[tokio::test]
+    async fn input_files_skip_processed_files_without_archiving() {
+        let dir = TempDir::new("ytb-watch-processed");
+        let path = dir.0.join("urls.txt");
+        std::fs::write(&path, "https://youtu.be/a\n").unwrap();
+        let mut files = InputFiles::new(dir.0.clone(), Duration::from_secs(60), false);
+
+        files.handle(closed(&path));
+        assert_eq!(ready(&mut files).await, std::slice::from_ref(&path));
+
+        // Events while the file is processed are ignored
+        files.handle(closed(&path));
+        assert!(ready(&mut files).await.is_empty());
+
+        files.finish(&path, finished(), &mut DownloadSummary::default());
+        assert!(path.exists());
+
+        // Left in place, the file is not read again
+        files.handle(closed(&path));
+        files.handle(event(EventKind::Modify(ModifyKind::Any), &path));
+        assert!(ready(&mut files).await.is_empty());
+
+        // Until it is replaced
+        files.handle(event(
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+            &path,
+        ));
+        assert_eq!(ready(&mut files).await, std::slice::from_ref(&path));
+        files.finish(&path, finished(), &mut DownloadSummary::default());
+
+        // Or removed and written again
+        files.handle(event(
+            EventKind::Remove(notify::event::RemoveKind::File),
+            &path,
+        ));
+        files.handle(closed(&path));
+        assert_eq!(ready(&mut files).await, std::slice::from_ref(&path));
+        files.finish(&path, finished(), &mut DownloadSummary::default());
+
+        // Or renamed over in an event naming both files
+        let other = dir.0.join("other.txt");
+        std::fs::write(&other, "https://youtu.be/b\n").unwrap();
+        files.handle(event(EventKind::Create(CreateKind::File), &other));
+        std::fs::rename(&other, &path).unwrap();
+        files.handle(
+            event(
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+                &other,
+            )
+            .add_path(path.clone()),
+        );
+        assert_eq!(ready(&mut files).await, std::slice::from_ref(&path));
+        assert!(files.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn input_files_archive_processed_files() {
+        let dir = TempDir::new("ytb-watch-archive");
+        let path = dir.0.join("urls.txt");
+        std::fs::write(&path, "https://youtu.be/a\n").unwrap();
+        let mut files = InputFiles::new(dir.0.clone(), Duration::from_secs(60), true);
+
+        files.handle(closed(&path));
+        assert_eq!(ready(&mut files).await, std::slice::from_ref(&path));
+        files.finish(&path, finished(), &mut DownloadSummary::default());
+
+        assert!(!path.exists());
+        assert!(dir.0.join("done").join("urls.txt").exists());
+        assert!(files.processed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn input_files_wait_for_settle_unless_closed() {
+        let dir = TempDir::new("ytb-watch-settle");
+        let path = dir.0.join("urls.txt");
+        std::fs::write(&path, "https://youtu.be/a\n").unwrap();
+        let mut files = InputFiles::new(dir.0.clone(), Duration::from_secs(60), false);
+
+        files.handle(event(EventKind::Create(CreateKind::File), &path));
+        assert!(ready(&mut files).await.is_empty());
+
+        files.settle = Duration::ZERO;
+        assert_eq!(ready(&mut files).await, [path]);
+    }
+
+    #[tokio::test]
+    async fn input_files_ignore_other_files() {
+        let dir = TempDir::new("ytb-watch-other");
+        let nested = dir.0.join("done");
+        std::fs::create_dir_all(&nested).unwrap();
+        let mut files = InputFiles::new(dir.0.clone(), Duration::ZERO, false);
+
+        for path in [dir.0.join("notes.md"), nested.join("urls.txt")] {
+            std::fs::write(&path, "https://youtu.be/a\n").unwrap();
+            files.handle(closed(&path));
+        }
+        assert!(ready(&mut files).await.is_empty());
+    }
+}